@@ -0,0 +1,403 @@
+// Run with `cargo bench`, or `cargo bench -- <name>` for the benchmarks whose
+// names contain `<name>`. Each figure is the fastest of a few runs.
+
+use std::{
+    collections::HashMap,
+    hash::{BuildHasherDefault, Hash, Hasher},
+    hint::black_box,
+    io,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use lox::{
+    arena::{ArenaExpr, ExprArena, ExprId},
+    compiler::compile_program,
+    execute,
+    expression::{BinaryExpr, Expr, UnaryExpr, Visitor},
+    gc::{Heap, Obj, ObjRef},
+    parse_program,
+    table::Table,
+    token::{Token, TokenKind},
+    vm::Vm,
+};
+
+const RUNS: usize = 5;
+
+fn main() {
+    let filters: Vec<String> = std::env::args()
+        .skip(1)
+        .filter(|arg| !arg.starts_with('-'))
+        .collect();
+    let benches: [(&str, fn()); 5] = [
+        ("arena", arena),
+        ("table", table),
+        ("fib", fib),
+        ("binary-trees", binary_trees),
+        ("string-loop", string_loop),
+    ];
+    for (name, bench) in benches {
+        if filters.is_empty() || filters.iter().any(|filter| name.contains(filter.as_str())) {
+            println!("{name}");
+            bench();
+        }
+    }
+}
+
+// Times `routine` on a fresh input from `setup`, so neither building the
+// input nor dropping the output is counted
+fn fastest<I, O>(mut setup: impl FnMut() -> I, mut routine: impl FnMut(I) -> O) -> Duration {
+    (0..RUNS)
+        .map(|_| {
+            let input = setup();
+            let start = Instant::now();
+            let output = black_box(routine(black_box(input)));
+            let elapsed = start.elapsed();
+            drop(output);
+            elapsed
+        })
+        .min()
+        .expect("RUNS is not zero")
+}
+
+fn report(label: &str, durations: &[(&str, Duration)]) {
+    let durations = durations
+        .iter()
+        .map(|(name, duration)| format!("{name} {duration:?}"))
+        .collect::<Vec<_>>();
+    println!("  {label:<6} {}", durations.join(", "));
+}
+
+// Balanced tree of `depth` levels of alternating `+` and unary `-`
+fn boxed_tree(depth: usize, counter: &mut f64) -> Expr {
+    if depth == 0 {
+        *counter += 1.0;
+        return Expr::NumberLiteral(*counter);
+    }
+    let left = boxed_tree(depth - 1, counter);
+    let right = boxed_tree(depth - 1, counter);
+    let right = Expr::Unary(UnaryExpr::new(Token::from(TokenKind::Minus), right));
+    Expr::Binary(BinaryExpr::new(left, Token::from(TokenKind::Plus), right))
+}
+
+// The same tree, built straight into an arena
+fn arena_tree(arena: &mut ExprArena, depth: usize, counter: &mut f64) -> ExprId {
+    if depth == 0 {
+        *counter += 1.0;
+        return arena.alloc(ArenaExpr::NumberLiteral(*counter));
+    }
+    let left = arena_tree(arena, depth - 1, counter);
+    let right = arena_tree(arena, depth - 1, counter);
+    let right = arena.alloc(ArenaExpr::Unary {
+        operator: Token::from(TokenKind::Minus),
+        right,
+    });
+    arena.alloc(ArenaExpr::Binary {
+        left,
+        operator: Token::from(TokenKind::Plus),
+        right,
+    })
+}
+
+#[derive(Default)]
+struct Sum(f64);
+
+impl Visitor for Sum {
+    fn visit_literal(&mut self, literal: &Expr) {
+        if let Expr::NumberLiteral(value) = literal {
+            self.0 += value;
+        }
+    }
+}
+
+// The same depth first walk as `Sum`, following ids instead of boxes
+fn sum_arena(arena: &ExprArena, id: ExprId) -> f64 {
+    match &arena[id] {
+        ArenaExpr::NumberLiteral(value) => *value,
+        ArenaExpr::Unary { right, .. } => sum_arena(arena, *right),
+        ArenaExpr::Binary { left, right, .. } => sum_arena(arena, *left) + sum_arena(arena, *right),
+        ArenaExpr::Grouping { expression } => sum_arena(arena, *expression),
+        _ => 0.0,
+    }
+}
+
+// The arena against the boxed `Expr` tree
+fn arena() {
+    const DEPTH: usize = 20;
+
+    let boxed = || boxed_tree(DEPTH, &mut 0.0);
+    let arena = || {
+        let mut arena = ExprArena::new();
+        let root = arena_tree(&mut arena, DEPTH, &mut 0.0);
+        (arena, root)
+    };
+
+    let (tree, (nodes, root)) = (boxed(), arena());
+    let mut sum = Sum::default();
+    sum.visit_expr(&tree);
+    assert_eq!(sum.0, sum_arena(&nodes, root));
+
+    println!("  {} nodes", nodes.len());
+    report(
+        "build",
+        &[
+            ("boxed", fastest(|| (), |()| boxed())),
+            ("arena", fastest(|| (), |()| arena())),
+        ],
+    );
+    report(
+        "walk",
+        &[
+            (
+                "boxed",
+                fastest(
+                    || (),
+                    |()| {
+                        let mut sum = Sum::default();
+                        sum.visit_expr(&tree);
+                        sum.0
+                    },
+                ),
+            ),
+            ("arena", fastest(|| (), |()| sum_arena(&nodes, root))),
+        ],
+    );
+    report(
+        "clone",
+        &[
+            ("boxed", fastest(|| (), |()| tree.clone())),
+            ("arena", fastest(|| (), |()| nodes.clone())),
+        ],
+    );
+    report(
+        "drop",
+        &[
+            ("boxed", fastest(boxed, drop)),
+            ("arena", fastest(arena, drop)),
+        ],
+    );
+}
+
+// A string key with the hash its object caches. Hashing one only passes the
+// cached hash on, so `HashMap` is compared with `Table` on how it stores
+// entries rather than on how it hashes.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Key {
+    obj: ObjRef,
+    hash: u32,
+}
+
+impl Hash for Key {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u32(self.hash);
+    }
+}
+
+#[derive(Default)]
+struct Precomputed(u64);
+
+impl Hasher for Precomputed {
+    fn write(&mut self, _: &[u8]) {
+        unreachable!("only cached hashes are written");
+    }
+
+    fn write_u32(&mut self, hash: u32) {
+        self.0 = hash as u64;
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+type KeyMap = HashMap<Key, usize, BuildHasherDefault<Precomputed>>;
+
+// The table against `HashMap`, both keyed by interned strings
+fn table() {
+    const KEYS: usize = 100_000;
+    const LOOKUPS: usize = 10;
+
+    let mut heap = Heap::new();
+    let keys = (0..KEYS)
+        .map(|index| {
+            let obj = heap.intern(&format!("key{index}"));
+            let Obj::String(string) = heap.get(obj) else {
+                unreachable!("interning makes a string");
+            };
+            Key {
+                obj,
+                hash: string.hash,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let fill_table = || {
+        let mut table = Table::new();
+        for (index, key) in keys.iter().enumerate() {
+            table.insert(key.obj, key.hash, index);
+        }
+        table
+    };
+    let fill_map = || {
+        let mut map = KeyMap::default();
+        for (index, key) in keys.iter().enumerate() {
+            map.insert(*key, index);
+        }
+        map
+    };
+
+    let (table, map) = (fill_table(), fill_map());
+    let sum_table = || {
+        let mut sum = 0;
+        for _ in 0..LOOKUPS {
+            for key in &keys {
+                sum += table.get(key.obj, key.hash).expect("key was inserted");
+            }
+        }
+        sum
+    };
+    let sum_map = || {
+        let mut sum = 0;
+        for _ in 0..LOOKUPS {
+            for key in &keys {
+                sum += map.get(key).expect("key was inserted");
+            }
+        }
+        sum
+    };
+    assert_eq!(sum_table(), sum_map());
+
+    println!("  {KEYS} keys, {LOOKUPS} lookups each");
+    report(
+        "insert",
+        &[
+            ("table", fastest(|| (), |()| fill_table())),
+            ("HashMap", fastest(|| (), |()| fill_map())),
+        ],
+    );
+    report(
+        "get",
+        &[
+            ("table", fastest(|| (), |()| sum_table())),
+            ("HashMap", fastest(|| (), |()| sum_map())),
+        ],
+    );
+    report(
+        "remove",
+        &[
+            (
+                "table",
+                fastest(fill_table, |mut table| {
+                    for key in &keys {
+                        table.remove(key.obj, key.hash);
+                    }
+                    table
+                }),
+            ),
+            (
+                "HashMap",
+                fastest(fill_map, |mut map| {
+                    for key in &keys {
+                        map.remove(key);
+                    }
+                    map
+                }),
+            ),
+        ],
+    );
+}
+
+// Runs `source` on the tree-walking interpreter and on the VM, after checking
+// that both print `expected`. Only running is timed, not parsing or
+// compiling. Build with and without `--features nan-boxing` to compare the
+// VM's value representations.
+fn program(source: &str, expected: &str) {
+    let program = parse_program(source).expect("source parses");
+    let script = Rc::new(compile_program(&program).expect("program compiles"));
+
+    let mut output = Vec::new();
+    execute(&program, &mut output).expect("program runs");
+    assert_eq!(String::from_utf8_lossy(&output), expected);
+    let mut output = Vec::new();
+    Vm::new()
+        .run_program(script.clone(), &mut output)
+        .expect("program runs");
+    assert_eq!(String::from_utf8_lossy(&output), expected);
+
+    let representation = if cfg!(feature = "nan-boxing") {
+        "nan-boxed"
+    } else {
+        "enum"
+    };
+    report(
+        "run",
+        &[
+            (
+                "tree",
+                fastest(|| (), |()| execute(&program, &mut io::sink())),
+            ),
+            (
+                representation,
+                fastest(
+                    || (Vm::new(), script.clone()),
+                    |(mut vm, script)| vm.run_program(script, &mut io::sink()),
+                ),
+            ),
+        ],
+    );
+}
+
+// Function calls and arithmetic
+fn fib() {
+    let source = "
+        fun fib(n) {
+            if (n < 2) return n;
+            return fib(n - 1) + fib(n - 2);
+        }
+        print fib(25);
+    ";
+    program(source, "75025\n");
+}
+
+// Allocation and collection, with closures standing in for the tree's nodes
+fn binary_trees() {
+    let source = "
+        fun tree(depth) {
+            if (depth == 0) return nil;
+            var left = tree(depth - 1);
+            var right = tree(depth - 1);
+            fun node(first) {
+                if (first) return left;
+                return right;
+            }
+            return node;
+        }
+
+        fun check(node) {
+            if (node == nil) return 1;
+            return 1 + check(node(true)) + check(node(false));
+        }
+
+        var total = 0;
+        for (var depth = 4; depth <= 14; depth = depth + 2) {
+            total = total + check(tree(depth));
+        }
+        print total;
+    ";
+    program(source, "43674\n");
+}
+
+// Concatenating, interning and comparing strings
+fn string_loop() {
+    let source = "
+        var text = \"\";
+        var matches = 0;
+        for (var i = 0; i < 2000; i = i + 1) {
+            text = text + \"ab\";
+            if (\"a\" + \"b\" == \"ab\") matches = matches + 1;
+        }
+        print matches;
+        print text == text + \"\";
+    ";
+    program(source, "2000\ntrue\n");
+}
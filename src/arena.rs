@@ -0,0 +1,431 @@
+use std::ops::Index;
+
+use crate::{
+    expression::{AssignExpr, BinaryExpr, CallExpr, Expr, GroupingExpr, UnaryExpr},
+    parser::ExprBuilder,
+    token::Token,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ExprId(u32);
+
+impl ExprId {
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+// The arena counterpart of `Expr`, with children referred to by id
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ArenaExpr {
+    BooleanLiteral(bool),
+    NumberLiteral(#[cfg_attr(feature = "serde", serde(with = "crate::serialize::number"))] f64),
+    StringLiteral(String),
+    NilLiteral,
+    Unary {
+        operator: Token,
+        right: ExprId,
+    },
+    Binary {
+        left: ExprId,
+        operator: Token,
+        right: ExprId,
+    },
+    Grouping {
+        expression: ExprId,
+    },
+    Variable(Token),
+    Assign {
+        name: Token,
+        value: ExprId,
+    },
+    Call {
+        callee: ExprId,
+        paren: Token,
+        arguments: Vec<ExprId>,
+    },
+}
+
+impl ArenaExpr {
+    // In source order, so the callee comes before the arguments
+    pub fn children(&self) -> impl Iterator<Item = ExprId> + '_ {
+        let (children, arguments) = match self {
+            ArenaExpr::Unary { right, .. } => ([Some(*right), None], &[][..]),
+            ArenaExpr::Binary { left, right, .. } => ([Some(*left), Some(*right)], &[][..]),
+            ArenaExpr::Grouping { expression } => ([Some(*expression), None], &[][..]),
+            ArenaExpr::Assign { value, .. } => ([Some(*value), None], &[][..]),
+            ArenaExpr::Call {
+                callee, arguments, ..
+            } => ([Some(*callee), None], &arguments[..]),
+            _ => ([None, None], &[][..]),
+        };
+        children
+            .into_iter()
+            .flatten()
+            .chain(arguments.iter().copied())
+    }
+}
+
+// Owns every node of one or more trees in a single allocation. Children are
+// always allocated before their parents, so walking the nodes in id order
+// visits every operand before the operation that uses it.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ExprArena {
+    nodes: Vec<ArenaExpr>,
+}
+
+impl ExprArena {
+    pub fn new() -> ExprArena {
+        ExprArena::default()
+    }
+
+    pub fn alloc(&mut self, node: ArenaExpr) -> ExprId {
+        let id =
+            ExprId(u32::try_from(self.nodes.len()).expect("arena holds at most u32::MAX nodes"));
+        self.nodes.push(node);
+        id
+    }
+
+    pub fn get(&self, id: ExprId) -> &ArenaExpr {
+        &self.nodes[id.index()]
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn iter(
+        &self,
+    ) -> impl DoubleEndedIterator<Item = (ExprId, &ArenaExpr)> + ExactSizeIterator {
+        self.nodes
+            .iter()
+            .enumerate()
+            .map(|(index, node)| (ExprId(index as u32), node))
+    }
+
+    // Copies `expr` into the arena and returns the id of its root. Uses an
+    // explicit stack, since chains of binary operators can be far deeper than
+    // the parser's nesting limit.
+    pub fn lower(&mut self, expr: &Expr) -> ExprId {
+        enum Step<'a> {
+            Visit(&'a Expr),
+            Alloc(&'a Expr),
+        }
+
+        let mut steps = vec![Step::Visit(expr)];
+        let mut ids = Vec::new();
+        while let Some(step) = steps.pop() {
+            match step {
+                Step::Visit(expr) => {
+                    steps.push(Step::Alloc(expr));
+                    // Pushed right first, so the left operand is allocated first
+                    match expr {
+                        Expr::Unary(unary) => steps.push(Step::Visit(&unary.right)),
+                        Expr::Binary(binary) => {
+                            steps.push(Step::Visit(&binary.right));
+                            steps.push(Step::Visit(&binary.left));
+                        }
+                        Expr::Grouping(grouping) => steps.push(Step::Visit(&grouping.expression)),
+                        Expr::Assign(assign) => steps.push(Step::Visit(&assign.value)),
+                        Expr::Call(call) => {
+                            steps.extend(call.arguments.iter().rev().map(Step::Visit));
+                            steps.push(Step::Visit(&call.callee));
+                        }
+                        _ => {}
+                    }
+                }
+                Step::Alloc(expr) => {
+                    let mut operand = || ids.pop().expect("operands are allocated first");
+                    let node = match expr {
+                        Expr::BooleanLiteral(value) => ArenaExpr::BooleanLiteral(*value),
+                        Expr::NumberLiteral(value) => ArenaExpr::NumberLiteral(*value),
+                        Expr::StringLiteral(value) => ArenaExpr::StringLiteral(value.clone()),
+                        Expr::NilLiteral => ArenaExpr::NilLiteral,
+                        Expr::Unary(unary) => ArenaExpr::Unary {
+                            operator: unary.operator.clone(),
+                            right: operand(),
+                        },
+                        Expr::Binary(binary) => {
+                            let right = operand();
+                            ArenaExpr::Binary {
+                                left: operand(),
+                                operator: binary.operator.clone(),
+                                right,
+                            }
+                        }
+                        Expr::Grouping(_) => ArenaExpr::Grouping {
+                            expression: operand(),
+                        },
+                        Expr::Variable(name) => ArenaExpr::Variable(name.clone()),
+                        Expr::Assign(assign) => ArenaExpr::Assign {
+                            name: assign.name.clone(),
+                            value: operand(),
+                        },
+                        Expr::Call(call) => {
+                            let arguments = ids.split_off(ids.len() - call.arguments.len());
+                            ArenaExpr::Call {
+                                callee: ids.pop().expect("the callee is allocated first"),
+                                paren: call.paren.clone(),
+                                arguments,
+                            }
+                        }
+                    };
+                    ids.push(self.alloc(node));
+                }
+            }
+        }
+        ids.pop().expect("the root is allocated last")
+    }
+
+    // Rebuilds the boxed tree rooted at `id`, again without recursing
+    pub fn to_expr(&self, id: ExprId) -> Expr {
+        enum Step {
+            Visit(ExprId),
+            Build(ExprId),
+        }
+
+        let mut steps = vec![Step::Visit(id)];
+        let mut exprs = Vec::new();
+        while let Some(step) = steps.pop() {
+            match step {
+                Step::Visit(id) => {
+                    steps.push(Step::Build(id));
+                    steps.extend(self[id].children().map(Step::Visit));
+                }
+                Step::Build(id) => {
+                    // Children were pushed left first, so the left operand was
+                    // built last
+                    if let ArenaExpr::Call {
+                        paren, arguments, ..
+                    } = &self[id]
+                    {
+                        let callee = exprs.pop().expect("the callee is built first");
+                        let arguments = (0..arguments.len())
+                            .map(|_| exprs.pop().expect("arguments are built first"))
+                            .collect();
+                        exprs.push(Expr::Call(CallExpr::new(callee, paren.clone(), arguments)));
+                        continue;
+                    }
+                    let mut operand = || exprs.pop().expect("operands are built first");
+                    let expr = match &self[id] {
+                        ArenaExpr::BooleanLiteral(value) => Expr::BooleanLiteral(*value),
+                        ArenaExpr::NumberLiteral(value) => Expr::NumberLiteral(*value),
+                        ArenaExpr::StringLiteral(value) => Expr::StringLiteral(value.clone()),
+                        ArenaExpr::NilLiteral => Expr::NilLiteral,
+                        ArenaExpr::Unary { operator, .. } => {
+                            Expr::Unary(UnaryExpr::new(operator.clone(), operand()))
+                        }
+                        ArenaExpr::Binary { operator, .. } => {
+                            let left = operand();
+                            Expr::Binary(BinaryExpr::new(left, operator.clone(), operand()))
+                        }
+                        ArenaExpr::Grouping { .. } => Expr::Grouping(GroupingExpr::new(operand())),
+                        ArenaExpr::Variable(name) => Expr::Variable(name.clone()),
+                        ArenaExpr::Assign { name, .. } => {
+                            Expr::Assign(AssignExpr::new(name.clone(), operand()))
+                        }
+                        ArenaExpr::Call { .. } => unreachable!("calls are built above"),
+                    };
+                    exprs.push(expr);
+                }
+            }
+        }
+        exprs.pop().expect("the root is built last")
+    }
+}
+
+impl ExprBuilder for ExprArena {
+    type Node = ExprId;
+
+    fn literal(&mut self, literal: Expr) -> ExprId {
+        self.lower(&literal)
+    }
+
+    fn unary(&mut self, operator: Token, right: ExprId) -> ExprId {
+        self.alloc(ArenaExpr::Unary { operator, right })
+    }
+
+    fn binary(&mut self, left: ExprId, operator: Token, right: ExprId) -> ExprId {
+        self.alloc(ArenaExpr::Binary {
+            left,
+            operator,
+            right,
+        })
+    }
+
+    fn grouping(&mut self, expression: ExprId) -> ExprId {
+        self.alloc(ArenaExpr::Grouping { expression })
+    }
+
+    fn variable(&mut self, name: Token) -> ExprId {
+        self.alloc(ArenaExpr::Variable(name))
+    }
+
+    fn assign(&mut self, name: Token, value: ExprId) -> ExprId {
+        self.alloc(ArenaExpr::Assign { name, value })
+    }
+
+    fn call(&mut self, callee: ExprId, paren: Token, arguments: Vec<ExprId>) -> ExprId {
+        self.alloc(ArenaExpr::Call {
+            callee,
+            paren,
+            arguments,
+        })
+    }
+}
+
+impl Index<ExprId> for ExprArena {
+    type Output = ArenaExpr;
+
+    fn index(&self, id: ExprId) -> &ArenaExpr {
+        self.get(id)
+    }
+}
+
+// Data attached to nodes by a pass, such as types or resolved depths, stored
+// densely by id rather than in the nodes themselves
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExprMap<T> {
+    values: Vec<Option<T>>,
+}
+
+impl<T> Default for ExprMap<T> {
+    fn default() -> ExprMap<T> {
+        ExprMap { values: Vec::new() }
+    }
+}
+
+impl<T> Index<ExprId> for ExprMap<T> {
+    type Output = T;
+
+    fn index(&self, id: ExprId) -> &T {
+        self.get(id).expect("id has a value")
+    }
+}
+
+impl<T> ExprMap<T> {
+    pub fn new() -> ExprMap<T> {
+        ExprMap::default()
+    }
+
+    pub fn insert(&mut self, id: ExprId, value: T) -> Option<T> {
+        if self.values.len() <= id.index() {
+            self.values.resize_with(id.index() + 1, || None);
+        }
+        self.values[id.index()].replace(value)
+    }
+
+    pub fn get(&self, id: ExprId) -> Option<&T> {
+        self.values.get(id.index())?.as_ref()
+    }
+
+    pub fn get_mut(&mut self, id: ExprId) -> Option<&mut T> {
+        self.values.get_mut(id.index())?.as_mut()
+    }
+
+    pub fn remove(&mut self, id: ExprId) -> Option<T> {
+        self.values.get_mut(id.index())?.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        arena::{ArenaExpr, ExprArena, ExprId, ExprMap},
+        parse,
+        parser::Parser,
+        token::{Token, TokenKind},
+    };
+
+    #[test]
+    fn test_round_trip() {
+        for source in ["1 + 2 * 3", "(1 + 2) * -3 >= 4 == !false", "\"a\" != nil"] {
+            let expr = parse(source).expect("source parses");
+            let mut arena = ExprArena::new();
+            let root = arena.lower(&expr);
+            assert_eq!(arena.to_expr(root), expr);
+        }
+    }
+
+    #[test]
+    fn test_parses_into_arena() {
+        let mut arena = ExprArena::new();
+        let mut roots = Vec::new();
+        for source in ["1 + 2 * 3", "(1 + 2) * -3 >= 4 == !false"] {
+            let tokens = crate::lex(source).expect("source lexes");
+            let root = Parser::new(tokens)
+                .parse_into(&mut arena)
+                .expect("source parses");
+            roots.push((root, source));
+        }
+        for (root, source) in roots {
+            assert_eq!(arena.to_expr(root), parse(source).expect("source parses"));
+        }
+    }
+
+    #[test]
+    fn test_children_allocated_before_parents() {
+        let mut arena = ExprArena::new();
+        let tokens = crate::lex("-(1 + 2) * 3").expect("source lexes");
+        let root = Parser::new(tokens)
+            .parse_into(&mut arena)
+            .expect("source parses");
+        assert_eq!(root.index(), arena.len() - 1);
+        for (id, node) in arena.iter() {
+            assert!(node.children().all(|child| child < id));
+        }
+    }
+
+    #[test]
+    fn test_side_table() {
+        let mut arena = ExprArena::new();
+        let root = arena.lower(&parse("1 + \"a\"").expect("source parses"));
+
+        // A toy type pass, filled in a single forward sweep
+        let mut types = ExprMap::new();
+        for (id, node) in arena.iter() {
+            let ty = match node {
+                ArenaExpr::NumberLiteral(_) => "number",
+                ArenaExpr::StringLiteral(_) => "string",
+                ArenaExpr::Binary { left, right, .. } if types.get(*left) == types.get(*right) => {
+                    types.get(*left).copied().unwrap_or("unknown")
+                }
+                _ => "unknown",
+            };
+            types.insert(id, ty);
+        }
+
+        assert_eq!(types.get(root), Some(&"unknown"));
+        assert_eq!(types.get(ExprId(0)), Some(&"number"));
+        assert_eq!(types.remove(ExprId(1)), Some("string"));
+        assert_eq!(types.get(ExprId(1)), None);
+        assert_eq!(types.get(ExprId(100)), None);
+    }
+
+    #[test]
+    fn test_long_chain_round_trip() {
+        // A left leaning chain of 200k additions, deeper than any recursive
+        // walk could go
+        let mut arena = ExprArena::new();
+        let mut root = arena.alloc(ArenaExpr::NumberLiteral(0.0));
+        for index in 1..200_000 {
+            let right = arena.alloc(ArenaExpr::NumberLiteral(index as f64));
+            root = arena.alloc(ArenaExpr::Binary {
+                left: root,
+                operator: Token::from(TokenKind::Plus),
+                right,
+            });
+        }
+
+        let mut copy = ExprArena::new();
+        copy.lower(&arena.to_expr(root));
+        assert_eq!(copy, arena);
+    }
+}
@@ -1,28 +1,308 @@
-use std::ops::Deref;
-
-use crate::expression::{Expr };
+use crate::{expression::Expr, token::TokenKind};
 
 pub trait AstDisplay {
     fn ast(&self) -> String;
+    // Reverse Polish notation, e.g. `1 2 + 4 3 - *`
+    fn rpn(&self) -> String;
+    // Indented tree drawn with box-drawing characters
+    fn tree(&self) -> String;
+    // Graphviz DOT graph
+    fn dot(&self) -> String;
 }
 
+// Every printer keeps its own stack of pending work rather than recursing,
+// since a chain of binary operators can be far deeper than the parser's
+// nesting limit.
+
 impl AstDisplay for Expr {
     fn ast(&self) -> String {
-        match self {
-            Expr::BooleanLiteral(value) => value.to_string(),
-            Expr::NumberLiteral(value) => value.to_string(),
-            Expr::StringLiteral(value) => format!("\"{}\"", value),
-            Expr::NilLiteral => "nil".to_string(),
-            Expr::Unary(unary) => format!("({} {})", unary.operator, unary.right.deref().ast()),
-            Expr::Binary(binary) => {
-                format!(
-                    "({} {} {})",
-                    binary.left.ast(),
-                    binary.operator,
-                    binary.right.ast()
-                )
+        enum Step<'a> {
+            Expr(&'a Expr),
+            Text(String),
+        }
+
+        let mut output = String::new();
+        let mut steps = vec![Step::Expr(self)];
+        while let Some(step) = steps.pop() {
+            let expr = match step {
+                Step::Expr(expr) => expr,
+                Step::Text(text) => {
+                    output.push_str(&text);
+                    continue;
+                }
+            };
+            match expr {
+                Expr::Unary(unary) => {
+                    output.push_str(&format!("({} ", unary.operator));
+                    steps.push(Step::Text(")".to_string()));
+                    steps.push(Step::Expr(&unary.right));
+                }
+                Expr::Binary(binary) => {
+                    output.push('(');
+                    steps.push(Step::Text(")".to_string()));
+                    steps.push(Step::Expr(&binary.right));
+                    steps.push(Step::Text(format!(" {} ", binary.operator)));
+                    steps.push(Step::Expr(&binary.left));
+                }
+                Expr::Grouping(grouping) => {
+                    output.push_str("(group ");
+                    steps.push(Step::Text(")".to_string()));
+                    steps.push(Step::Expr(&grouping.expression));
+                }
+                Expr::Assign(assign) => {
+                    output.push_str(&format!("(= {} ", assign.name.name()));
+                    steps.push(Step::Text(")".to_string()));
+                    steps.push(Step::Expr(&assign.value));
+                }
+                Expr::Call(call) => {
+                    output.push_str("(call ");
+                    steps.push(Step::Text(")".to_string()));
+                    for argument in call.arguments.iter().rev() {
+                        steps.push(Step::Expr(argument));
+                        steps.push(Step::Text(" ".to_string()));
+                    }
+                    steps.push(Step::Expr(&call.callee));
+                }
+                _ => output.push_str(&label(expr)),
+            }
+        }
+        output
+    }
+
+    // Groupings only exist to override precedence, which RPN does not need
+    fn rpn(&self) -> String {
+        enum Step<'a> {
+            Visit(&'a Expr),
+            Emit(String),
+        }
+
+        let mut output = Vec::new();
+        let mut steps = vec![Step::Visit(self)];
+        while let Some(step) = steps.pop() {
+            let expr = match step {
+                Step::Visit(expr) => expr,
+                Step::Emit(operator) => {
+                    output.push(operator);
+                    continue;
+                }
+            };
+            match expr {
+                Expr::Unary(unary) => {
+                    // Unary minus gets its own name so it cannot be mistaken
+                    // for subtraction
+                    steps.push(Step::Emit(match unary.operator.kind {
+                        TokenKind::Minus => "neg".to_string(),
+                        _ => unary.operator.to_string(),
+                    }));
+                    steps.push(Step::Visit(&unary.right));
+                }
+                Expr::Binary(binary) => {
+                    steps.push(Step::Emit(binary.operator.to_string()));
+                    steps.push(Step::Visit(&binary.right));
+                    steps.push(Step::Visit(&binary.left));
+                }
+                Expr::Grouping(grouping) => steps.push(Step::Visit(&grouping.expression)),
+                Expr::Assign(assign) => {
+                    steps.push(Step::Emit(format!("={}", assign.name.name())));
+                    steps.push(Step::Visit(&assign.value));
+                }
+                // The argument count, since calls take any number of operands
+                Expr::Call(call) => {
+                    steps.push(Step::Emit(format!("call{}", call.arguments.len())));
+                    steps.extend(call.arguments.iter().rev().map(Step::Visit));
+                    steps.push(Step::Visit(&call.callee));
+                }
+                _ => output.push(label(expr)),
+            }
+        }
+        output.join(" ")
+    }
+
+    fn tree(&self) -> String {
+        let mut output = String::new();
+        // The indentation drawn by each ancestor below the root. Nodes come
+        // off the stack depth first, so it only ever has to be cut back to
+        // the next node's level.
+        let mut indent: Vec<&str> = Vec::new();
+        let mut steps = vec![(self, 0, "")];
+        while let Some((expr, level, connector)) = steps.pop() {
+            indent.truncate(level);
+            output.push_str(&format!(
+                "{}{}{}\n",
+                indent.concat(),
+                connector,
+                label(expr)
+            ));
+
+            let level = match connector {
+                "├── " => {
+                    indent.push("│   ");
+                    level + 1
+                }
+                "└── " => {
+                    indent.push("    ");
+                    level + 1
+                }
+                _ => level,
+            };
+            let children = operands(expr);
+            for (index, child) in children.iter().enumerate().rev() {
+                let connector = if index + 1 == children.len() {
+                    "└── "
+                } else {
+                    "├── "
+                };
+                steps.push((child, level, connector));
+            }
+        }
+        output.trim_end().to_string()
+    }
+
+    fn dot(&self) -> String {
+        let mut output = "digraph ast {\n".to_string();
+        let mut next_id = 0;
+        let mut steps = vec![(self, None)];
+        while let Some((expr, parent)) = steps.pop() {
+            let id = next_id;
+            next_id += 1;
+            if let Some(parent) = parent {
+                output.push_str(&format!("    node{} -> node{};\n", parent, id));
             }
-            Expr::Grouping(grouping) => format!("(group {})", grouping.expression.ast()),
+            output.push_str(&format!(
+                "    node{} [label=\"{}\"];\n",
+                id,
+                label(expr).replace('\\', "\\\\").replace('"', "\\\"")
+            ));
+            steps.extend(
+                operands(expr)
+                    .into_iter()
+                    .rev()
+                    .map(|child| (child, Some(id))),
+            );
+        }
+        output.push('}');
+        output
+    }
+}
+
+// How a node is labelled in the tree and DOT output, which is also how every
+// printer writes literals
+fn label(expr: &Expr) -> String {
+    match expr {
+        Expr::BooleanLiteral(value) => value.to_string(),
+        Expr::NumberLiteral(value) => value.to_string(),
+        Expr::StringLiteral(value) => format!("\"{}\"", value),
+        Expr::NilLiteral => "nil".to_string(),
+        Expr::Unary(unary) => unary.operator.to_string(),
+        Expr::Binary(binary) => binary.operator.to_string(),
+        Expr::Grouping(_) => "group".to_string(),
+        Expr::Variable(name) => name.name().to_string(),
+        Expr::Assign(assign) => format!("= {}", assign.name.name()),
+        Expr::Call(_) => "call".to_string(),
+    }
+}
+
+fn operands(expr: &Expr) -> Vec<&Expr> {
+    match expr {
+        Expr::Unary(unary) => vec![&unary.right],
+        Expr::Binary(binary) => vec![&binary.left, &binary.right],
+        Expr::Grouping(grouping) => vec![&grouping.expression],
+        Expr::Assign(assign) => vec![&assign.value],
+        Expr::Call(call) => std::iter::once(&*call.callee)
+            .chain(&call.arguments)
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        ast_display::AstDisplay,
+        expression::{BinaryExpr, Expr, GroupingExpr, UnaryExpr},
+        token::{Token, TokenKind},
+    };
+
+    // (1 + 2) * (4 - -3)
+    fn example() -> Expr {
+        Expr::Binary(BinaryExpr::new(
+            Expr::Grouping(GroupingExpr::new(Expr::Binary(BinaryExpr::new(
+                Expr::NumberLiteral(1.0),
+                Token::from(TokenKind::Plus),
+                Expr::NumberLiteral(2.0),
+            )))),
+            Token::from(TokenKind::Star),
+            Expr::Grouping(GroupingExpr::new(Expr::Binary(BinaryExpr::new(
+                Expr::NumberLiteral(4.0),
+                Token::from(TokenKind::Minus),
+                Expr::Unary(UnaryExpr::new(
+                    Token::from(TokenKind::Minus),
+                    Expr::NumberLiteral(3.0),
+                )),
+            )))),
+        ))
+    }
+
+    #[test]
+    fn test_rpn() {
+        assert_eq!(example().rpn(), "1 2 + 4 3 neg - *");
+    }
+
+    #[test]
+    fn test_tree() {
+        assert_eq!(
+            example().tree(),
+            "\
+*
+├── group
+│   └── +
+│       ├── 1
+│       └── 2
+└── group
+    └── -
+        ├── 4
+        └── -
+            └── 3"
+        );
+    }
+
+    #[test]
+    fn test_dot() {
+        let expr = Expr::Binary(BinaryExpr::new(
+            Expr::StringLiteral("a".to_string()),
+            Token::from(TokenKind::Plus),
+            Expr::NilLiteral,
+        ));
+        assert_eq!(
+            expr.dot(),
+            "\
+digraph ast {
+    node0 [label=\"+\"];
+    node0 -> node1;
+    node1 [label=\"\\\"a\\\"\"];
+    node0 -> node2;
+    node2 [label=\"nil\"];
+}"
+        );
+    }
+
+    #[test]
+    fn test_long_chain() {
+        let mut expr = Expr::NumberLiteral(0.0);
+        for _ in 0..200_000 {
+            expr = Expr::Binary(BinaryExpr::new(
+                expr,
+                Token::from(TokenKind::Plus),
+                Expr::NumberLiteral(1.0),
+            ));
         }
+        let ast = expr.ast();
+        assert!(ast.starts_with(&"(".repeat(200_000)));
+        assert!(ast.trim_start_matches('(').starts_with("0 + 1) + 1)"));
+        assert!(expr.rpn().starts_with("0 1 + 1 + 1 +"));
+        assert!(
+            expr.dot()
+                .ends_with("    node0 -> node400000;\n    node400000 [label=\"1\"];\n}")
+        );
     }
 }
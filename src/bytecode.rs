@@ -0,0 +1,414 @@
+use std::fmt::Display;
+
+use crate::{
+    chunk::{Chunk, OpCode},
+    token::Span,
+    value::Value,
+};
+
+// A compiled `.loxc` file is laid out as
+//
+//     magic     4 bytes  "LOXC"
+//     version   u16
+//     checksum  u32      CRC-32 of everything after the header
+//     code      u32 length, then the bytes
+//     constants u32 count, then a tag byte and payload for each
+//     lines     u32 count, then offset, line and column of each run
+//
+// with every integer little endian. Loading verifies the bytecode, so a file
+// that loads can be run without further checks. Only expressions are saved:
+// programs declare functions, which have no representation here.
+
+pub const MAGIC: &[u8; 4] = b"LOXC";
+pub const VERSION: u16 = 1;
+const HEADER_LEN: usize = 10;
+
+const TAG_NIL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_NUMBER: u8 = 3;
+const TAG_STRING: u8 = 4;
+
+#[derive(Debug, PartialEq)]
+pub enum LoadError {
+    NotBytecode,
+    UnsupportedVersion(u16),
+    ChecksumMismatch,
+    Truncated,
+    InvalidConstant(u8),
+    InvalidString,
+    InvalidBytecode(usize),
+    // Bytes left over after the chunk, starting at this offset in the file
+    TrailingData(usize),
+}
+
+impl From<&LoadError> for String {
+    fn from(value: &LoadError) -> Self {
+        match value {
+            LoadError::NotBytecode => "Not a compiled Lox file".to_string(),
+            LoadError::UnsupportedVersion(version) => {
+                format!(
+                    "Unsupported bytecode version {}, expected {}",
+                    version, VERSION
+                )
+            }
+            LoadError::ChecksumMismatch => "Checksum mismatch, the file is corrupt".to_string(),
+            LoadError::Truncated => "Unexpected end of file".to_string(),
+            LoadError::InvalidConstant(tag) => format!("Invalid constant tag {}", tag),
+            LoadError::InvalidString => "Invalid UTF-8 in string constant".to_string(),
+            LoadError::InvalidBytecode(offset) => {
+                format!("Invalid bytecode at offset {}", offset)
+            }
+            LoadError::TrailingData(position) => {
+                format!("Unexpected data after the chunk at byte {}", position)
+            }
+        }
+    }
+}
+
+impl From<LoadError> for String {
+    fn from(value: LoadError) -> Self {
+        String::from(&value)
+    }
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", String::from(self))
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+// Saves an expression's chunk, which has no function constants
+pub fn write(chunk: &Chunk) -> Vec<u8> {
+    let mut payload = Vec::new();
+
+    write_u32(&mut payload, chunk.code.len());
+    payload.extend_from_slice(&chunk.code);
+
+    write_u32(&mut payload, chunk.constants.len());
+    for constant in &chunk.constants {
+        match constant {
+            Value::Nil => payload.push(TAG_NIL),
+            Value::Boolean(false) => payload.push(TAG_FALSE),
+            Value::Boolean(true) => payload.push(TAG_TRUE),
+            Value::Number(value) => {
+                payload.push(TAG_NUMBER);
+                payload.extend_from_slice(&value.to_le_bytes());
+            }
+            Value::String(value) => {
+                payload.push(TAG_STRING);
+                write_u32(&mut payload, value.len());
+                payload.extend_from_slice(value.as_bytes());
+            }
+            Value::Function(_) => unreachable!("only programs have function constants"),
+        }
+    }
+
+    let runs = chunk.lines.runs();
+    write_u32(&mut payload, runs.len());
+    for (offset, span) in runs {
+        write_u32(&mut payload, *offset);
+        write_u32(&mut payload, span.line);
+        write_u32(&mut payload, span.column);
+    }
+
+    let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    bytes.extend_from_slice(&crc32(&payload).to_le_bytes());
+    bytes.extend_from_slice(&payload);
+    bytes
+}
+
+pub fn read(bytes: &[u8]) -> Result<Chunk, LoadError> {
+    if !is_bytecode(bytes) {
+        return Err(LoadError::NotBytecode);
+    }
+    let mut reader = Reader {
+        bytes,
+        position: MAGIC.len(),
+    };
+    let version = u16::from_le_bytes(reader.array()?);
+    if version != VERSION {
+        return Err(LoadError::UnsupportedVersion(version));
+    }
+    let checksum = u32::from_le_bytes(reader.array()?);
+    if crc32(&bytes[HEADER_LEN..]) != checksum {
+        return Err(LoadError::ChecksumMismatch);
+    }
+
+    let mut chunk = Chunk::new();
+    let len = reader.u32()?;
+    chunk.code = reader.take(len)?.to_vec();
+
+    for _ in 0..reader.u32()? {
+        let constant = match reader.byte()? {
+            TAG_NIL => Value::Nil,
+            TAG_FALSE => Value::Boolean(false),
+            TAG_TRUE => Value::Boolean(true),
+            TAG_NUMBER => Value::Number(f64::from_le_bytes(reader.array()?)),
+            TAG_STRING => {
+                let len = reader.u32()?;
+                let string =
+                    std::str::from_utf8(reader.take(len)?).map_err(|_| LoadError::InvalidString)?;
+                Value::String(string.to_string())
+            }
+            tag => return Err(LoadError::InvalidConstant(tag)),
+        };
+        chunk.constants.push(constant);
+    }
+
+    for _ in 0..reader.u32()? {
+        let offset = reader.u32()?;
+        let span = Span::new(reader.u32()?, reader.u32()?);
+        chunk.lines.push(offset, span);
+    }
+
+    if reader.position != bytes.len() {
+        return Err(LoadError::TrailingData(reader.position));
+    }
+    verify(&chunk)?;
+    Ok(chunk)
+}
+
+// Checks what the VM takes for granted of compiler output: every opcode is
+// valid with all its operands, constants exist, the stack never underflows
+// and the chunk ends by returning a value. Instructions only programs use
+// are invalid, as an expression can't be compiled to them.
+fn verify(chunk: &Chunk) -> Result<(), LoadError> {
+    let mut depth = 0usize;
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let invalid = LoadError::InvalidBytecode(offset);
+        let op =
+            OpCode::try_from(chunk.code[offset]).map_err(|_| LoadError::InvalidBytecode(offset))?;
+        let operands = chunk
+            .code
+            .get(offset + 1..offset + 1 + op.operand_len())
+            .ok_or(LoadError::InvalidBytecode(offset))?;
+        let next = offset + 1 + op.operand_len();
+
+        let (pops, pushes) = match op {
+            OpCode::Constant | OpCode::ConstantLong | OpCode::ReturnConstant => {
+                let mut index = [0; 4];
+                index[..operands.len()].copy_from_slice(operands);
+                if u32::from_le_bytes(index) as usize >= chunk.constants.len() {
+                    return Err(invalid);
+                }
+                if op == OpCode::ReturnConstant {
+                    return if next == chunk.code.len() {
+                        Ok(())
+                    } else {
+                        Err(invalid)
+                    };
+                }
+                (0, 1)
+            }
+            OpCode::Nil | OpCode::True | OpCode::False => (0, 1),
+            OpCode::Not | OpCode::Negate => (1, 1),
+            OpCode::Equal
+            | OpCode::NotEqual
+            | OpCode::Greater
+            | OpCode::GreaterEqual
+            | OpCode::Less
+            | OpCode::LessEqual
+            | OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide => (2, 1),
+            OpCode::GetGlobal | OpCode::SetGlobal => {
+                let name = chunk.constants.get(operands[0] as usize);
+                if !matches!(name, Some(Value::String(_))) {
+                    return Err(invalid);
+                }
+                match op {
+                    OpCode::GetGlobal => (0, 1),
+                    _ => (1, 1),
+                }
+            }
+            // The callee and its arguments
+            OpCode::Call => (operands[0] as usize + 1, 1),
+            OpCode::Return => {
+                if depth == 0 || next != chunk.code.len() {
+                    return Err(invalid);
+                }
+                return Ok(());
+            }
+            OpCode::Pop
+            | OpCode::Print
+            | OpCode::DefineGlobal
+            | OpCode::GetLocal
+            | OpCode::SetLocal
+            | OpCode::GetUpvalue
+            | OpCode::SetUpvalue
+            | OpCode::Jump
+            | OpCode::JumpIfFalse
+            | OpCode::Loop
+            | OpCode::Closure
+            | OpCode::CloseUpvalue => return Err(invalid),
+        };
+
+        depth = depth.checked_sub(pops).ok_or(invalid)? + pushes;
+        offset = next;
+    }
+    Err(LoadError::InvalidBytecode(offset))
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], LoadError> {
+        let bytes = self
+            .bytes
+            .get(self.position..self.position.saturating_add(len))
+            .ok_or(LoadError::Truncated)?;
+        self.position += len;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], LoadError> {
+        Ok(self.take(N)?.try_into().expect("took N bytes"))
+    }
+
+    fn byte(&mut self) -> Result<u8, LoadError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<usize, LoadError> {
+        Ok(u32::from_le_bytes(self.array()?) as usize)
+    }
+}
+
+fn write_u32(bytes: &mut Vec<u8>, value: usize) {
+    let value = u32::try_from(value).expect("bytecode sizes fit in 32 bits");
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+// CRC-32 as used by zip and PNG, computed bit by bit since files are small
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        bytecode::{HEADER_LEN, LoadError, VERSION, crc32, read, write},
+        chunk::{Chunk, OpCode},
+        compiler::compile,
+        peephole::optimize,
+        token::Span,
+        value::Value,
+        vm::Vm,
+    };
+
+    fn compiled(source: &str) -> Chunk {
+        compile(&crate::parse(source).expect("source parses")).expect("expression compiles")
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for source in [
+            "1 + 2 * 3",
+            "\"a\" + \"b\" == \"ab\"",
+            "!nil != (true == false)",
+            "1 +\n  -\"x\"",
+            "(\"a\")",
+            "f(x = 1, x)",
+        ] {
+            let chunk = compiled(source);
+            for chunk in [optimize(&chunk), chunk] {
+                let loaded = read(&write(&chunk)).expect("written bytecode loads");
+                assert_eq!(loaded, chunk);
+                assert_eq!(Vm::new().run(&loaded), Vm::new().run(&chunk));
+            }
+        }
+    }
+
+    #[test]
+    fn test_rejects_bad_headers() {
+        let bytes = write(&compiled("1 + 2"));
+        assert_eq!(read(b"print 1;"), Err(LoadError::NotBytecode));
+
+        let mut newer = bytes.clone();
+        newer[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert_eq!(
+            read(&newer),
+            Err(LoadError::UnsupportedVersion(VERSION + 1))
+        );
+
+        let mut corrupt = bytes.clone();
+        *corrupt.last_mut().expect("file is not empty") ^= 1;
+        assert_eq!(read(&corrupt), Err(LoadError::ChecksumMismatch));
+
+        assert_eq!(read(&bytes[..HEADER_LEN - 1]), Err(LoadError::Truncated));
+
+        // Covered by the checksum, so only the length gives it away
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        let checksum = crc32(&trailing[HEADER_LEN..]);
+        trailing[6..HEADER_LEN].copy_from_slice(&checksum.to_le_bytes());
+        assert_eq!(read(&trailing), Err(LoadError::TrailingData(bytes.len())));
+    }
+
+    #[test]
+    fn test_rejects_invalid_bytecode() {
+        let span = Span::new(1, 1);
+        let mut underflow = Chunk::new();
+        underflow.write_constant(Value::Number(1.0), span);
+        underflow.write_op(OpCode::Add, span);
+        underflow.write_op(OpCode::Return, span);
+
+        let mut missing_constant = Chunk::new();
+        missing_constant.write_op(OpCode::Constant, span);
+        missing_constant.write_byte(3, span);
+        missing_constant.write_op(OpCode::Return, span);
+
+        let mut no_return = Chunk::new();
+        no_return.write_op(OpCode::Nil, span);
+
+        let mut bad_opcode = Chunk::new();
+        bad_opcode.write_byte(0xff, span);
+
+        let mut program_only = Chunk::new();
+        program_only.write_op(OpCode::Nil, span);
+        program_only.write_op(OpCode::Pop, span);
+        program_only.write_op(OpCode::Nil, span);
+        program_only.write_op(OpCode::Return, span);
+
+        for (chunk, offset) in [
+            (underflow, 2),
+            (missing_constant, 0),
+            (no_return, 1),
+            (bad_opcode, 0),
+            (program_only, 1),
+        ] {
+            assert_eq!(
+                read(&write(&chunk)),
+                Err(LoadError::InvalidBytecode(offset))
+            );
+        }
+    }
+}
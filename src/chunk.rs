@@ -0,0 +1,287 @@
+use crate::{token::Span, value::Value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum OpCode {
+    // Followed by a one byte constant index
+    Constant,
+    // Followed by a three byte little endian constant index
+    ConstantLong,
+    Nil,
+    True,
+    False,
+    Equal,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Not,
+    Negate,
+    Return,
+    // The superinstructions below are only made by the peephole optimizer
+    // `Equal` then `Not`
+    NotEqual,
+    // `Constant` then `Return`, followed by the same one byte index
+    ReturnConstant,
+    // The instructions below are only compiled from programs, apart from the
+    // global and call instructions an expression on its own can use
+    Pop,
+    Print,
+    // Each followed by the one byte constant index of the variable's name
+    DefineGlobal,
+    GetGlobal,
+    SetGlobal,
+    // Followed by a one byte slot in the current call's stack window
+    GetLocal,
+    SetLocal,
+    // Followed by a one byte index into the running closure's upvalues
+    GetUpvalue,
+    SetUpvalue,
+    // Followed by a two byte little endian distance from the end of the
+    // instruction, forwards for the jumps and backwards for `Loop`.
+    // `JumpIfFalse` pops the condition.
+    Jump,
+    JumpIfFalse,
+    Loop,
+    // Followed by a one byte argument count
+    Call,
+    // Followed by the one byte constant index of the function to close over
+    Closure,
+    // Moves the variable on top of the stack to the heap for the closures
+    // that captured it, then pops it
+    CloseUpvalue,
+}
+
+impl OpCode {
+    const ALL: [OpCode; 34] = [
+        OpCode::Constant,
+        OpCode::ConstantLong,
+        OpCode::Nil,
+        OpCode::True,
+        OpCode::False,
+        OpCode::Equal,
+        OpCode::Greater,
+        OpCode::GreaterEqual,
+        OpCode::Less,
+        OpCode::LessEqual,
+        OpCode::Add,
+        OpCode::Subtract,
+        OpCode::Multiply,
+        OpCode::Divide,
+        OpCode::Not,
+        OpCode::Negate,
+        OpCode::Return,
+        OpCode::NotEqual,
+        OpCode::ReturnConstant,
+        OpCode::Pop,
+        OpCode::Print,
+        OpCode::DefineGlobal,
+        OpCode::GetGlobal,
+        OpCode::SetGlobal,
+        OpCode::GetLocal,
+        OpCode::SetLocal,
+        OpCode::GetUpvalue,
+        OpCode::SetUpvalue,
+        OpCode::Jump,
+        OpCode::JumpIfFalse,
+        OpCode::Loop,
+        OpCode::Call,
+        OpCode::Closure,
+        OpCode::CloseUpvalue,
+    ];
+
+    // The number of operand bytes following the opcode
+    pub fn operand_len(self) -> usize {
+        match self {
+            OpCode::Constant
+            | OpCode::ReturnConstant
+            | OpCode::DefineGlobal
+            | OpCode::GetGlobal
+            | OpCode::SetGlobal
+            | OpCode::GetLocal
+            | OpCode::SetLocal
+            | OpCode::GetUpvalue
+            | OpCode::SetUpvalue
+            | OpCode::Call
+            | OpCode::Closure => 1,
+            OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop => 2,
+            OpCode::ConstantLong => 3,
+            _ => 0,
+        }
+    }
+}
+
+impl TryFrom<u8> for OpCode {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        OpCode::ALL.get(value as usize).copied().ok_or(value)
+    }
+}
+
+// The largest index a `ConstantLong` operand can hold
+pub const MAX_CONSTANTS: usize = 1 << 24;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Chunk {
+    pub code: Vec<u8>,
+    pub constants: Vec<Value>,
+    pub lines: LineTable,
+}
+
+impl Chunk {
+    pub fn new() -> Chunk {
+        Chunk::default()
+    }
+
+    pub fn write_op(&mut self, op: OpCode, span: Span) {
+        self.write_byte(op as u8, span);
+    }
+
+    pub fn write_byte(&mut self, byte: u8, span: Span) {
+        self.lines.push(self.code.len(), span);
+        self.code.push(byte);
+    }
+
+    // Where in the source the byte at `offset` was compiled from
+    pub fn span(&self, offset: usize) -> Span {
+        self.lines.get(offset)
+    }
+
+    pub fn add_constant(&mut self, value: Value) -> Option<usize> {
+        if self.constants.len() == MAX_CONSTANTS {
+            return None;
+        }
+        self.constants.push(value);
+        Some(self.constants.len() - 1)
+    }
+
+    // Adds `value` to the constant pool and the instruction that loads it,
+    // or returns `None` when the pool is full
+    pub fn write_constant(&mut self, value: Value, span: Span) -> Option<()> {
+        let index = self.add_constant(value)?;
+        if let Ok(index) = u8::try_from(index) {
+            self.write_op(OpCode::Constant, span);
+            self.write_byte(index, span);
+        } else {
+            self.write_op(OpCode::ConstantLong, span);
+            for byte in &index.to_le_bytes()[..3] {
+                self.write_byte(*byte, span);
+            }
+        }
+        Some(())
+    }
+}
+
+// A compiled function, or the script at the top of a program. Functions
+// declared in it are among its constants, so `Closure` can refer to them.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Prototype {
+    // Empty for the script
+    pub name: String,
+    pub arity: u8,
+    pub chunk: Chunk,
+    // Where each of the function's upvalues is captured from when a closure
+    // is made of it
+    pub upvalues: Vec<Capture>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Capture {
+    // A local of the enclosing function, rather than one of its upvalues
+    pub is_local: bool,
+    pub index: u8,
+}
+
+// Source positions of the bytes of a chunk, run length encoded. Each run
+// starts at a code offset and covers every byte up to the start of the next,
+// so consecutive instructions from the same token share one entry.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LineTable {
+    runs: Vec<(usize, Span)>,
+}
+
+impl LineTable {
+    // Records that the bytes from `offset` on come from `span`. Offsets must
+    // be pushed in increasing order.
+    pub fn push(&mut self, offset: usize, span: Span) {
+        if self.runs.last().is_some_and(|(_, last)| *last == span) {
+            return;
+        }
+        self.runs.push((offset, span));
+    }
+
+    pub fn get(&self, offset: usize) -> Span {
+        let index = self.runs.partition_point(|(start, _)| *start <= offset);
+        index
+            .checked_sub(1)
+            .map_or(Span::default(), |index| self.runs[index].1)
+    }
+
+    pub fn runs(&self) -> &[(usize, Span)] {
+        &self.runs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        chunk::{Chunk, LineTable, OpCode},
+        token::Span,
+        value::Value,
+    };
+
+    #[test]
+    fn test_opcodes_round_trip() {
+        for op in OpCode::ALL {
+            assert_eq!(OpCode::try_from(op as u8), Ok(op));
+        }
+        assert_eq!(OpCode::try_from(OpCode::ALL.len() as u8), Err(34));
+    }
+
+    #[test]
+    fn test_long_constants() {
+        let mut chunk = Chunk::new();
+        for index in 0..300 {
+            chunk.write_constant(Value::Number(index as f64), Span::new(1, 1));
+        }
+        assert_eq!(&chunk.code[..2], &[OpCode::Constant as u8, 0]);
+        assert_eq!(
+            &chunk.code[chunk.code.len() - 4..],
+            &[OpCode::ConstantLong as u8, 43, 1, 0]
+        );
+        assert_eq!(chunk.lines.runs().len(), 1);
+    }
+
+    #[test]
+    fn test_line_table() {
+        let mut lines = LineTable::default();
+        lines.push(0, Span::new(1, 1));
+        lines.push(1, Span::new(1, 1));
+        lines.push(2, Span::new(1, 5));
+        lines.push(6, Span::new(3, 2));
+        lines.push(7, Span::new(1, 5));
+        assert_eq!(lines.runs().len(), 4);
+
+        let spans = (0..9).map(|offset| lines.get(offset)).collect::<Vec<_>>();
+        assert_eq!(
+            spans,
+            [
+                Span::new(1, 1),
+                Span::new(1, 1),
+                Span::new(1, 5),
+                Span::new(1, 5),
+                Span::new(1, 5),
+                Span::new(1, 5),
+                Span::new(3, 2),
+                Span::new(1, 5),
+                Span::new(1, 5),
+            ]
+        );
+        assert_eq!(LineTable::default().get(0), Span::default());
+    }
+}
@@ -0,0 +1,716 @@
+use std::{fmt::Display, mem, rc::Rc};
+
+use crate::{
+    arena::{ArenaExpr, ExprArena, ExprId, ExprMap},
+    chunk::{Capture, Chunk, OpCode, Prototype},
+    expression::Expr,
+    statement::{FunctionStmt, Stmt},
+    token::{Span, Token, TokenKind},
+    value::{Function, Value},
+};
+
+// A function's locals are addressed by a byte, as are its upvalues
+const MAX_LOCALS: usize = 256;
+const MAX_UPVALUES: usize = 256;
+
+#[derive(Debug, PartialEq)]
+pub enum CompileError {
+    TooManyConstants,
+    UnknownOperator(TokenKind),
+    TooManyLocals,
+    TooManyUpvalues,
+    JumpTooFar,
+}
+
+impl From<&CompileError> for String {
+    fn from(value: &CompileError) -> Self {
+        match value {
+            CompileError::TooManyConstants => "Too many constants in one chunk".to_string(),
+            CompileError::UnknownOperator(kind) => format!("Unknown operator {}", kind),
+            CompileError::TooManyLocals => "Too many local variables in function".to_string(),
+            CompileError::TooManyUpvalues => "Too many closure variables in function".to_string(),
+            CompileError::JumpTooFar => "Too much code to jump over".to_string(),
+        }
+    }
+}
+
+impl From<CompileError> for String {
+    fn from(value: CompileError) -> Self {
+        String::from(&value)
+    }
+}
+
+impl Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", String::from(self))
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+// Compiles `expr` into a chunk that leaves its value on the stack and returns
+pub fn compile(expr: &Expr) -> Result<Chunk, CompileError> {
+    let mut arena = ExprArena::new();
+    let root = arena.lower(expr);
+    compile_arena(&arena, root)
+}
+
+// Compiles the tree rooted at `root`, which may share `arena` with others.
+// An expression on its own declares nothing, so every variable in it is a
+// global.
+pub fn compile_arena(arena: &ExprArena, root: ExprId) -> Result<Chunk, CompileError> {
+    let mut chunk = Chunk::new();
+    let span = emit_tree(arena, root, Span::new(1, 1), &mut chunk, &mut |_| {
+        Ok(Resolved::Global)
+    })?;
+    chunk.write_op(OpCode::Return, span);
+    Ok(chunk)
+}
+
+// Compiles a program into the script that runs it, which returns nil
+pub fn compile_program(program: &[Stmt]) -> Result<Prototype, CompileError> {
+    let mut compiler = ProgramCompiler {
+        functions: vec![FunctionCompiler::new(String::new(), 0)],
+        span: Span::new(1, 1),
+    };
+    for statement in program {
+        compiler.statement(statement)?;
+    }
+    compiler.op(OpCode::Nil);
+    compiler.op(OpCode::Return);
+    Ok(compiler
+        .functions
+        .pop()
+        .expect("the script is compiled last")
+        .prototype)
+}
+
+// Where a variable named in an expression is found
+enum Resolved {
+    // A slot in the current call's stack window
+    Local(u8),
+    Upvalue(u8),
+    Global,
+}
+
+type Resolve<'a> = dyn FnMut(&str) -> Result<Resolved, CompileError> + 'a;
+
+// Emits the code for the tree rooted at `root` and returns the position
+// attributed to it, or `fallback` if none is known
+fn emit_tree(
+    arena: &ExprArena,
+    root: ExprId,
+    fallback: Span,
+    chunk: &mut Chunk,
+    resolve: &mut Resolve,
+) -> Result<Span, CompileError> {
+    let spans = spans(arena, root, fallback);
+
+    // Operands are emitted before their operator, from an explicit stack so
+    // that deep trees can't overflow the native one
+    let mut stack = vec![Step::Visit(root)];
+    while let Some(step) = stack.pop() {
+        match step {
+            Step::Visit(id) => match &arena[id] {
+                ArenaExpr::Unary { right, .. } => {
+                    stack.extend([Step::Emit(id), Step::Visit(*right)])
+                }
+                ArenaExpr::Binary { left, right, .. } => {
+                    stack.extend([Step::Emit(id), Step::Visit(*right), Step::Visit(*left)])
+                }
+                ArenaExpr::Grouping { expression } => stack.push(Step::Visit(*expression)),
+                ArenaExpr::Assign { value, .. } => {
+                    stack.extend([Step::Emit(id), Step::Visit(*value)])
+                }
+                ArenaExpr::Call {
+                    callee, arguments, ..
+                } => {
+                    stack.push(Step::Emit(id));
+                    stack.extend(
+                        arguments
+                            .iter()
+                            .rev()
+                            .map(|argument| Step::Visit(*argument)),
+                    );
+                    stack.push(Step::Visit(*callee));
+                }
+                _ => emit(&arena[id], spans[id], chunk, resolve)?,
+            },
+            Step::Emit(id) => emit(&arena[id], spans[id], chunk, resolve)?,
+        }
+    }
+
+    Ok(spans[root])
+}
+
+enum Step {
+    Visit(ExprId),
+    Emit(ExprId),
+}
+
+// The position each node's code is attributed to. Literals don't keep their
+// tokens, so they take the position of the operator that uses them, and the
+// root falls back to the first operator inside any groupings around it.
+// Parents always come after their children, so sweeping down from the root
+// reaches every node of its tree after its parent.
+fn spans(arena: &ExprArena, root: ExprId, fallback: Span) -> ExprMap<Span> {
+    let mut inner = root;
+    while let ArenaExpr::Grouping { expression } = &arena[inner] {
+        inner = *expression;
+    }
+    let mut spans = ExprMap::new();
+    spans.insert(root, operator_span(&arena[inner]).unwrap_or(fallback));
+
+    for (id, node) in arena.iter().take(root.index() + 1).rev() {
+        // Not part of this tree
+        let Some(&parent) = spans.get(id) else {
+            continue;
+        };
+        let span = operator_span(node).unwrap_or(parent);
+        spans.insert(id, span);
+        for child in node.children() {
+            spans.insert(child, span);
+        }
+    }
+    spans
+}
+
+fn operator_span(node: &ArenaExpr) -> Option<Span> {
+    let span = match node {
+        ArenaExpr::Unary { operator, .. } | ArenaExpr::Binary { operator, .. } => operator.span,
+        ArenaExpr::Variable(name) | ArenaExpr::Assign { name, .. } => name.span,
+        ArenaExpr::Call { paren, .. } => paren.span,
+        _ => return None,
+    };
+    span.is_known().then_some(span)
+}
+
+fn emit(
+    node: &ArenaExpr,
+    span: Span,
+    chunk: &mut Chunk,
+    resolve: &mut Resolve,
+) -> Result<(), CompileError> {
+    match node {
+        ArenaExpr::BooleanLiteral(true) => chunk.write_op(OpCode::True, span),
+        ArenaExpr::BooleanLiteral(false) => chunk.write_op(OpCode::False, span),
+        ArenaExpr::NilLiteral => chunk.write_op(OpCode::Nil, span),
+        ArenaExpr::NumberLiteral(value) => chunk
+            .write_constant(Value::Number(*value), span)
+            .ok_or(CompileError::TooManyConstants)?,
+        ArenaExpr::StringLiteral(value) => chunk
+            .write_constant(Value::String(value.clone()), span)
+            .ok_or(CompileError::TooManyConstants)?,
+        ArenaExpr::Unary { operator, .. } => match operator.kind {
+            TokenKind::Minus => chunk.write_op(OpCode::Negate, span),
+            TokenKind::Bang => chunk.write_op(OpCode::Not, span),
+            ref kind => return Err(CompileError::UnknownOperator(kind.clone())),
+        },
+        ArenaExpr::Binary { operator, .. } => {
+            let op = match operator.kind {
+                TokenKind::EqualEqual => OpCode::Equal,
+                // There is no opcode for `!=`, since `!(a == b)` means the same
+                TokenKind::BangEqual => {
+                    chunk.write_op(OpCode::Equal, span);
+                    OpCode::Not
+                }
+                TokenKind::Greater => OpCode::Greater,
+                TokenKind::GreaterEqual => OpCode::GreaterEqual,
+                TokenKind::Less => OpCode::Less,
+                TokenKind::LessEqual => OpCode::LessEqual,
+                TokenKind::Plus => OpCode::Add,
+                TokenKind::Minus => OpCode::Subtract,
+                TokenKind::Star => OpCode::Multiply,
+                TokenKind::Slash => OpCode::Divide,
+                ref kind => return Err(CompileError::UnknownOperator(kind.clone())),
+            };
+            chunk.write_op(op, span);
+        }
+        ArenaExpr::Grouping { .. } => {}
+        ArenaExpr::Variable(name) => {
+            let (op, operand) = match resolve(name.name())? {
+                Resolved::Local(slot) => (OpCode::GetLocal, slot),
+                Resolved::Upvalue(index) => (OpCode::GetUpvalue, index),
+                Resolved::Global => (OpCode::GetGlobal, name_constant(chunk, name)?),
+            };
+            chunk.write_op(op, span);
+            chunk.write_byte(operand, span);
+        }
+        ArenaExpr::Assign { name, .. } => {
+            let (op, operand) = match resolve(name.name())? {
+                Resolved::Local(slot) => (OpCode::SetLocal, slot),
+                Resolved::Upvalue(index) => (OpCode::SetUpvalue, index),
+                Resolved::Global => (OpCode::SetGlobal, name_constant(chunk, name)?),
+            };
+            chunk.write_op(op, span);
+            chunk.write_byte(operand, span);
+        }
+        ArenaExpr::Call { arguments, .. } => {
+            chunk.write_op(OpCode::Call, span);
+            // The parser allows no more arguments than fit
+            chunk.write_byte(arguments.len() as u8, span);
+        }
+    }
+    Ok(())
+}
+
+// The index of the constant holding a global's name, shared by every use of
+// it in the chunk. Global instructions take the index in a single byte.
+fn name_constant(chunk: &mut Chunk, name: &Token) -> Result<u8, CompileError> {
+    let existing = chunk
+        .constants
+        .iter()
+        .position(|constant| matches!(constant, Value::String(string) if string == name.name()));
+    let index = match existing {
+        Some(index) => index,
+        None => chunk
+            .add_constant(Value::String(name.name().to_string()))
+            .ok_or(CompileError::TooManyConstants)?,
+    };
+    u8::try_from(index).map_err(|_| CompileError::TooManyConstants)
+}
+
+// Compiles statements into the function being compiled, which is the
+// innermost of `functions`. Variables declared outside of any block or
+// function are globals, and the rest are locals that live on the stack.
+// Locals a closure captures are moved to the heap by `CloseUpvalue` or
+// `Return` when they go out of scope.
+struct ProgramCompiler {
+    // The script, then each function enclosing the current statement
+    functions: Vec<FunctionCompiler>,
+    // Where the instructions that belong to no expression are attributed
+    span: Span,
+}
+
+struct FunctionCompiler {
+    prototype: Prototype,
+    // Every local in scope, innermost last. The first is the slot that holds
+    // the function being called.
+    locals: Vec<Local>,
+    // How many blocks enclose the current statement, counting the function
+    // body
+    depth: usize,
+}
+
+struct Local {
+    name: String,
+    depth: usize,
+    // Whether a closure refers to it, so it must be moved to the heap
+    // rather than just popped at the end of its scope
+    captured: bool,
+}
+
+impl FunctionCompiler {
+    fn new(name: String, arity: u8) -> FunctionCompiler {
+        let script = name.is_empty();
+        FunctionCompiler {
+            prototype: Prototype {
+                name,
+                arity,
+                ..Prototype::default()
+            },
+            locals: vec![Local {
+                name: String::new(),
+                depth: 0,
+                captured: false,
+            }],
+            depth: usize::from(!script),
+        }
+    }
+
+    fn local(&self, name: &str) -> Option<u8> {
+        let slot = self.locals.iter().rposition(|local| local.name == name)?;
+        Some(slot as u8)
+    }
+
+    fn add_upvalue(&mut self, capture: Capture) -> Result<Resolved, CompileError> {
+        let upvalues = &mut self.prototype.upvalues;
+        let index = match upvalues.iter().position(|existing| *existing == capture) {
+            Some(index) => index,
+            None if upvalues.len() == MAX_UPVALUES => return Err(CompileError::TooManyUpvalues),
+            None => {
+                upvalues.push(capture);
+                upvalues.len() - 1
+            }
+        };
+        Ok(Resolved::Upvalue(index as u8))
+    }
+}
+
+impl ProgramCompiler {
+    fn function(&mut self) -> &mut FunctionCompiler {
+        self.functions
+            .last_mut()
+            .expect("the script is always there")
+    }
+
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.function().prototype.chunk
+    }
+
+    fn op(&mut self, op: OpCode) {
+        let span = self.span;
+        self.chunk().write_op(op, span);
+    }
+
+    fn byte(&mut self, byte: u8) {
+        let span = self.span;
+        self.chunk().write_byte(byte, span);
+    }
+
+    fn statement(&mut self, statement: &Stmt) -> Result<(), CompileError> {
+        match statement {
+            Stmt::Expression(expression) => {
+                self.expression(expression)?;
+                self.op(OpCode::Pop);
+            }
+            Stmt::Print(print) => {
+                self.expression(&print.expression)?;
+                self.span = print.keyword.span;
+                self.op(OpCode::Print);
+            }
+            Stmt::Var(var) => {
+                self.span = var.name.span;
+                match &var.initializer {
+                    Some(initializer) => self.expression(initializer)?,
+                    None => self.op(OpCode::Nil),
+                }
+                self.define(&var.name)?;
+            }
+            Stmt::Block(statements) => {
+                self.function().depth += 1;
+                for statement in statements {
+                    self.statement(statement)?;
+                }
+                self.end_scope();
+            }
+            Stmt::If(if_stmt) => {
+                self.expression(&if_stmt.condition)?;
+                let then_jump = self.jump(OpCode::JumpIfFalse);
+                self.statement(&if_stmt.then_branch)?;
+                match &if_stmt.else_branch {
+                    Some(else_branch) => {
+                        let else_jump = self.jump(OpCode::Jump);
+                        self.patch(then_jump)?;
+                        self.statement(else_branch)?;
+                        self.patch(else_jump)?;
+                    }
+                    None => self.patch(then_jump)?,
+                }
+            }
+            Stmt::While(while_stmt) => {
+                let start = self.chunk().code.len();
+                self.expression(&while_stmt.condition)?;
+                let exit = self.jump(OpCode::JumpIfFalse);
+                self.statement(&while_stmt.body)?;
+                self.loop_back(start)?;
+                self.patch(exit)?;
+            }
+            Stmt::Function(declaration) => {
+                self.span = declaration.name.span;
+                // A local function is in scope in its own body, so it can
+                // call itself, while a global is found by name when called
+                if self.function().depth > 0 {
+                    self.add_local(&declaration.name)?;
+                }
+                self.closure(declaration)?;
+                if self.function().depth == 0 {
+                    self.define(&declaration.name)?;
+                }
+            }
+            Stmt::Return(return_stmt) => {
+                match &return_stmt.value {
+                    Some(value) => self.expression(value)?,
+                    None => self.op(OpCode::Nil),
+                }
+                self.span = return_stmt.keyword.span;
+                self.op(OpCode::Return);
+            }
+        }
+        Ok(())
+    }
+
+    fn expression(&mut self, expr: &Expr) -> Result<(), CompileError> {
+        let mut arena = ExprArena::new();
+        let root = arena.lower(expr);
+        let level = self.functions.len() - 1;
+
+        // The chunk is taken out while the expression is emitted into it, as
+        // resolving variables may add upvalues to the functions
+        let mut chunk = mem::take(self.chunk());
+        let span = emit_tree(&arena, root, self.span, &mut chunk, &mut |name| {
+            self.resolve(level, name)
+        });
+        *self.chunk() = chunk;
+        self.span = span?;
+        Ok(())
+    }
+
+    // Looks `name` up in the function at `level`, then in each enclosing
+    // one. A local of an enclosing function is captured, becoming an upvalue
+    // of every function in between.
+    fn resolve(&mut self, level: usize, name: &str) -> Result<Resolved, CompileError> {
+        if let Some(slot) = self.functions[level].local(name) {
+            return Ok(Resolved::Local(slot));
+        }
+        if level == 0 {
+            return Ok(Resolved::Global);
+        }
+        let capture = match self.resolve(level - 1, name)? {
+            Resolved::Local(slot) => {
+                self.functions[level - 1].locals[slot as usize].captured = true;
+                Capture {
+                    is_local: true,
+                    index: slot,
+                }
+            }
+            Resolved::Upvalue(index) => Capture {
+                is_local: false,
+                index,
+            },
+            Resolved::Global => return Ok(Resolved::Global),
+        };
+        self.functions[level].add_upvalue(capture)
+    }
+
+    // Binds the value on top of the stack to `name`
+    fn define(&mut self, name: &Token) -> Result<(), CompileError> {
+        if self.function().depth > 0 {
+            return self.add_local(name);
+        }
+        let index = name_constant(self.chunk(), name)?;
+        self.op(OpCode::DefineGlobal);
+        self.byte(index);
+        Ok(())
+    }
+
+    fn add_local(&mut self, name: &Token) -> Result<(), CompileError> {
+        let function = self.function();
+        if function.locals.len() == MAX_LOCALS {
+            return Err(CompileError::TooManyLocals);
+        }
+        let depth = function.depth;
+        function.locals.push(Local {
+            name: name.name().to_string(),
+            depth,
+            captured: false,
+        });
+        Ok(())
+    }
+
+    // Pops the locals of the block that is ending
+    fn end_scope(&mut self) {
+        let function = self.function();
+        function.depth -= 1;
+        let depth = function.depth;
+        while let Some(local) = self.function().locals.pop_if(|local| local.depth > depth) {
+            self.op(match local.captured {
+                true => OpCode::CloseUpvalue,
+                false => OpCode::Pop,
+            });
+        }
+    }
+
+    // Compiles a function declaration into a closure left on the stack
+    fn closure(&mut self, declaration: &FunctionStmt) -> Result<(), CompileError> {
+        let name = declaration.name.name().to_string();
+        // The parser allows no more parameters than fit
+        let arity = declaration.params.len() as u8;
+        self.functions
+            .push(FunctionCompiler::new(name.clone(), arity));
+        for param in &declaration.params {
+            self.add_local(param)?;
+        }
+        for statement in &declaration.body {
+            self.statement(statement)?;
+        }
+        self.op(OpCode::Nil);
+        self.op(OpCode::Return);
+        let prototype = self.functions.pop().expect("pushed above").prototype;
+
+        let function = Value::Function(Function::new(&name, Rc::new(prototype)));
+        let index = self
+            .chunk()
+            .add_constant(function)
+            .and_then(|index| u8::try_from(index).ok())
+            .ok_or(CompileError::TooManyConstants)?;
+        self.span = declaration.name.span;
+        self.op(OpCode::Closure);
+        self.byte(index);
+        Ok(())
+    }
+
+    // Emits a jump to be patched once its target is known, returning where
+    // its operand is
+    fn jump(&mut self, op: OpCode) -> usize {
+        self.op(op);
+        self.byte(0xff);
+        self.byte(0xff);
+        self.chunk().code.len() - 2
+    }
+
+    // Points the jump with its operand at `operand` to the end of the code
+    fn patch(&mut self, operand: usize) -> Result<(), CompileError> {
+        let code = &mut self.chunk().code;
+        let distance =
+            u16::try_from(code.len() - operand - 2).map_err(|_| CompileError::JumpTooFar)?;
+        code[operand..operand + 2].copy_from_slice(&distance.to_le_bytes());
+        Ok(())
+    }
+
+    fn loop_back(&mut self, start: usize) -> Result<(), CompileError> {
+        self.op(OpCode::Loop);
+        let distance = u16::try_from(self.chunk().code.len() + 2 - start)
+            .map_err(|_| CompileError::JumpTooFar)?;
+        for byte in distance.to_le_bytes() {
+            self.byte(byte);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        arena::ExprArena,
+        chunk::{
+            Capture,
+            OpCode::{self, *},
+            Prototype,
+        },
+        compiler::{CompileError, compile, compile_arena, compile_program},
+        parser::Parser,
+        token::Span,
+        value::Value,
+    };
+
+    fn ops(source: &str) -> Vec<u8> {
+        let expr = crate::parse(source).expect("source parses");
+        compile(&expr).expect("expression compiles").code
+    }
+
+    fn bytes(ops: &[OpCode]) -> Vec<u8> {
+        ops.iter().map(|op| *op as u8).collect()
+    }
+
+    #[test]
+    fn test_compiles_in_postfix_order() {
+        let expr = crate::parse("-(1 + 2) * 3").expect("source parses");
+        let chunk = compile(&expr).expect("expression compiles");
+        assert_eq!(
+            chunk.code,
+            [
+                Constant as u8,
+                0,
+                Constant as u8,
+                1,
+                Add as u8,
+                Negate as u8,
+                Constant as u8,
+                2,
+                Multiply as u8,
+                Return as u8
+            ]
+        );
+        assert_eq!(
+            chunk.constants,
+            [Value::Number(1.0), Value::Number(2.0), Value::Number(3.0)]
+        );
+    }
+
+    #[test]
+    fn test_records_operator_lines() {
+        let expr = crate::parse("1 +\n2 *\n\n-3").expect("source parses");
+        let chunk = compile(&expr).expect("expression compiles");
+        let lines = (0..chunk.code.len())
+            .map(|offset| chunk.span(offset).line)
+            .collect::<Vec<_>>();
+        assert_eq!(lines, [1, 1, 2, 2, 4, 4, 4, 2, 1, 1]);
+        assert_eq!(chunk.span(6), Span::new(4, 1));
+        assert_eq!(chunk.lines.runs().len(), 5);
+    }
+
+    #[test]
+    fn test_compiles_one_tree_of_an_arena() {
+        let mut arena = ExprArena::new();
+        let mut parse_into = |source: &str| {
+            Parser::new(crate::lex(source).expect("source lexes"))
+                .parse_into(&mut arena)
+                .expect("source parses")
+        };
+        let first = parse_into("1 +\n-2");
+        let second = parse_into("!(true == nil)");
+
+        for (root, source) in [(first, "1 +\n-2"), (second, "!(true == nil)")] {
+            let chunk = compile_arena(&arena, root).expect("expression compiles");
+            let expected = compile(&crate::parse(source).expect("source parses"))
+                .expect("expression compiles");
+            assert_eq!(chunk, expected);
+        }
+    }
+
+    #[test]
+    fn test_literals_and_not_equal() {
+        assert_eq!(
+            ops("true != !nil"),
+            bytes(&[True, Nil, Not, Equal, Not, Return])
+        );
+    }
+
+    #[test]
+    fn test_captured_locals_are_closed() {
+        let program = crate::parse_program("{ var a = 1; var b = 2; fun f() { return a; } }")
+            .expect("source parses");
+        let script = compile_program(&program).expect("program compiles");
+        // `f` and `b` are popped, while `a` is moved to the heap
+        assert_eq!(
+            script.chunk.code,
+            [
+                Constant as u8,
+                0,
+                Constant as u8,
+                1,
+                Closure as u8,
+                2,
+                Pop as u8,
+                Pop as u8,
+                CloseUpvalue as u8,
+                Nil as u8,
+                Return as u8
+            ]
+        );
+
+        let Value::Function(function) = &script.chunk.constants[2] else {
+            panic!("expected a function constant");
+        };
+        let prototype = function
+            .object()
+            .downcast_ref::<Prototype>()
+            .expect("function constants hold a prototype");
+        assert_eq!(
+            prototype.upvalues,
+            [Capture {
+                is_local: true,
+                index: 1
+            }]
+        );
+        assert_eq!(
+            prototype.chunk.code,
+            [GetUpvalue as u8, 0, Return as u8, Nil as u8, Return as u8]
+        );
+    }
+
+    #[test]
+    fn test_too_many_locals() {
+        // Slot 0 holds the function being run, leaving 255 for variables
+        let block = |count: usize| {
+            let vars = (0..count)
+                .map(|index| format!("var v{index};"))
+                .collect::<String>();
+            let program = crate::parse_program(&format!("{{ {vars} }}")).expect("source parses");
+            compile_program(&program).map(|_| ())
+        };
+        assert_eq!(block(255), Ok(()));
+        assert_eq!(block(256), Err(CompileError::TooManyLocals));
+    }
+}
@@ -0,0 +1,607 @@
+use std::{fmt::Display, ops::Range, rc::Rc};
+
+use crate::{
+    expression::{BinaryExpr, Expr, GroupingExpr, UnaryExpr},
+    token::{Token, TokenKind, TokenValue},
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SyntaxKind {
+    Token(TokenKind),
+    Whitespace,
+    Comment,
+    // Source text the lexer could not turn into a token
+    Unknown,
+
+    Root,
+    Literal,
+    Variable,
+    Unary,
+    Binary,
+    Grouping,
+    Error,
+}
+
+impl SyntaxKind {
+    pub fn is_trivia(&self) -> bool {
+        matches!(self, SyntaxKind::Whitespace | SyntaxKind::Comment)
+    }
+}
+
+// Green tree: immutable, position independent and shareable between trees.
+
+#[derive(Debug, PartialEq)]
+pub struct GreenToken {
+    kind: SyntaxKind,
+    text: String,
+}
+
+impl GreenToken {
+    pub fn new(kind: SyntaxKind, text: String) -> Rc<GreenToken> {
+        Rc::new(GreenToken { kind, text })
+    }
+
+    pub fn kind(&self) -> &SyntaxKind {
+        &self.kind
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct GreenNode {
+    kind: SyntaxKind,
+    children: Vec<GreenElement>,
+    text_len: usize,
+}
+
+impl GreenNode {
+    pub fn new(kind: SyntaxKind, children: Vec<GreenElement>) -> Rc<GreenNode> {
+        let text_len = children.iter().map(GreenElement::text_len).sum();
+        Rc::new(GreenNode {
+            kind,
+            children,
+            text_len,
+        })
+    }
+
+    pub fn kind(&self) -> &SyntaxKind {
+        &self.kind
+    }
+
+    pub fn children(&self) -> &[GreenElement] {
+        &self.children
+    }
+
+    pub fn text_len(&self) -> usize {
+        self.text_len
+    }
+
+    pub fn replace_child(&self, index: usize, replacement: GreenElement) -> Rc<GreenNode> {
+        let mut children = self.children.clone();
+        children[index] = replacement;
+        GreenNode::new(self.kind.clone(), children)
+    }
+}
+
+// A chain of binary operators is as deep as it is long, so neither dropping
+// nor printing a tree may recurse.
+
+impl Drop for GreenNode {
+    fn drop(&mut self) {
+        let mut children = std::mem::take(&mut self.children);
+        while let Some(child) = children.pop() {
+            if let GreenElement::Node(node) = child
+                && let Some(mut node) = Rc::into_inner(node)
+            {
+                children.append(&mut node.children);
+            }
+        }
+    }
+}
+
+impl Display for GreenNode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut children: Vec<&GreenElement> = self.children.iter().rev().collect();
+        while let Some(child) = children.pop() {
+            match child {
+                GreenElement::Node(node) => children.extend(node.children.iter().rev()),
+                GreenElement::Token(token) => write!(f, "{}", token.text())?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum GreenElement {
+    Node(Rc<GreenNode>),
+    Token(Rc<GreenToken>),
+}
+
+impl GreenElement {
+    pub fn kind(&self) -> &SyntaxKind {
+        match self {
+            GreenElement::Node(node) => node.kind(),
+            GreenElement::Token(token) => token.kind(),
+        }
+    }
+
+    pub fn text_len(&self) -> usize {
+        match self {
+            GreenElement::Node(node) => node.text_len(),
+            GreenElement::Token(token) => token.text().len(),
+        }
+    }
+}
+
+impl Display for GreenElement {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GreenElement::Node(node) => write!(f, "{}", node),
+            GreenElement::Token(token) => write!(f, "{}", token.text()),
+        }
+    }
+}
+
+// Red tree: a view over the green tree that knows absolute offsets and parents.
+
+#[derive(Debug, Clone)]
+pub struct SyntaxNode(Rc<SyntaxNodeData>);
+
+#[derive(Debug)]
+struct SyntaxNodeData {
+    green: Rc<GreenNode>,
+    offset: usize,
+    parent: Option<SyntaxNode>,
+    // Position within the parent's children
+    index: usize,
+}
+
+// Releases the chain of parents one at a time rather than recursively
+impl Drop for SyntaxNodeData {
+    fn drop(&mut self) {
+        let mut parent = self.parent.take();
+        while let Some(SyntaxNode(node)) = parent {
+            parent = Rc::into_inner(node).and_then(|mut node| node.parent.take());
+        }
+    }
+}
+
+impl SyntaxNode {
+    pub fn new_root(green: Rc<GreenNode>) -> SyntaxNode {
+        SyntaxNode(Rc::new(SyntaxNodeData {
+            green,
+            offset: 0,
+            parent: None,
+            index: 0,
+        }))
+    }
+
+    pub fn kind(&self) -> &SyntaxKind {
+        self.0.green.kind()
+    }
+
+    pub fn green(&self) -> &Rc<GreenNode> {
+        &self.0.green
+    }
+
+    pub fn parent(&self) -> Option<SyntaxNode> {
+        self.0.parent.clone()
+    }
+
+    pub fn text_range(&self) -> Range<usize> {
+        self.0.offset..self.0.offset + self.0.green.text_len()
+    }
+
+    pub fn children_with_tokens(&self) -> Vec<SyntaxElement> {
+        let mut offset = self.0.offset;
+        self.0
+            .green
+            .children()
+            .iter()
+            .enumerate()
+            .map(|(index, child)| {
+                let element = match child {
+                    GreenElement::Node(green) => {
+                        SyntaxElement::Node(SyntaxNode(Rc::new(SyntaxNodeData {
+                            green: green.clone(),
+                            offset,
+                            parent: Some(self.clone()),
+                            index,
+                        })))
+                    }
+                    GreenElement::Token(green) => SyntaxElement::Token(SyntaxToken {
+                        green: green.clone(),
+                        offset,
+                        parent: self.clone(),
+                        index,
+                    }),
+                };
+                offset += child.text_len();
+                element
+            })
+            .collect()
+    }
+
+    pub fn children(&self) -> impl Iterator<Item = SyntaxNode> {
+        self.children_with_tokens()
+            .into_iter()
+            .filter_map(|element| match element {
+                SyntaxElement::Node(node) => Some(node),
+                SyntaxElement::Token(_) => None,
+            })
+    }
+
+    // Swaps this node for `replacement` and returns the new root. Everything
+    // outside the path from this node to the root is shared with the old tree.
+    pub fn replace_with(&self, replacement: Rc<GreenNode>) -> Rc<GreenNode> {
+        let mut node = self.clone();
+        let mut replacement = replacement;
+        while let Some(parent) = node.parent() {
+            replacement = parent
+                .green()
+                .replace_child(node.0.index, GreenElement::Node(replacement));
+            node = parent;
+        }
+        replacement
+    }
+
+    // The deepest element whose text range contains `range`
+    pub fn covering_element(&self, range: Range<usize>) -> SyntaxElement {
+        let mut node = self.clone();
+        'descend: loop {
+            for child in node.children_with_tokens() {
+                match child {
+                    SyntaxElement::Node(child)
+                        if child.text_range().start <= range.start
+                            && range.end <= child.text_range().end
+                            && !child.text_range().is_empty() =>
+                    {
+                        node = child;
+                        continue 'descend;
+                    }
+                    SyntaxElement::Token(token)
+                        if token.text_range().start <= range.start
+                            && range.end <= token.text_range().end =>
+                    {
+                        return SyntaxElement::Token(token);
+                    }
+                    _ => {}
+                }
+            }
+            return SyntaxElement::Node(node);
+        }
+    }
+
+    // Every token below this node in source order, including trivia
+    pub fn descendant_tokens(&self) -> Vec<SyntaxToken> {
+        let mut tokens = Vec::new();
+        let mut elements = vec![SyntaxElement::Node(self.clone())];
+        while let Some(element) = elements.pop() {
+            match element {
+                SyntaxElement::Node(node) => {
+                    elements.extend(node.children_with_tokens().into_iter().rev())
+                }
+                SyntaxElement::Token(token) => tokens.push(token),
+            }
+        }
+        tokens
+    }
+
+    // Direct child tokens, skipping trivia
+    pub fn tokens(&self) -> impl Iterator<Item = SyntaxToken> {
+        self.children_with_tokens()
+            .into_iter()
+            .filter_map(|element| match element {
+                SyntaxElement::Token(token) if !token.kind().is_trivia() => Some(token),
+                _ => None,
+            })
+    }
+}
+
+impl SyntaxNode {
+    // An indented dump of the tree with the kind and text range of every element
+    pub fn debug_tree(&self) -> String {
+        let mut output = String::new();
+        let mut elements = vec![(SyntaxElement::Node(self.clone()), 0)];
+        while let Some((element, depth)) = elements.pop() {
+            match element {
+                SyntaxElement::Node(node) => {
+                    let range = node.text_range();
+                    output.push_str(&format!(
+                        "{}{:?}@{}..{}\n",
+                        "  ".repeat(depth),
+                        node.kind(),
+                        range.start,
+                        range.end
+                    ));
+                    elements.extend(
+                        node.children_with_tokens()
+                            .into_iter()
+                            .rev()
+                            .map(|child| (child, depth + 1)),
+                    );
+                }
+                SyntaxElement::Token(token) => {
+                    let range = token.text_range();
+                    output.push_str(&format!(
+                        "{}{:?}@{}..{} {:?}\n",
+                        "  ".repeat(depth),
+                        token.kind(),
+                        range.start,
+                        range.end,
+                        token.text()
+                    ));
+                }
+            }
+        }
+        output
+    }
+}
+
+impl PartialEq for SyntaxNode {
+    fn eq(&self, other: &SyntaxNode) -> bool {
+        Rc::ptr_eq(&self.0.green, &other.0.green) && self.0.offset == other.0.offset
+    }
+}
+
+impl Display for SyntaxNode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0.green)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SyntaxToken {
+    green: Rc<GreenToken>,
+    offset: usize,
+    parent: SyntaxNode,
+    index: usize,
+}
+
+impl SyntaxToken {
+    pub fn kind(&self) -> &SyntaxKind {
+        self.green.kind()
+    }
+
+    pub fn text(&self) -> &str {
+        self.green.text()
+    }
+
+    pub fn parent(&self) -> SyntaxNode {
+        self.parent.clone()
+    }
+
+    pub fn text_range(&self) -> Range<usize> {
+        self.offset..self.offset + self.green.text().len()
+    }
+
+    pub fn replace_with(&self, replacement: Rc<GreenToken>) -> Rc<GreenNode> {
+        self.parent.replace_with(
+            self.parent
+                .green()
+                .replace_child(self.index, GreenElement::Token(replacement)),
+        )
+    }
+
+    pub fn to_token(&self) -> Option<Token> {
+        match self.kind() {
+            SyntaxKind::Token(kind) => Some(token_from_text(kind.clone(), self.text())),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum SyntaxElement {
+    Node(SyntaxNode),
+    Token(SyntaxToken),
+}
+
+pub fn token_from_text(kind: TokenKind, text: &str) -> Token {
+    match kind {
+        TokenKind::Number => match text.parse::<f64>() {
+            Ok(number) => Token::from((kind, number)),
+            Err(_) => Token::from(kind),
+        },
+        TokenKind::String => Token::from((kind, text.trim_matches('"').to_string())),
+        TokenKind::Identifier => Token::from((kind, text.to_string())),
+        _ => Token::from(kind),
+    }
+}
+
+// Typed view: thin wrappers over syntax nodes of a known kind.
+
+pub trait AstNode: Sized {
+    fn cast(node: SyntaxNode) -> Option<Self>;
+    fn syntax(&self) -> &SyntaxNode;
+}
+
+macro_rules! ast_node {
+    ($name:ident, $kind:ident) => {
+        #[derive(Debug, Clone, PartialEq)]
+        pub struct $name(SyntaxNode);
+
+        impl AstNode for $name {
+            fn cast(node: SyntaxNode) -> Option<$name> {
+                (node.kind() == &SyntaxKind::$kind).then_some($name(node))
+            }
+
+            fn syntax(&self) -> &SyntaxNode {
+                &self.0
+            }
+        }
+    };
+}
+
+ast_node!(Root, Root);
+ast_node!(LiteralNode, Literal);
+ast_node!(VariableNode, Variable);
+ast_node!(UnaryNode, Unary);
+ast_node!(BinaryNode, Binary);
+ast_node!(GroupingNode, Grouping);
+
+impl Root {
+    pub fn expression(&self) -> Option<ExprNode> {
+        self.0.children().find_map(ExprNode::cast)
+    }
+}
+
+impl LiteralNode {
+    pub fn token(&self) -> Option<SyntaxToken> {
+        self.0.tokens().next()
+    }
+}
+
+impl VariableNode {
+    pub fn name(&self) -> Option<SyntaxToken> {
+        self.0.tokens().next()
+    }
+}
+
+impl UnaryNode {
+    pub fn operator(&self) -> Option<SyntaxToken> {
+        self.0.tokens().next()
+    }
+
+    pub fn operand(&self) -> Option<ExprNode> {
+        self.0.children().find_map(ExprNode::cast)
+    }
+}
+
+impl BinaryNode {
+    pub fn left(&self) -> Option<ExprNode> {
+        self.0.children().next().and_then(ExprNode::cast)
+    }
+
+    pub fn operator(&self) -> Option<SyntaxToken> {
+        self.0.tokens().next()
+    }
+
+    pub fn right(&self) -> Option<ExprNode> {
+        self.0.children().nth(1).and_then(ExprNode::cast)
+    }
+}
+
+impl GroupingNode {
+    pub fn left_paren(&self) -> Option<SyntaxToken> {
+        self.0
+            .tokens()
+            .find(|token| token.kind() == &SyntaxKind::Token(TokenKind::LeftParen))
+    }
+
+    pub fn expression(&self) -> Option<ExprNode> {
+        self.0.children().find_map(ExprNode::cast)
+    }
+
+    pub fn right_paren(&self) -> Option<SyntaxToken> {
+        self.0
+            .tokens()
+            .find(|token| token.kind() == &SyntaxKind::Token(TokenKind::RightParen))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprNode {
+    Literal(LiteralNode),
+    Variable(VariableNode),
+    Unary(UnaryNode),
+    Binary(BinaryNode),
+    Grouping(GroupingNode),
+}
+
+impl AstNode for ExprNode {
+    fn cast(node: SyntaxNode) -> Option<ExprNode> {
+        match node.kind() {
+            SyntaxKind::Literal => Some(ExprNode::Literal(LiteralNode(node))),
+            SyntaxKind::Variable => Some(ExprNode::Variable(VariableNode(node))),
+            SyntaxKind::Unary => Some(ExprNode::Unary(UnaryNode(node))),
+            SyntaxKind::Binary => Some(ExprNode::Binary(BinaryNode(node))),
+            SyntaxKind::Grouping => Some(ExprNode::Grouping(GroupingNode(node))),
+            _ => None,
+        }
+    }
+
+    fn syntax(&self) -> &SyntaxNode {
+        match self {
+            ExprNode::Literal(node) => node.syntax(),
+            ExprNode::Variable(node) => node.syntax(),
+            ExprNode::Unary(node) => node.syntax(),
+            ExprNode::Binary(node) => node.syntax(),
+            ExprNode::Grouping(node) => node.syntax(),
+        }
+    }
+}
+
+impl ExprNode {
+    // Lowers the node into the typed `Expr` tree, or `None` if the subtree
+    // contains syntax errors. Uses an explicit stack, like `ExprArena::to_expr`.
+    pub fn to_expr(&self) -> Option<Expr> {
+        enum Step {
+            Visit(ExprNode),
+            Build(ExprNode),
+        }
+
+        let mut steps = vec![Step::Visit(self.clone())];
+        let mut exprs = Vec::new();
+        while let Some(step) = steps.pop() {
+            match step {
+                Step::Visit(node) => {
+                    let operands = match &node {
+                        ExprNode::Literal(_) | ExprNode::Variable(_) => Vec::new(),
+                        ExprNode::Unary(unary) => vec![unary.operand()?],
+                        ExprNode::Binary(binary) => vec![binary.left()?, binary.right()?],
+                        ExprNode::Grouping(grouping) => {
+                            grouping.right_paren()?;
+                            vec![grouping.expression()?]
+                        }
+                    };
+                    steps.push(Step::Build(node));
+                    steps.extend(operands.into_iter().map(Step::Visit));
+                }
+                Step::Build(node) => {
+                    // Operands were pushed left first, so the left operand was
+                    // built last
+                    let mut operand = || exprs.pop().expect("operands are built first");
+                    let expr = match node {
+                        ExprNode::Literal(literal) => {
+                            let token = literal.token()?.to_token()?;
+                            match (token.kind, token.value) {
+                                (TokenKind::False, _) => Expr::BooleanLiteral(false),
+                                (TokenKind::True, _) => Expr::BooleanLiteral(true),
+                                (TokenKind::Nil, _) => Expr::NilLiteral,
+                                (TokenKind::Number, Some(TokenValue::Number(value))) => {
+                                    Expr::NumberLiteral(value)
+                                }
+                                (TokenKind::String, Some(TokenValue::String(value))) => {
+                                    Expr::StringLiteral(value)
+                                }
+                                _ => return None,
+                            }
+                        }
+                        ExprNode::Variable(variable) => {
+                            Expr::Variable(variable.name()?.to_token()?)
+                        }
+                        ExprNode::Unary(unary) => {
+                            Expr::Unary(UnaryExpr::new(unary.operator()?.to_token()?, operand()))
+                        }
+                        ExprNode::Binary(binary) => {
+                            let left = operand();
+                            Expr::Binary(BinaryExpr::new(
+                                left,
+                                binary.operator()?.to_token()?,
+                                operand(),
+                            ))
+                        }
+                        ExprNode::Grouping(_) => Expr::Grouping(GroupingExpr::new(operand())),
+                    };
+                    exprs.push(expr);
+                }
+            }
+        }
+        exprs.pop()
+    }
+}
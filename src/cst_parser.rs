@@ -0,0 +1,393 @@
+use std::rc::Rc;
+
+use crate::{
+    cst::{
+        AstNode, GreenElement, GreenNode, GreenToken, Root, SyntaxKind, SyntaxNode, token_from_text,
+    },
+    expression::Expr,
+    lexer::{Lexeme, Lexer, LexerError},
+    parser::{DEFAULT_MAX_DEPTH, ParserError, Precedence, Prefix, rule},
+    token::{Token, TokenKind},
+};
+
+pub struct Parse {
+    green: Rc<GreenNode>,
+    pub errors: Vec<ParserError>,
+    pub lexer_errors: Vec<LexerError>,
+    // Kept so that edits to the tree are parsed with the same limit
+    max_depth: usize,
+}
+
+impl Parse {
+    pub fn new(
+        green: Rc<GreenNode>,
+        errors: Vec<ParserError>,
+        lexer_errors: Vec<LexerError>,
+    ) -> Parse {
+        Parse {
+            green,
+            errors,
+            lexer_errors,
+            max_depth: DEFAULT_MAX_DEPTH,
+        }
+    }
+
+    pub fn with_max_depth(self, max_depth: usize) -> Parse {
+        Parse { max_depth, ..self }
+    }
+
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    pub fn green(&self) -> &Rc<GreenNode> {
+        &self.green
+    }
+
+    pub fn syntax(&self) -> SyntaxNode {
+        SyntaxNode::new_root(self.green.clone())
+    }
+
+    pub fn root(&self) -> Root {
+        Root::cast(self.syntax()).expect("CST parser always produces a root node")
+    }
+
+    pub fn to_expr(&self) -> Option<Expr> {
+        if !self.errors.is_empty() || !self.lexer_errors.is_empty() {
+            return None;
+        }
+        self.root().expression()?.to_expr()
+    }
+}
+
+pub fn parse_syntax(source: &str) -> Parse {
+    parse_syntax_with_max_depth(source, DEFAULT_MAX_DEPTH)
+}
+
+pub fn parse_syntax_with_max_depth(source: &str, max_depth: usize) -> Parse {
+    let mut lexer_errors = Vec::new();
+    let tokens = Lexer::new(source.to_string())
+        .scan_lexemes()
+        .into_iter()
+        .map(|(lexeme, text)| {
+            let kind = match lexeme {
+                Lexeme::Token(Ok(token)) => SyntaxKind::Token(token.kind),
+                Lexeme::Token(Err(error)) => {
+                    lexer_errors.push(error);
+                    SyntaxKind::Unknown
+                }
+                Lexeme::Whitespace => SyntaxKind::Whitespace,
+                Lexeme::Comment => SyntaxKind::Comment,
+            };
+            GreenToken::new(kind, text)
+        })
+        .collect();
+
+    let mut parser = SyntaxParser::new(tokens, max_depth);
+    let green = parser.root();
+    Parse::new(green, parser.errors, lexer_errors).with_max_depth(max_depth)
+}
+
+// Builds a lossless tree: every lexeme, including whitespace, comments and
+// tokens that fail to parse, ends up somewhere in the green tree.
+struct SyntaxParser {
+    tokens: Vec<Rc<GreenToken>>,
+    current: usize,
+    errors: Vec<ParserError>,
+    depth: usize,
+    max_depth: usize,
+    // How many groupings are waiting for their closing paren
+    open_groups: usize,
+}
+
+impl SyntaxParser {
+    fn new(tokens: Vec<Rc<GreenToken>>, max_depth: usize) -> SyntaxParser {
+        SyntaxParser {
+            tokens,
+            current: 0,
+            errors: Vec::new(),
+            depth: 0,
+            max_depth,
+            open_groups: 0,
+        }
+    }
+
+    // The next significant token, looking past any trivia
+    fn peek(&self) -> Option<&Rc<GreenToken>> {
+        self.tokens[self.current..]
+            .iter()
+            .find(|token| !token.kind().is_trivia())
+    }
+
+    fn peek_token_kind(&self) -> Option<&TokenKind> {
+        match self.peek()?.kind() {
+            SyntaxKind::Token(kind) => Some(kind),
+            _ => None,
+        }
+    }
+
+    fn trivia(&mut self, children: &mut Vec<GreenElement>) {
+        while let Some(token) = self.tokens.get(self.current)
+            && token.kind().is_trivia()
+        {
+            children.push(GreenElement::Token(token.clone()));
+            self.current += 1;
+        }
+    }
+
+    // Consumes any trivia followed by the next significant token
+    fn bump(&mut self, children: &mut Vec<GreenElement>) {
+        self.trivia(children);
+        if let Some(token) = self.tokens.get(self.current) {
+            children.push(GreenElement::Token(token.clone()));
+            self.current += 1;
+        }
+    }
+
+    fn root(&mut self) -> Rc<GreenNode> {
+        let mut children = Vec::new();
+
+        self.trivia(&mut children);
+        children.push(self.expression(Precedence::Equality));
+        self.trivia(&mut children);
+
+        if let Some(token) = self.peek() {
+            // Unknown tokens have already been reported by the lexer
+            if let Some(token) = token_from_green(token) {
+                self.errors.push(ParserError::UnexpectedToken(token));
+            }
+            let mut trailing = Vec::new();
+            while self.current < self.tokens.len() {
+                self.bump(&mut trailing);
+            }
+            children.push(GreenElement::Node(GreenNode::new(
+                SyntaxKind::Error,
+                trailing,
+            )));
+        }
+
+        GreenNode::new(SyntaxKind::Root, children)
+    }
+
+    fn expression(&mut self, precedence: Precedence) -> GreenElement {
+        // Past the limit the rest of the input is kept as a single error node,
+        // which also unwinds every enclosing level without further errors
+        if self.depth == self.max_depth {
+            self.errors.push(ParserError::TooDeeplyNested);
+            let mut rest = Vec::new();
+            while self.current < self.tokens.len() {
+                self.bump(&mut rest);
+            }
+            return GreenElement::Node(GreenNode::new(SyntaxKind::Error, rest));
+        }
+
+        self.depth += 1;
+        let expr = self.parse_nested(precedence);
+        self.depth -= 1;
+        expr
+    }
+
+    fn too_deep(&self) -> bool {
+        matches!(self.errors.last(), Some(ParserError::TooDeeplyNested))
+    }
+
+    fn parse_nested(&mut self, precedence: Precedence) -> GreenElement {
+        let mut expr = self.prefix();
+
+        // As in `Parser`, only the right operands of a chain count towards
+        // the depth
+        while let Some(kind) = self.peek_token_kind()
+            && let Some(infix) = rule(kind).infix
+            && infix.precedence >= precedence
+        {
+            let mut children = vec![expr];
+            self.bump(&mut children);
+            self.trivia(&mut children);
+            children.push(self.expression(infix.right_precedence));
+            expr = GreenElement::Node(GreenNode::new(SyntaxKind::Binary, children));
+        }
+
+        expr
+    }
+
+    fn prefix(&mut self) -> GreenElement {
+        let mut children = Vec::new();
+
+        let Some(token) = self.peek() else {
+            self.errors.push(ParserError::ExpectedExpression);
+            return GreenElement::Node(GreenNode::new(SyntaxKind::Error, children));
+        };
+
+        let kind = match token_from_green(token) {
+            Some(token) => match rule(&token.kind).prefix {
+                Some(Prefix::Grouping) => return self.grouping(),
+                Some(Prefix::Unary) => SyntaxKind::Unary,
+                Some(Prefix::Literal) => SyntaxKind::Literal,
+                Some(Prefix::Variable) => SyntaxKind::Variable,
+                None => {
+                    self.errors
+                        .push(ParserError::ExpectedPrimaryExpressionGot(token));
+                    SyntaxKind::Error
+                }
+            },
+            // Already reported by the lexer
+            None => SyntaxKind::Error,
+        };
+
+        // Leave a closing paren for an enclosing grouping to pick up. Outside
+        // of one it is consumed here, having already been reported.
+        if kind != SyntaxKind::Error
+            || self.open_groups == 0
+            || self.peek_token_kind() != Some(&TokenKind::RightParen)
+        {
+            self.bump(&mut children);
+        }
+        if kind == SyntaxKind::Unary {
+            self.trivia(&mut children);
+            children.push(self.expression(Precedence::Unary));
+        }
+
+        GreenElement::Node(GreenNode::new(kind, children))
+    }
+
+    fn grouping(&mut self) -> GreenElement {
+        let mut children = Vec::new();
+
+        self.bump(&mut children);
+        self.trivia(&mut children);
+        self.open_groups += 1;
+        children.push(self.expression(Precedence::Equality));
+        self.open_groups -= 1;
+
+        if self.peek_token_kind() == Some(&TokenKind::RightParen) {
+            self.bump(&mut children);
+        } else if !self.too_deep() {
+            self.errors.push(ParserError::UnclosedParenthesis);
+        }
+
+        GreenElement::Node(GreenNode::new(SyntaxKind::Grouping, children))
+    }
+}
+
+fn token_from_green(token: &GreenToken) -> Option<Token> {
+    match token.kind() {
+        SyntaxKind::Token(kind) => Some(token_from_text(kind.clone(), token.text())),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        Error,
+        cst::{AstNode, ExprNode, SyntaxKind},
+        cst_parser::{parse_syntax, parse_syntax_with_max_depth},
+        parse,
+        parser::ParserError,
+    };
+
+    #[test]
+    fn test_round_trip() {
+        for source in [
+            "1 + 2",
+            "  // leading comment\n(1 +  2)\t* -3 // trailing\n",
+            "!(\"a\" == \"b\")",
+            "(1 + ",
+            "1 + 2 3 @ \"unterminated",
+            "",
+        ] {
+            assert_eq!(parse_syntax(source).syntax().to_string(), source);
+        }
+    }
+
+    #[test]
+    fn test_lowers_to_parser_ast() -> Result<(), Error> {
+        for source in ["1 + 2 * 3", "(1 + 2) * -3 >= 4 == !false", "\"a\" != nil"] {
+            assert_eq!(parse_syntax(source).to_expr(), Some(parse(source)?));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_grouping_keeps_paren_positions() {
+        let parse = parse_syntax("2 * ( 1 + 3 )");
+        let Some(ExprNode::Binary(binary)) = parse.root().expression() else {
+            panic!("expected binary expression");
+        };
+        let Some(ExprNode::Grouping(grouping)) = binary.right() else {
+            panic!("expected grouping");
+        };
+
+        assert_eq!(
+            grouping.left_paren().map(|token| token.text_range()),
+            Some(4..5)
+        );
+        assert_eq!(
+            grouping.right_paren().map(|token| token.text_range()),
+            Some(12..13)
+        );
+        assert_eq!(grouping.syntax().text_range(), 4..13);
+        assert_eq!(
+            grouping.expression().map(|expr| expr.syntax().to_string()),
+            Some("1 + 3".to_string())
+        );
+    }
+
+    #[test]
+    fn test_errors_are_kept_in_tree() {
+        let parse = parse_syntax("(1 + ) 2");
+        assert_eq!(parse.to_expr(), None);
+        assert_eq!(parse.errors.len(), 2);
+        assert!(matches!(
+            parse.errors[0],
+            ParserError::ExpectedPrimaryExpressionGot(_)
+        ));
+        assert!(matches!(parse.errors[1], ParserError::UnexpectedToken(_)));
+        assert!(
+            parse
+                .syntax()
+                .children()
+                .any(|node| node.kind() == &SyntaxKind::Error)
+        );
+    }
+
+    #[test]
+    fn test_stray_closing_paren_is_one_error() {
+        for source in [")", "1 + )", "-)"] {
+            let parse = parse_syntax(source);
+            assert_eq!(parse.syntax().to_string(), source);
+            assert!(matches!(
+                parse.errors.as_slice(),
+                [ParserError::ExpectedPrimaryExpressionGot(_)]
+            ));
+        }
+    }
+
+    #[test]
+    fn test_deep_nesting_is_an_error() {
+        let source = format!("{}1", "(-".repeat(100_000));
+        let parse = parse_syntax(&source);
+        assert_eq!(parse.syntax().to_string(), source);
+        assert!(matches!(
+            parse.errors.as_slice(),
+            [ParserError::TooDeeplyNested]
+        ));
+
+        let parse = parse_syntax_with_max_depth("--1", 3);
+        assert!(parse.errors.is_empty());
+        let parse = parse_syntax_with_max_depth("--1", 2);
+        assert!(matches!(
+            parse.errors.as_slice(),
+            [ParserError::TooDeeplyNested]
+        ));
+    }
+
+    #[test]
+    fn test_long_chain_is_accepted() {
+        let source = format!("1{}", " + 1".repeat(200_000));
+        let parse = parse_syntax(&source);
+        assert!(parse.errors.is_empty());
+        assert_eq!(parse.syntax().to_string(), source);
+        assert!(parse.to_expr().is_some());
+    }
+}
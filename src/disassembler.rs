@@ -0,0 +1,162 @@
+use crate::{
+    chunk::{Chunk, OpCode, Prototype},
+    printer::print,
+    value::Value,
+};
+
+// Lists every instruction in `chunk` as
+//
+//     <offset> <line> <opcode> [<constant index> '<constant>' | <operand>]
+//
+// with `|` in place of the line when it is the same as the previous one, and
+// a jump's operand shown as the offset it jumps to. The functions declared in
+// the chunk are listed after it.
+pub fn disassemble(chunk: &Chunk, name: &str) -> String {
+    let mut output = format!("== {} ==\n", name);
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let (line, next) = disassemble_instruction(chunk, offset);
+        output.push_str(&line);
+        output.push('\n');
+        offset = next;
+    }
+
+    for constant in &chunk.constants {
+        if let Value::Function(function) = constant
+            && let Some(prototype) = function.object().downcast_ref::<Prototype>()
+        {
+            output.push_str(&disassemble(&prototype.chunk, &function.to_string()));
+        }
+    }
+    output
+}
+
+// Describes the instruction at `offset` and returns the offset of the next one
+pub fn disassemble_instruction(chunk: &Chunk, offset: usize) -> (String, usize) {
+    let line = chunk.span(offset).line;
+    let line = if offset > 0 && chunk.span(offset - 1).line == line {
+        "   |".to_string()
+    } else {
+        format!("{:4}", line)
+    };
+
+    let Ok(op) = OpCode::try_from(chunk.code[offset]) else {
+        let text = format!(
+            "{:04} {} Unknown opcode {}",
+            offset, line, chunk.code[offset]
+        );
+        return (text, offset + 1);
+    };
+
+    let operands = &chunk.code[offset + 1..offset + 1 + op.operand_len()];
+    let next = offset + 1 + op.operand_len();
+    let text = format!("{:04} {} {:?}", offset, line, op);
+    let text = match op {
+        OpCode::Constant
+        | OpCode::ReturnConstant
+        | OpCode::ConstantLong
+        | OpCode::DefineGlobal
+        | OpCode::GetGlobal
+        | OpCode::SetGlobal
+        | OpCode::Closure => {
+            let mut index = [0; 4];
+            index[..operands.len()].copy_from_slice(operands);
+            let index = u32::from_le_bytes(index) as usize;
+            let constant = print(&chunk.constants[index].clone().into_literal());
+            format!("{:<24} {:4} '{}'", text, index, constant)
+        }
+        OpCode::GetLocal
+        | OpCode::SetLocal
+        | OpCode::GetUpvalue
+        | OpCode::SetUpvalue
+        | OpCode::Call => format!("{:<24} {:4}", text, operands[0]),
+        OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop => {
+            let distance = u16::from_le_bytes([operands[0], operands[1]]) as usize;
+            let target = match op {
+                OpCode::Loop => next - distance,
+                _ => next + distance,
+            };
+            format!("{:<24} -> {:04}", text, target)
+        }
+        _ => text,
+    };
+    (text, next)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        chunk::{Chunk, OpCode},
+        compiler::{compile, compile_program},
+        disassembler::disassemble,
+        token::Span,
+        value::Value,
+    };
+
+    #[test]
+    fn test_disassemble() {
+        let expr = crate::parse("-(1 +\n\"a\") == nil").expect("source parses");
+        let chunk = compile(&expr).expect("expression compiles");
+        let expected = "\
+== test ==
+0000    1 Constant          0 '1'
+0002    | Constant          1 '\"a\"'
+0004    | Add
+0005    | Negate
+0006    2 Nil
+0007    | Equal
+0008    | Return
+";
+        assert_eq!(disassemble(&chunk, "test"), expected);
+    }
+
+    #[test]
+    fn test_long_constants() {
+        let mut chunk = Chunk::new();
+        for index in 0..257 {
+            chunk.write_constant(Value::Number(index as f64), Span::new(1, 1));
+        }
+        chunk.write_op(OpCode::Return, Span::new(1, 1));
+
+        let output = disassemble(&chunk, "long");
+        let lines = output.lines().collect::<Vec<_>>();
+        assert_eq!(lines[257], "0512    | ConstantLong    256 '256'");
+        assert_eq!(lines[258], "0516    | Return");
+    }
+
+    #[test]
+    fn test_functions_and_jumps() {
+        let source = "fun f(n) {\n  while (n > 0) n = n - 1;\n  return n;\n}\nif (f(2)) print 1;";
+        let program = crate::parse_program(source).expect("source parses");
+        let script = compile_program(&program).expect("program compiles");
+        let expected = "\
+== test ==
+0000    1 Closure           0 'f'
+0002    | DefineGlobal      1 '\"f\"'
+0004    5 GetGlobal         1 '\"f\"'
+0006    | Constant          2 '2'
+0008    | Call              1
+0010    | JumpIfFalse    -> 0016
+0013    | Constant          3 '1'
+0015    | Print
+0016    | Nil
+0017    | Return
+== <fn f> ==
+0000    2 GetLocal          1
+0002    | Constant          0 '0'
+0004    | Greater
+0005    | JumpIfFalse    -> 0019
+0008    | GetLocal          1
+0010    | Constant          1 '1'
+0012    | Subtract
+0013    | SetLocal          1
+0015    | Pop
+0016    | Loop           -> 0000
+0019    3 GetLocal          1
+0021    | Return
+0022    | Nil
+0023    | Return
+";
+        assert_eq!(disassemble(&script.chunk, "test"), expected);
+    }
+}
@@ -1,6 +1,10 @@
-use crate::token::Token;
+use std::mem;
 
-#[derive(Debug, PartialEq)]
+use crate::token::{Token, TokenKind};
+
+// With the `serde` feature, `Expr` is (de)serialized through `ExprArena`, see
+// the `serialize` module
+#[derive(Debug, Clone, PartialEq)]
 pub enum Expr {
     BooleanLiteral(bool),
     NumberLiteral(f64),
@@ -9,9 +13,51 @@ pub enum Expr {
     Unary(UnaryExpr),
     Binary(BinaryExpr),
     Grouping(GroupingExpr),
+    Variable(Token),
+    Assign(AssignExpr),
+    Call(CallExpr),
+}
+
+impl Expr {
+    // Moves the direct children of this node onto `stack`, leaving leaves behind
+    fn take_children(&mut self, stack: &mut Vec<Expr>) {
+        match self {
+            Expr::Unary(unary) => stack.push(mem::replace(&mut unary.right, Expr::NilLiteral)),
+            Expr::Binary(binary) => {
+                stack.push(mem::replace(&mut binary.left, Expr::NilLiteral));
+                stack.push(mem::replace(&mut binary.right, Expr::NilLiteral));
+            }
+            Expr::Grouping(grouping) => {
+                stack.push(mem::replace(&mut grouping.expression, Expr::NilLiteral))
+            }
+            Expr::Assign(assign) => stack.push(mem::replace(&mut assign.value, Expr::NilLiteral)),
+            Expr::Call(call) => {
+                stack.push(mem::replace(&mut call.callee, Expr::NilLiteral));
+                stack.append(&mut call.arguments);
+            }
+            Expr::BooleanLiteral(_)
+            | Expr::NumberLiteral(_)
+            | Expr::StringLiteral(_)
+            | Expr::NilLiteral
+            | Expr::Variable(_) => {}
+        }
+    }
 }
 
-#[derive(Debug, PartialEq)]
+// Dropping nested boxes recursively would overflow the stack on deep trees,
+// so children are detached and dropped from an explicit stack instead.
+impl Drop for Expr {
+    fn drop(&mut self) {
+        let mut stack = Vec::new();
+        self.take_children(&mut stack);
+        while let Some(mut expr) = stack.pop() {
+            expr.take_children(&mut stack);
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct UnaryExpr {
     pub operator: Token,
     pub right: Box<Expr>,
@@ -26,7 +72,8 @@ impl UnaryExpr {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct BinaryExpr {
     pub left: Box<Expr>,
     pub operator: Token,
@@ -43,7 +90,8 @@ impl BinaryExpr {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct GroupingExpr {
     pub expression: Box<Expr>,
 }
@@ -55,3 +103,446 @@ impl GroupingExpr {
         }
     }
 }
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AssignExpr {
+    pub name: Token,
+    pub value: Box<Expr>,
+}
+
+impl AssignExpr {
+    pub fn new(name: Token, value: Expr) -> AssignExpr {
+        AssignExpr {
+            name,
+            value: Box::new(value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CallExpr {
+    pub callee: Box<Expr>,
+    // The closing paren, where errors in the call are reported
+    pub paren: Token,
+    pub arguments: Vec<Expr>,
+}
+
+impl CallExpr {
+    pub fn new(callee: Expr, paren: Token, arguments: Vec<Expr>) -> CallExpr {
+        CallExpr {
+            callee: Box::new(callee),
+            paren,
+            arguments,
+        }
+    }
+}
+
+impl UnaryExpr {
+    // Moves the contents out, leaving a placeholder behind for `Expr::drop`
+    fn take(&mut self) -> UnaryExpr {
+        UnaryExpr {
+            operator: mem::replace(&mut self.operator, Token::from(TokenKind::EoF)),
+            right: mem::replace(&mut self.right, Box::new(Expr::NilLiteral)),
+        }
+    }
+}
+
+impl BinaryExpr {
+    pub(crate) fn take(&mut self) -> BinaryExpr {
+        BinaryExpr {
+            left: mem::replace(&mut self.left, Box::new(Expr::NilLiteral)),
+            operator: mem::replace(&mut self.operator, Token::from(TokenKind::EoF)),
+            right: mem::replace(&mut self.right, Box::new(Expr::NilLiteral)),
+        }
+    }
+}
+
+impl GroupingExpr {
+    fn take(&mut self) -> GroupingExpr {
+        GroupingExpr {
+            expression: mem::replace(&mut self.expression, Box::new(Expr::NilLiteral)),
+        }
+    }
+}
+
+impl AssignExpr {
+    fn take(&mut self) -> AssignExpr {
+        AssignExpr {
+            name: mem::replace(&mut self.name, Token::from(TokenKind::EoF)),
+            value: mem::replace(&mut self.value, Box::new(Expr::NilLiteral)),
+        }
+    }
+}
+
+impl CallExpr {
+    pub(crate) fn take(&mut self) -> CallExpr {
+        CallExpr {
+            callee: mem::replace(&mut self.callee, Box::new(Expr::NilLiteral)),
+            paren: mem::replace(&mut self.paren, Token::from(TokenKind::EoF)),
+            arguments: mem::take(&mut self.arguments),
+        }
+    }
+}
+
+// Passes over the AST implement these traits and override only the nodes they
+// care about. The `walk_*` functions hold the default traversal, so an override
+// can still recurse into the children by calling them.
+
+pub trait Visitor {
+    fn visit_expr(&mut self, expr: &Expr) {
+        walk_expr(self, expr)
+    }
+
+    fn visit_literal(&mut self, _literal: &Expr) {}
+
+    fn visit_unary(&mut self, unary: &UnaryExpr) {
+        walk_unary(self, unary)
+    }
+
+    fn visit_binary(&mut self, binary: &BinaryExpr) {
+        walk_binary(self, binary)
+    }
+
+    fn visit_grouping(&mut self, grouping: &GroupingExpr) {
+        walk_grouping(self, grouping)
+    }
+
+    fn visit_variable(&mut self, _name: &Token) {}
+
+    fn visit_assign(&mut self, assign: &AssignExpr) {
+        walk_assign(self, assign)
+    }
+
+    fn visit_call(&mut self, call: &CallExpr) {
+        walk_call(self, call)
+    }
+}
+
+pub fn walk_expr<V: Visitor + ?Sized>(visitor: &mut V, expr: &Expr) {
+    match expr {
+        Expr::BooleanLiteral(_)
+        | Expr::NumberLiteral(_)
+        | Expr::StringLiteral(_)
+        | Expr::NilLiteral => visitor.visit_literal(expr),
+        Expr::Unary(unary) => visitor.visit_unary(unary),
+        Expr::Binary(binary) => visitor.visit_binary(binary),
+        Expr::Grouping(grouping) => visitor.visit_grouping(grouping),
+        Expr::Variable(name) => visitor.visit_variable(name),
+        Expr::Assign(assign) => visitor.visit_assign(assign),
+        Expr::Call(call) => visitor.visit_call(call),
+    }
+}
+
+pub fn walk_unary<V: Visitor + ?Sized>(visitor: &mut V, unary: &UnaryExpr) {
+    visitor.visit_expr(&unary.right);
+}
+
+pub fn walk_binary<V: Visitor + ?Sized>(visitor: &mut V, binary: &BinaryExpr) {
+    visitor.visit_expr(&binary.left);
+    visitor.visit_expr(&binary.right);
+}
+
+pub fn walk_grouping<V: Visitor + ?Sized>(visitor: &mut V, grouping: &GroupingExpr) {
+    visitor.visit_expr(&grouping.expression);
+}
+
+pub fn walk_assign<V: Visitor + ?Sized>(visitor: &mut V, assign: &AssignExpr) {
+    visitor.visit_expr(&assign.value);
+}
+
+pub fn walk_call<V: Visitor + ?Sized>(visitor: &mut V, call: &CallExpr) {
+    visitor.visit_expr(&call.callee);
+    for argument in &call.arguments {
+        visitor.visit_expr(argument);
+    }
+}
+
+pub trait VisitorMut {
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        walk_expr_mut(self, expr)
+    }
+
+    fn visit_literal_mut(&mut self, _literal: &mut Expr) {}
+
+    fn visit_unary_mut(&mut self, unary: &mut UnaryExpr) {
+        walk_unary_mut(self, unary)
+    }
+
+    fn visit_binary_mut(&mut self, binary: &mut BinaryExpr) {
+        walk_binary_mut(self, binary)
+    }
+
+    fn visit_grouping_mut(&mut self, grouping: &mut GroupingExpr) {
+        walk_grouping_mut(self, grouping)
+    }
+
+    fn visit_variable_mut(&mut self, _name: &mut Token) {}
+
+    fn visit_assign_mut(&mut self, assign: &mut AssignExpr) {
+        walk_assign_mut(self, assign)
+    }
+
+    fn visit_call_mut(&mut self, call: &mut CallExpr) {
+        walk_call_mut(self, call)
+    }
+}
+
+pub fn walk_expr_mut<V: VisitorMut + ?Sized>(visitor: &mut V, expr: &mut Expr) {
+    match expr {
+        Expr::BooleanLiteral(_)
+        | Expr::NumberLiteral(_)
+        | Expr::StringLiteral(_)
+        | Expr::NilLiteral => visitor.visit_literal_mut(expr),
+        Expr::Unary(unary) => visitor.visit_unary_mut(unary),
+        Expr::Binary(binary) => visitor.visit_binary_mut(binary),
+        Expr::Grouping(grouping) => visitor.visit_grouping_mut(grouping),
+        Expr::Variable(name) => visitor.visit_variable_mut(name),
+        Expr::Assign(assign) => visitor.visit_assign_mut(assign),
+        Expr::Call(call) => visitor.visit_call_mut(call),
+    }
+}
+
+pub fn walk_unary_mut<V: VisitorMut + ?Sized>(visitor: &mut V, unary: &mut UnaryExpr) {
+    visitor.visit_expr_mut(&mut unary.right);
+}
+
+pub fn walk_binary_mut<V: VisitorMut + ?Sized>(visitor: &mut V, binary: &mut BinaryExpr) {
+    visitor.visit_expr_mut(&mut binary.left);
+    visitor.visit_expr_mut(&mut binary.right);
+}
+
+pub fn walk_grouping_mut<V: VisitorMut + ?Sized>(visitor: &mut V, grouping: &mut GroupingExpr) {
+    visitor.visit_expr_mut(&mut grouping.expression);
+}
+
+pub fn walk_assign_mut<V: VisitorMut + ?Sized>(visitor: &mut V, assign: &mut AssignExpr) {
+    visitor.visit_expr_mut(&mut assign.value);
+}
+
+pub fn walk_call_mut<V: VisitorMut + ?Sized>(visitor: &mut V, call: &mut CallExpr) {
+    visitor.visit_expr_mut(&mut call.callee);
+    for argument in &mut call.arguments {
+        visitor.visit_expr_mut(argument);
+    }
+}
+
+// Rebuilds a tree bottom up. Each method returns an `Expr` rather than the
+// node type it was given, so a pass can replace a node with any expression.
+pub trait Fold {
+    fn fold_expr(&mut self, expr: Expr) -> Expr {
+        fold_walk_expr(self, expr)
+    }
+
+    fn fold_literal(&mut self, literal: Expr) -> Expr {
+        literal
+    }
+
+    fn fold_unary(&mut self, unary: UnaryExpr) -> Expr {
+        Expr::Unary(fold_walk_unary(self, unary))
+    }
+
+    fn fold_binary(&mut self, binary: BinaryExpr) -> Expr {
+        Expr::Binary(fold_walk_binary(self, binary))
+    }
+
+    fn fold_grouping(&mut self, grouping: GroupingExpr) -> Expr {
+        Expr::Grouping(fold_walk_grouping(self, grouping))
+    }
+
+    fn fold_variable(&mut self, name: Token) -> Expr {
+        Expr::Variable(name)
+    }
+
+    fn fold_assign(&mut self, assign: AssignExpr) -> Expr {
+        Expr::Assign(fold_walk_assign(self, assign))
+    }
+
+    fn fold_call(&mut self, call: CallExpr) -> Expr {
+        Expr::Call(fold_walk_call(self, call))
+    }
+}
+
+pub fn fold_walk_expr<F: Fold + ?Sized>(folder: &mut F, mut expr: Expr) -> Expr {
+    match &mut expr {
+        Expr::BooleanLiteral(_)
+        | Expr::NumberLiteral(_)
+        | Expr::StringLiteral(_)
+        | Expr::NilLiteral => folder.fold_literal(expr),
+        Expr::Unary(unary) => folder.fold_unary(unary.take()),
+        Expr::Binary(binary) => folder.fold_binary(binary.take()),
+        Expr::Grouping(grouping) => folder.fold_grouping(grouping.take()),
+        Expr::Variable(name) => {
+            folder.fold_variable(mem::replace(name, Token::from(TokenKind::EoF)))
+        }
+        Expr::Assign(assign) => folder.fold_assign(assign.take()),
+        Expr::Call(call) => folder.fold_call(call.take()),
+    }
+}
+
+// Folds `expr` in place, reusing its box
+fn fold_boxed<F: Fold + ?Sized>(folder: &mut F, mut expr: Box<Expr>) -> Box<Expr> {
+    *expr = folder.fold_expr(mem::replace(&mut *expr, Expr::NilLiteral));
+    expr
+}
+
+pub fn fold_walk_unary<F: Fold + ?Sized>(folder: &mut F, unary: UnaryExpr) -> UnaryExpr {
+    UnaryExpr {
+        operator: unary.operator,
+        right: fold_boxed(folder, unary.right),
+    }
+}
+
+pub fn fold_walk_binary<F: Fold + ?Sized>(folder: &mut F, binary: BinaryExpr) -> BinaryExpr {
+    BinaryExpr {
+        left: fold_boxed(folder, binary.left),
+        operator: binary.operator,
+        right: fold_boxed(folder, binary.right),
+    }
+}
+
+pub fn fold_walk_grouping<F: Fold + ?Sized>(
+    folder: &mut F,
+    grouping: GroupingExpr,
+) -> GroupingExpr {
+    GroupingExpr {
+        expression: fold_boxed(folder, grouping.expression),
+    }
+}
+
+pub fn fold_walk_assign<F: Fold + ?Sized>(folder: &mut F, assign: AssignExpr) -> AssignExpr {
+    AssignExpr {
+        name: assign.name,
+        value: fold_boxed(folder, assign.value),
+    }
+}
+
+pub fn fold_walk_call<F: Fold + ?Sized>(folder: &mut F, call: CallExpr) -> CallExpr {
+    CallExpr {
+        callee: fold_boxed(folder, call.callee),
+        paren: call.paren,
+        arguments: call
+            .arguments
+            .into_iter()
+            .map(|argument| folder.fold_expr(argument))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        expression::{
+            BinaryExpr, Expr, Fold, GroupingExpr, UnaryExpr, Visitor, VisitorMut, walk_unary,
+        },
+        token::{Token, TokenKind},
+    };
+
+    // (1 + -2) * "three"
+    fn example() -> Expr {
+        Expr::Binary(BinaryExpr::new(
+            Expr::Grouping(GroupingExpr::new(Expr::Binary(BinaryExpr::new(
+                Expr::NumberLiteral(1.0),
+                Token::from(TokenKind::Plus),
+                Expr::Unary(UnaryExpr::new(
+                    Token::from(TokenKind::Minus),
+                    Expr::NumberLiteral(2.0),
+                )),
+            )))),
+            Token::from(TokenKind::Star),
+            Expr::StringLiteral("three".to_string()),
+        ))
+    }
+
+    #[test]
+    fn test_visitor() {
+        // Only overrides literals and unary nodes, everything else is walked
+        #[derive(Default)]
+        struct Counter {
+            literals: usize,
+            unary: usize,
+        }
+
+        impl Visitor for Counter {
+            fn visit_literal(&mut self, _literal: &Expr) {
+                self.literals += 1;
+            }
+
+            fn visit_unary(&mut self, unary: &UnaryExpr) {
+                self.unary += 1;
+                walk_unary(self, unary);
+            }
+        }
+
+        let mut counter = Counter::default();
+        counter.visit_expr(&example());
+        assert_eq!((counter.literals, counter.unary), (3, 1));
+    }
+
+    #[test]
+    fn test_visitor_mut() {
+        struct Double;
+
+        impl VisitorMut for Double {
+            fn visit_literal_mut(&mut self, literal: &mut Expr) {
+                if let Expr::NumberLiteral(value) = literal {
+                    *value *= 2.0;
+                }
+            }
+        }
+
+        let mut expr = Expr::Binary(BinaryExpr::new(
+            Expr::NumberLiteral(1.0),
+            Token::from(TokenKind::Plus),
+            Expr::Grouping(GroupingExpr::new(Expr::NumberLiteral(2.0))),
+        ));
+        Double.visit_expr_mut(&mut expr);
+        assert_eq!(
+            expr,
+            Expr::Binary(BinaryExpr::new(
+                Expr::NumberLiteral(2.0),
+                Token::from(TokenKind::Plus),
+                Expr::Grouping(GroupingExpr::new(Expr::NumberLiteral(4.0))),
+            ))
+        );
+    }
+
+    #[test]
+    fn test_fold() {
+        // Replaces every grouping with the expression inside it
+        struct Ungroup;
+
+        impl Fold for Ungroup {
+            fn fold_grouping(&mut self, grouping: GroupingExpr) -> Expr {
+                self.fold_expr(*grouping.expression)
+            }
+        }
+
+        assert_eq!(
+            Ungroup.fold_expr(example()),
+            Expr::Binary(BinaryExpr::new(
+                Expr::Binary(BinaryExpr::new(
+                    Expr::NumberLiteral(1.0),
+                    Token::from(TokenKind::Plus),
+                    Expr::Unary(UnaryExpr::new(
+                        Token::from(TokenKind::Minus),
+                        Expr::NumberLiteral(2.0),
+                    )),
+                )),
+                Token::from(TokenKind::Star),
+                Expr::StringLiteral("three".to_string()),
+            ))
+        );
+    }
+
+    #[test]
+    fn test_drop_deep_tree() {
+        let mut expr = Expr::NumberLiteral(1.0);
+        for _ in 0..1_000_000 {
+            expr = Expr::Unary(UnaryExpr::new(Token::from(TokenKind::Minus), expr));
+            expr = Expr::Grouping(GroupingExpr::new(expr));
+        }
+        drop(expr);
+    }
+}
@@ -0,0 +1,380 @@
+use crate::{
+    lexer::{Lexeme, Lexer, LexerError},
+    parser::rule,
+    token::TokenKind,
+};
+
+pub const DEFAULT_MAX_WIDTH: usize = 100;
+const INDENT: &str = "    ";
+
+// Reformats a whole Lox file. This works on the token stream rather than the
+// AST, so it handles statements and keeps every comment, but needs the source
+// to lex cleanly.
+pub fn format(source: &str, max_width: usize) -> Result<String, LexerError> {
+    let mut formatter = Formatter {
+        max_width,
+        output: String::new(),
+        line: Vec::new(),
+        line_indent: 0,
+        line_done: false,
+        indent: 0,
+        parens: 0,
+        previous: None,
+        previous_unary: false,
+        blank_line: false,
+    };
+
+    let mut newlines = 0;
+    let (lexemes, errors): (Vec<_>, Vec<_>) = Lexer::new(source.to_string())
+        .scan_lexemes()
+        .into_iter()
+        .partition(|(lexeme, _)| !matches!(lexeme, Lexeme::Token(Err(_))));
+    if let Some((Lexeme::Token(Err(error)), _)) = errors.into_iter().next() {
+        return Err(error);
+    }
+
+    for (index, (lexeme, text)) in lexemes.iter().enumerate() {
+        let text = text.clone();
+        match lexeme {
+            Lexeme::Whitespace => newlines += text.matches('\n').count(),
+            Lexeme::Comment => {
+                if newlines > 1 {
+                    formatter.blank_line = true;
+                }
+                formatter.comment(text, newlines > 0);
+                newlines = 0;
+            }
+            Lexeme::Token(Ok(token)) => {
+                if newlines > 1 {
+                    formatter.blank_line = true;
+                }
+                newlines = 0;
+
+                let next = lexemes[index + 1..]
+                    .iter()
+                    .find_map(|(lexeme, _)| match lexeme {
+                        Lexeme::Token(Ok(token)) => Some(token.kind.clone()),
+                        _ => None,
+                    });
+                formatter.token(token.kind.clone(), text, next);
+            }
+            Lexeme::Token(Err(_)) => unreachable!("lexer errors are returned above"),
+        }
+    }
+    formatter.end_line();
+
+    Ok(formatter.output)
+}
+
+struct Piece {
+    text: String,
+    space_before: bool,
+    // Lines that are too long are broken before binary operators, loosest
+    // binding first. The rank is the number of enclosing parentheses, then
+    // the operator's precedence.
+    break_rank: Option<(usize, u8)>,
+}
+
+struct Formatter {
+    max_width: usize,
+    output: String,
+    line: Vec<Piece>,
+    line_indent: usize,
+    // The line is complete but kept open for a trailing comment
+    line_done: bool,
+    indent: usize,
+    parens: usize,
+    previous: Option<TokenKind>,
+    // The previous token was a unary operator, so the operand attaches to it
+    previous_unary: bool,
+    blank_line: bool,
+}
+
+impl Formatter {
+    fn token(&mut self, kind: TokenKind, text: String, next: Option<TokenKind>) {
+        match kind {
+            TokenKind::LeftBrace => {
+                self.push(&kind, text);
+                self.line_done = true;
+                self.indent += 1;
+            }
+            TokenKind::RightBrace => {
+                self.end_line();
+                self.indent = self.indent.saturating_sub(1);
+                self.push(&kind, text);
+                if !matches!(next, Some(TokenKind::Else | TokenKind::Semicolon)) {
+                    self.line_done = true;
+                }
+            }
+            TokenKind::Semicolon => {
+                self.push(&kind, text);
+                if self.parens == 0 {
+                    self.line_done = true;
+                }
+            }
+            TokenKind::LeftParen => {
+                self.push(&kind, text);
+                self.parens += 1;
+            }
+            TokenKind::RightParen => {
+                self.parens = self.parens.saturating_sub(1);
+                self.push(&kind, text);
+            }
+            _ => self.push(&kind, text),
+        }
+    }
+
+    fn push(&mut self, kind: &TokenKind, text: String) {
+        if self.line_done {
+            self.end_line();
+        }
+        let previous = self.previous.replace(kind.clone());
+        let previous_unary = std::mem::replace(&mut self.previous_unary, false);
+        if self.line.is_empty() {
+            self.start_line();
+        }
+
+        let unary = self.is_unary(kind, previous.as_ref());
+        let space_before =
+            !self.line.is_empty() && !previous_unary && !Self::attaches(previous.as_ref(), kind);
+        let break_rank = match kind {
+            _ if unary => None,
+            TokenKind::And | TokenKind::Or => Some((self.parens, 0)),
+            _ => rule(kind)
+                .infix
+                .map(|infix| (self.parens, infix.precedence as u8 + 1)),
+        };
+        self.line.push(Piece {
+            text,
+            space_before,
+            break_rank,
+        });
+        self.previous_unary = unary;
+    }
+
+    fn is_unary(&self, kind: &TokenKind, previous: Option<&TokenKind>) -> bool {
+        match kind {
+            TokenKind::Bang => true,
+            TokenKind::Minus => !previous.is_some_and(ends_operand),
+            _ => false,
+        }
+    }
+
+    // Whether `kind` is written directly after `previous` without a space
+    fn attaches(previous: Option<&TokenKind>, kind: &TokenKind) -> bool {
+        match (previous, kind) {
+            (
+                _,
+                TokenKind::RightParen | TokenKind::Comma | TokenKind::Semicolon | TokenKind::Dot,
+            ) => true,
+            (Some(TokenKind::LeftParen | TokenKind::Dot), _) => true,
+            // Calls
+            (Some(TokenKind::Identifier | TokenKind::RightParen), TokenKind::LeftParen) => true,
+            _ => false,
+        }
+    }
+
+    fn comment(&mut self, text: String, own_line: bool) {
+        if own_line {
+            self.end_line();
+        }
+        if self.line.is_empty() {
+            self.start_line();
+        }
+        let space_before = !self.line.is_empty();
+        self.line.push(Piece {
+            text,
+            space_before,
+            break_rank: None,
+        });
+        self.end_line();
+    }
+
+    fn start_line(&mut self) {
+        if self.blank_line && !self.output.is_empty() {
+            self.output.push('\n');
+        }
+        self.blank_line = false;
+        self.line_indent = self.indent;
+    }
+
+    fn end_line(&mut self) {
+        self.line_done = false;
+        if self.line.is_empty() {
+            return;
+        }
+
+        let line = std::mem::take(&mut self.line);
+        // `previous` is kept, since a line can end in a comment halfway
+        // through an expression
+        self.write_wrapped(&line, self.line_indent, self.line_indent + 1);
+    }
+
+    fn write_wrapped(&mut self, pieces: &[Piece], indent: usize, continuation: usize) {
+        if INDENT.len() * indent + width(pieces) > self.max_width
+            && let Some(segments) = split(pieces)
+        {
+            for (index, segment) in segments.into_iter().enumerate() {
+                let indent = if index == 0 { indent } else { continuation };
+                self.write_wrapped(segment, indent, continuation);
+            }
+            return;
+        }
+
+        self.output.push_str(&INDENT.repeat(indent));
+        for (index, piece) in pieces.iter().enumerate() {
+            if index > 0 && piece.space_before {
+                self.output.push(' ');
+            }
+            self.output.push_str(&piece.text);
+        }
+        self.output.push('\n');
+    }
+}
+
+fn ends_operand(kind: &TokenKind) -> bool {
+    matches!(
+        kind,
+        TokenKind::Number
+            | TokenKind::String
+            | TokenKind::Identifier
+            | TokenKind::True
+            | TokenKind::False
+            | TokenKind::Nil
+            | TokenKind::This
+            | TokenKind::Super
+            | TokenKind::RightParen
+    )
+}
+
+fn width(pieces: &[Piece]) -> usize {
+    pieces
+        .iter()
+        .enumerate()
+        .map(|(index, piece)| {
+            piece.text.chars().count() + usize::from(index > 0 && piece.space_before)
+        })
+        .sum()
+}
+
+// Splits a line before each of its loosest binding operators
+fn split(pieces: &[Piece]) -> Option<Vec<&[Piece]>> {
+    let loosest = pieces[1..]
+        .iter()
+        .filter_map(|piece| piece.break_rank)
+        .min()?;
+
+    let mut segments = Vec::new();
+    let mut start = 0;
+    for (index, piece) in pieces.iter().enumerate().skip(1) {
+        if piece.break_rank == Some(loosest) {
+            segments.push(&pieces[start..index]);
+            start = index;
+        }
+    }
+    segments.push(&pieces[start..]);
+    Some(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        formatter::{DEFAULT_MAX_WIDTH, format},
+        lexer::LexerError,
+    };
+
+    fn format_default(source: &str) -> String {
+        format(source, DEFAULT_MAX_WIDTH).expect("source lexes")
+    }
+
+    #[test]
+    fn test_spacing() {
+        assert_eq!(format_default("1+2*  -3"), "1 + 2 * -3\n");
+        assert_eq!(format_default("!( a==b )"), "!(a == b)\n");
+        assert_eq!(format_default("a - -b"), "a - -b\n");
+        assert_eq!(format_default("- - ! a"), "--!a\n");
+        assert_eq!(format_default("foo (a ,b) . c"), "foo(a, b).c\n");
+        assert_eq!(format_default("x = 1 and y  or!z;"), "x = 1 and y or !z;\n");
+    }
+
+    #[test]
+    fn test_statements_and_blocks() {
+        let source = "var a=1;print a;\n\n\n\nif(a>0){print a;}else{print -a;}\n\
+                      for(var i=0;i<10;i=i+1){ while (true) { } }";
+        let expected = "\
+var a = 1;
+print a;
+
+if (a > 0) {
+    print a;
+} else {
+    print -a;
+}
+for (var i = 0; i < 10; i = i + 1) {
+    while (true) {
+    }
+}
+";
+        assert_eq!(format_default(source), expected);
+    }
+
+    #[test]
+    fn test_preserves_comments() {
+        let source = "// header\nvar a = 1; // trailing\n  // own line\n{ // open\nprint a;}";
+        let expected = "\
+// header
+var a = 1; // trailing
+// own line
+{ // open
+    print a;
+}
+";
+        assert_eq!(format_default(source), expected);
+    }
+
+    #[test]
+    fn test_comment_inside_expression() {
+        assert_eq!(format_default("x = a // c\n  - b;"), "x = a // c\n- b;\n");
+        assert_eq!(format_default("x = - // c\n b;"), "x = - // c\nb;\n");
+    }
+
+    #[test]
+    fn test_wraps_long_lines() {
+        let source =
+            "print first_long_operand * second_operand + (third_operand - fourth) == result;";
+        let expected = "\
+print first_long_operand * second_operand
+    + (third_operand - fourth)
+    == result;
+";
+        assert_eq!(format(source, 45).expect("source lexes"), expected);
+
+        // Measured in characters rather than bytes
+        let source = "print \"ééééé\" + \"ééééé\";";
+        assert_eq!(
+            format(source, 24).expect("source lexes"),
+            format!("{}\n", source)
+        );
+    }
+
+    #[test]
+    fn test_idempotent() {
+        for source in [
+            "var a=1;print a;\n\n\n\nif(a>0){print a;}else{print -a;}",
+            "// c\n{ // open\nprint 1+2;}\n",
+            "print first_long_operand * second_operand + (third_operand - fourth) == result;",
+        ] {
+            let once = format(source, 45).expect("source lexes");
+            assert_eq!(format(&once, 45).expect("source lexes"), once);
+        }
+    }
+
+    #[test]
+    fn test_lexer_errors() {
+        assert!(format("print \"open", DEFAULT_MAX_WIDTH).is_err());
+        assert_eq!(
+            format("a @\nb # c", DEFAULT_MAX_WIDTH),
+            Err(LexerError::UnexpectedChar('@', 1))
+        );
+    }
+}
@@ -0,0 +1,630 @@
+use std::{
+    fmt::Display,
+    mem::size_of,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    chunk::Prototype,
+    slot::Slot,
+    table::{Table, hash_string},
+};
+
+// A handle to an object on a `Heap`. It stays valid for as long as the
+// object is reachable from the roots given to each collection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ObjRef(u32);
+
+impl ObjRef {
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+
+    // For representations that store the index rather than the handle
+    #[cfg(any(test, feature = "nan-boxing"))]
+    pub(crate) fn from_index(index: usize) -> ObjRef {
+        ObjRef(index as u32)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Obj {
+    String(ObjString),
+    Function(ObjFunction),
+    Closure(ObjClosure),
+    Upvalue(ObjUpvalue),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ObjString {
+    pub chars: Box<str>,
+    // Cached for tables keyed by the string
+    pub hash: u32,
+}
+
+impl ObjString {
+    pub fn new(chars: &str) -> ObjString {
+        ObjString {
+            chars: chars.into(),
+            hash: hash_string(chars),
+        }
+    }
+}
+
+// A compiled function with its constants loaded onto the heap, which
+// closures are made from
+#[derive(Debug, PartialEq)]
+pub struct ObjFunction {
+    pub prototype: Rc<Prototype>,
+    pub(crate) constants: Box<[Slot]>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ObjClosure {
+    pub function: ObjRef,
+    // In the order of the prototype's captures
+    pub upvalues: Box<[ObjRef]>,
+}
+
+// A variable captured by a closure. While the variable is in scope it lives
+// on the VM's stack and the upvalue points at it, and once it goes out of
+// scope the upvalue holds it instead. Closures that captured the same
+// variable share the upvalue, and so see each other's assignments.
+#[derive(Debug, PartialEq)]
+pub enum ObjUpvalue {
+    // The index of the variable's stack slot
+    Open(usize),
+    // Changed with `Heap::set_upvalue`, so that incremental marking sees
+    // the store
+    Closed(Slot),
+}
+
+impl Obj {
+    // What the object counts for towards the next collection
+    fn size(&self) -> usize {
+        let payload = match self {
+            Obj::String(string) => string.chars.len(),
+            Obj::Function(function) => size_of_val(&*function.constants),
+            Obj::Closure(closure) => size_of_val(&*closure.upvalues),
+            Obj::Upvalue(_) => 0,
+        };
+        size_of::<Entry>() + payload
+    }
+
+    // Adds the objects this one refers to to the gray worklist
+    fn trace(&self, gray: &mut Vec<ObjRef>) {
+        match self {
+            Obj::String(_) | Obj::Upvalue(ObjUpvalue::Open(_)) => {}
+            Obj::Function(function) => {
+                gray.extend(function.constants.iter().filter_map(|slot| slot.as_obj()))
+            }
+            Obj::Closure(closure) => {
+                gray.push(closure.function);
+                gray.extend(&closure.upvalues);
+            }
+            Obj::Upvalue(ObjUpvalue::Closed(slot)) => gray.extend(slot.as_obj()),
+        }
+    }
+}
+
+struct Entry {
+    obj: Obj,
+    marked: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum GcMode {
+    // Each collection marks and sweeps the whole heap at once
+    #[default]
+    StopTheWorld,
+    // Collections are spread over allocations, a bounded step at a time
+    Incremental,
+}
+
+// Where an incremental collection is up to
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Phase {
+    Idle,
+    Marking,
+    // Entries before the index have been swept
+    Sweeping(usize),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct GcStats {
+    // Completed collections
+    pub collections: usize,
+    pub bytes_freed: usize,
+    // The longest a single call to `collect` took
+    pub max_pause: Duration,
+}
+
+impl Display for GcStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} collections, {} bytes freed, longest pause {:?}",
+            self.collections, self.bytes_freed, self.max_pause
+        )
+    }
+}
+
+// The heap collects after this many bytes have been allocated, and from then
+// on once it has grown by the growth factor since the last collection
+pub const INITIAL_THRESHOLD: usize = 1 << 20;
+pub const DEFAULT_GROWTH_FACTOR: f64 = 2.0;
+// Objects marked or entries swept by one incremental step
+const STEP_WORK: usize = 32;
+
+// Objects managed by a tracing mark-sweep collector. Freed slots are reused,
+// so handles are small indices rather than pointers.
+pub struct Heap {
+    entries: Vec<Option<Entry>>,
+    free: Vec<u32>,
+    gray: Vec<ObjRef>,
+    // Every string on the heap, so equal strings can share one object. It
+    // doesn't keep them alive: the sweep removes the ones it frees.
+    strings: Table<()>,
+    phase: Phase,
+    mode: GcMode,
+    bytes_allocated: usize,
+    next_gc: usize,
+    growth_factor: f64,
+    stress: bool,
+    stats: GcStats,
+}
+
+impl Default for Heap {
+    fn default() -> Heap {
+        Heap::new()
+    }
+}
+
+impl Heap {
+    pub fn new() -> Heap {
+        Heap {
+            entries: Vec::new(),
+            free: Vec::new(),
+            gray: Vec::new(),
+            strings: Table::new(),
+            phase: Phase::Idle,
+            mode: GcMode::default(),
+            bytes_allocated: 0,
+            next_gc: INITIAL_THRESHOLD,
+            growth_factor: DEFAULT_GROWTH_FACTOR,
+            stress: false,
+            stats: GcStats::default(),
+        }
+    }
+
+    pub fn with_mode(self, mode: GcMode) -> Heap {
+        Heap { mode, ..self }
+    }
+
+    // How much the heap may grow after a collection before the next one.
+    // Factors below 1 are treated as 1.
+    pub fn with_growth_factor(self, growth_factor: f64) -> Heap {
+        Heap {
+            growth_factor: growth_factor.max(1.0),
+            ..self
+        }
+    }
+
+    // Asks for a collection, or an incremental step, before every allocation,
+    // to shake out objects that aren't rooted
+    pub fn with_stress(self, stress: bool) -> Heap {
+        Heap { stress, ..self }
+    }
+
+    // Whether the owner should `collect` before its next allocation
+    pub fn should_collect(&self) -> bool {
+        self.stress || self.bytes_allocated >= self.next_gc || self.is_collecting()
+    }
+
+    // Whether an incremental collection has started but not finished
+    pub fn is_collecting(&self) -> bool {
+        self.phase != Phase::Idle
+    }
+
+    // Strings should be allocated with `intern` instead, or they won't be
+    // found by it
+    pub fn alloc(&mut self, obj: Obj) -> ObjRef {
+        self.bytes_allocated += obj.size();
+        let entry = Some(Entry { obj, marked: false });
+        let obj = match self.free.pop() {
+            Some(index) => {
+                self.entries[index as usize] = entry;
+                ObjRef(index)
+            }
+            None => {
+                let index = u32::try_from(self.entries.len()).expect("fewer than 2^32 objects");
+                self.entries.push(entry);
+                ObjRef(index)
+            }
+        };
+
+        self.keep(obj);
+        obj
+    }
+
+    // The string object holding `chars`, allocated only if there isn't one
+    pub fn intern(&mut self, chars: &str) -> ObjRef {
+        let hash = hash_string(chars);
+        let existing = self.strings.find_key(hash, |obj| match self.get(obj) {
+            Obj::String(string) => *string.chars == *chars,
+            _ => false,
+        });
+        if let Some(obj) = existing {
+            // It may be garbage the current collection has yet to free
+            self.keep(obj);
+            return obj;
+        }
+
+        let obj = self.alloc(Obj::String(ObjString::new(chars)));
+        self.strings.insert(obj, hash, ());
+        obj
+    }
+
+    // Makes an object handed out during a collection survive it. While
+    // marking it turns gray so whatever it refers to is traced too, and
+    // while sweeping it is marked if the sweep has yet to reach it.
+    fn keep(&mut self, obj: ObjRef) {
+        match self.phase {
+            Phase::Idle => {}
+            Phase::Marking => self.gray.push(obj),
+            Phase::Sweeping(swept) => self.set_marked(obj, obj.index() >= swept),
+        }
+    }
+
+    pub fn get(&self, obj: ObjRef) -> &Obj {
+        &self.entry(obj).obj
+    }
+
+    // The number of live objects
+    pub fn len(&self) -> usize {
+        self.entries.len() - self.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn bytes_allocated(&self) -> usize {
+        self.bytes_allocated
+    }
+
+    pub fn stats(&self) -> GcStats {
+        self.stats
+    }
+
+    // Closes `upvalue` over `value`, or replaces the value it has closed over
+    pub(crate) fn set_upvalue(&mut self, upvalue: ObjRef, value: Slot) {
+        let Some(Entry {
+            obj: Obj::Upvalue(target),
+            ..
+        }) = &mut self.entries[upvalue.index()]
+        else {
+            panic!("{:?} is not a live upvalue", upvalue);
+        };
+        *target = ObjUpvalue::Closed(value);
+        if let Some(value) = value.as_obj() {
+            self.write_barrier(upvalue, value);
+        }
+    }
+
+    // Must be called when `parent` is changed to refer to `child`. Marking
+    // never revisits a black object, so without this a child stored in one
+    // during an incremental collection would be freed while still in use.
+    // Roots need no barrier since they are scanned again before marking ends.
+    fn write_barrier(&mut self, parent: ObjRef, child: ObjRef) {
+        if self.phase == Phase::Marking && self.entry(parent).marked && !self.entry(child).marked {
+            self.gray.push(child);
+        }
+    }
+
+    // Does the collection work the mode calls for and returns the number of
+    // bytes freed. That is a whole collection, finishing any that's under
+    // way, or in incremental mode a single step of one.
+    pub fn collect(&mut self, roots: impl IntoIterator<Item = ObjRef>) -> usize {
+        let start = Instant::now();
+        let work = match self.mode {
+            GcMode::StopTheWorld => usize::MAX,
+            GcMode::Incremental => STEP_WORK,
+        };
+        let freed = self.advance(roots, work);
+        self.stats.max_pause = self.stats.max_pause.max(start.elapsed());
+        freed
+    }
+
+    // Marks or sweeps until `work` objects have been handled or the
+    // collection is finished
+    fn advance(&mut self, roots: impl IntoIterator<Item = ObjRef>, mut work: usize) -> usize {
+        let mut roots = Some(roots);
+        if self.phase == Phase::Idle {
+            self.gray.extend(roots.take().into_iter().flatten());
+            self.phase = Phase::Marking;
+        }
+
+        let mut freed = 0;
+        while work > 0 {
+            match self.phase {
+                Phase::Idle => break,
+                Phase::Marking => {
+                    if let Some(obj) = self.gray.pop() {
+                        self.blacken(obj);
+                    } else {
+                        // The roots may have changed since the collection
+                        // started, so marking ends by scanning them again.
+                        // That is done in one go, or a busy program could
+                        // keep the collection from ever finishing.
+                        self.gray.extend(roots.take().into_iter().flatten());
+                        while let Some(obj) = self.gray.pop() {
+                            self.blacken(obj);
+                        }
+                        self.phase = Phase::Sweeping(0);
+                    }
+                }
+                Phase::Sweeping(index) if index == self.entries.len() => {
+                    self.phase = Phase::Idle;
+                    self.stats.collections += 1;
+                    self.next_gc = ((self.bytes_allocated as f64 * self.growth_factor) as usize)
+                        .max(INITIAL_THRESHOLD);
+                }
+                Phase::Sweeping(index) => {
+                    let slot = &mut self.entries[index];
+                    match slot {
+                        Some(entry) if entry.marked => entry.marked = false,
+                        Some(entry) => {
+                            freed += entry.obj.size();
+                            if let Obj::String(string) = &entry.obj {
+                                self.strings.remove(ObjRef(index as u32), string.hash);
+                            }
+                            *slot = None;
+                            self.free.push(index as u32);
+                        }
+                        None => {}
+                    }
+                    self.phase = Phase::Sweeping(index + 1);
+                }
+            }
+            work -= 1;
+        }
+
+        self.bytes_allocated -= freed;
+        self.stats.bytes_freed += freed;
+        freed
+    }
+
+    // An object is black once it is marked, so one reached again is skipped
+    // and cycles end
+    fn blacken(&mut self, obj: ObjRef) {
+        let Some(entry) = &mut self.entries[obj.index()] else {
+            panic!("{:?} was rooted after being collected", obj);
+        };
+        if !entry.marked {
+            entry.marked = true;
+            entry.obj.trace(&mut self.gray);
+        }
+    }
+
+    fn entry(&self, obj: ObjRef) -> &Entry {
+        match &self.entries[obj.index()] {
+            Some(entry) => entry,
+            None => panic!("{:?} was used after being collected", obj),
+        }
+    }
+
+    fn set_marked(&mut self, obj: ObjRef, marked: bool) {
+        if let Some(entry) = &mut self.entries[obj.index()] {
+            entry.marked = marked;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        gc::{GcMode, Heap, INITIAL_THRESHOLD, Obj, ObjRef, ObjString, ObjUpvalue},
+        slot::{Slot, Unpacked},
+    };
+
+    fn string(value: &str) -> Obj {
+        Obj::String(ObjString::new(value))
+    }
+
+    fn slot(value: Option<ObjRef>) -> Slot {
+        Slot::new(value.map_or(Unpacked::Nil, Unpacked::Obj))
+    }
+
+    // A closed upvalue holding `value`, or nil
+    fn upvalue(heap: &mut Heap, value: Option<ObjRef>) -> ObjRef {
+        let upvalue = heap.alloc(Obj::Upvalue(ObjUpvalue::Open(0)));
+        heap.set_upvalue(upvalue, slot(value));
+        upvalue
+    }
+
+    #[test]
+    fn test_collect_keeps_only_reachable_objects() {
+        let mut heap = Heap::new();
+        let kept = heap.alloc(string("kept"));
+        let lost = heap.alloc(string("lost"));
+        let bytes = heap.bytes_allocated();
+
+        let freed = heap.collect([kept, kept]);
+        assert_eq!(freed, bytes - heap.bytes_allocated());
+        assert_eq!(heap.len(), 1);
+        assert_eq!(heap.get(kept), &string("kept"));
+
+        // The freed slot is reused, and survivors can be collected later
+        assert_eq!(heap.alloc(string("new")), lost);
+        let freed_again = heap.collect([]);
+        assert!(heap.is_empty());
+        assert_eq!(heap.bytes_allocated(), 0);
+
+        let stats = heap.stats();
+        assert_eq!(stats.collections, 2);
+        assert_eq!(stats.bytes_freed, freed + freed_again);
+    }
+
+    #[test]
+    #[should_panic(expected = "after being collected")]
+    fn test_collected_handles_are_invalid() {
+        let mut heap = Heap::new();
+        let obj = heap.alloc(string("a"));
+        heap.collect([]);
+        heap.get(obj);
+    }
+
+    #[test]
+    fn test_collection_threshold() {
+        let mut heap = Heap::new();
+        assert!(!heap.should_collect());
+        let big = heap.alloc(string(&"x".repeat(INITIAL_THRESHOLD)));
+        assert!(heap.should_collect());
+
+        // A heap that stays big only collects again once it has doubled
+        heap.collect([big]);
+        assert!(!heap.should_collect());
+        heap.alloc(string(&"y".repeat(INITIAL_THRESHOLD)));
+        assert!(heap.should_collect());
+
+        // or tripled, with a bigger growth factor
+        let mut heap = Heap::new().with_growth_factor(3.0);
+        let big = heap.alloc(string(&"x".repeat(INITIAL_THRESHOLD)));
+        heap.collect([big]);
+        heap.alloc(string(&"y".repeat(INITIAL_THRESHOLD)));
+        assert!(!heap.should_collect());
+
+        assert!(Heap::new().with_stress(true).should_collect());
+    }
+
+    #[test]
+    fn test_incremental_collection() {
+        let mut heap = Heap::new().with_mode(GcMode::Incremental);
+        let objects = (0..100)
+            .map(|index| heap.alloc(string(&index.to_string())))
+            .collect::<Vec<_>>();
+        let roots = objects.iter().step_by(2).copied().collect::<Vec<_>>();
+
+        let mut steps = 0;
+        let mut during = Vec::new();
+        loop {
+            heap.collect(roots.iter().chain(&during).copied());
+            steps += 1;
+            if !heap.is_collecting() {
+                break;
+            }
+            // Objects allocated part way through survive, whether marking or
+            // sweeping was under way
+            during.push(heap.alloc(string("during")));
+        }
+
+        assert!(steps > 2, "took {} steps", steps);
+        assert_eq!(heap.len(), roots.len() + during.len());
+        for obj in roots.iter().chain(&during) {
+            heap.get(*obj);
+        }
+        assert_eq!(heap.stats().collections, 1);
+    }
+
+    #[test]
+    fn test_interning() {
+        let mut heap = Heap::new();
+        let a = heap.intern("a");
+        assert_eq!(heap.intern("a"), a);
+        assert_ne!(heap.intern("b"), a);
+        assert_eq!(heap.len(), 2);
+
+        // Interned strings are still collected, and then forgotten
+        heap.collect([a]);
+        assert_eq!(heap.len(), 1);
+        assert_eq!(heap.intern("a"), a);
+        let b = heap.intern("b");
+        assert_eq!(heap.get(b), &string("b"));
+        assert_eq!(heap.len(), 2);
+    }
+
+    #[test]
+    fn test_interning_during_a_sweep() {
+        let mut heap = Heap::new().with_mode(GcMode::Incremental);
+        let strings = (0..100)
+            .map(|index| heap.intern(&index.to_string()))
+            .collect::<Vec<_>>();
+
+        // Nothing is rooted, but a string looked up again before the sweep
+        // reaches it is in use and must be kept
+        heap.collect([]);
+        while heap.is_collecting() && heap.phase == super::Phase::Marking {
+            heap.collect([]);
+        }
+        assert_eq!(heap.intern("99"), strings[99]);
+        while heap.is_collecting() {
+            heap.collect([strings[99]]);
+        }
+        assert_eq!(heap.len(), 1);
+        assert_eq!(heap.get(strings[99]), &string("99"));
+    }
+
+    #[test]
+    fn test_cycles_are_reclaimed() {
+        for mode in [GcMode::StopTheWorld, GcMode::Incremental] {
+            let mut heap = Heap::new().with_mode(mode);
+
+            // A rooted cycle, which keeps the string it holds alive too
+            let held = heap.alloc(string("held"));
+            let kept = upvalue(&mut heap, None);
+            let other = upvalue(&mut heap, Some(kept));
+            heap.set_upvalue(kept, slot(Some(other)));
+            let holder = upvalue(&mut heap, Some(held));
+
+            // Two cycles nothing refers to, one through an upvalue itself
+            let lost = upvalue(&mut heap, None);
+            heap.set_upvalue(lost, slot(Some(lost)));
+            let first = upvalue(&mut heap, None);
+            let second = upvalue(&mut heap, Some(first));
+            heap.set_upvalue(first, slot(Some(second)));
+
+            heap.collect([kept, holder]);
+            while heap.is_collecting() {
+                heap.collect([kept, holder]);
+            }
+            assert_eq!(heap.len(), 4);
+            assert_eq!(heap.get(held), &string("held"));
+            assert_eq!(
+                heap.get(other),
+                &Obj::Upvalue(ObjUpvalue::Closed(slot(Some(kept))))
+            );
+
+            // Once the root goes, so does the cycle
+            heap.collect([holder]);
+            while heap.is_collecting() {
+                heap.collect([holder]);
+            }
+            assert_eq!(heap.len(), 2);
+        }
+    }
+
+    #[test]
+    fn test_write_barrier() {
+        let mut heap = Heap::new().with_mode(GcMode::Incremental);
+        let child = heap.alloc(string("child"));
+        let black = upvalue(&mut heap, None);
+        let gray = upvalue(&mut heap, Some(child));
+
+        // Once `black` has been traced, moving the child into it from an
+        // upvalue that hasn't must gray the child, as `black` won't be traced
+        // again and `gray` no longer leads to it
+        heap.advance([gray, black], 1);
+        assert!(heap.entry(black).marked);
+        assert!(!heap.entry(gray).marked);
+        heap.set_upvalue(black, slot(Some(child)));
+        heap.set_upvalue(gray, slot(None));
+
+        while heap.is_collecting() {
+            heap.collect([gray, black]);
+        }
+        assert_eq!(heap.len(), 3);
+        assert_eq!(heap.get(child), &string("child"));
+    }
+}
@@ -0,0 +1,422 @@
+use std::{fmt::Display, ops::Range};
+
+use crate::{
+    cst::{AstNode, GreenElement, GreenToken, GroupingNode, SyntaxElement, SyntaxKind, SyntaxNode},
+    cst_parser::{Parse, parse_syntax_with_max_depth},
+    lexer::{Lexeme, Lexer},
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextEdit {
+    pub range: Range<usize>,
+    pub replacement: String,
+}
+
+impl TextEdit {
+    pub fn new(range: Range<usize>, replacement: &str) -> TextEdit {
+        TextEdit {
+            range,
+            replacement: replacement.to_string(),
+        }
+    }
+
+    // Fails if the range is out of bounds or splits a character
+    pub fn apply(&self, text: &str) -> Result<String, EditError> {
+        if self.range.start > self.range.end || text.get(self.range.clone()).is_none() {
+            return Err(EditError::InvalidRange(self.range.clone()));
+        }
+        let mut text = text.to_string();
+        text.replace_range(self.range.clone(), &self.replacement);
+        Ok(text)
+    }
+
+    // The same edit expressed relative to a region starting at `offset`
+    fn shifted(&self, offset: usize) -> TextEdit {
+        TextEdit {
+            range: self.range.start - offset..self.range.end - offset,
+            replacement: self.replacement.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EditError {
+    InvalidRange(Range<usize>),
+}
+
+impl From<&EditError> for String {
+    fn from(value: &EditError) -> Self {
+        match value {
+            EditError::InvalidRange(range) => format!(
+                "Edit range {}..{} is out of bounds or not on character boundaries",
+                range.start, range.end
+            ),
+        }
+    }
+}
+
+impl From<EditError> for String {
+    fn from(value: EditError) -> Self {
+        String::from(&value)
+    }
+}
+
+impl Display for EditError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", String::from(self))
+    }
+}
+
+impl std::error::Error for EditError {}
+
+// Applies `edit` to the document `previous` was parsed from. Edits that stay
+// within a single token, or within a parenthesised group, are relexed and
+// reparsed on their own and spliced into the old tree, sharing every untouched
+// subtree. Anything else, including documents with errors, is parsed again
+// from scratch. Either way the result is identical to a full reparse with
+// the nesting limit `previous` was parsed with.
+pub fn reparse(previous: &Parse, edit: &TextEdit) -> Result<Parse, EditError> {
+    let text = edit.apply(&previous.syntax().to_string())?;
+    if previous.errors.is_empty()
+        && previous.lexer_errors.is_empty()
+        && let Some(parse) =
+            reparse_token(previous, edit).or_else(|| reparse_grouping(previous, edit))
+    {
+        return Ok(parse);
+    }
+
+    Ok(parse_syntax_with_max_depth(&text, previous.max_depth()))
+}
+
+// How many preceding tokens could change when the edited token does, as when
+// `1` `.` is followed by a digit and becomes a single number
+const LOOKBEHIND: usize = 2;
+
+fn reparse_token(previous: &Parse, edit: &TextEdit) -> Option<Parse> {
+    let tokens = previous.syntax().descendant_tokens();
+    let index = tokens.iter().position(|token| {
+        let range = token.text_range();
+        range.start <= edit.range.start && edit.range.end <= range.end
+    })?;
+    let token = &tokens[index];
+    let text = edit
+        .shifted(token.text_range().start)
+        .apply(token.text())
+        .ok()?;
+
+    // Relex the edited token with its neighbours to make sure it does not
+    // merge with or split from them
+    let before = &tokens[index.saturating_sub(LOOKBEHIND)..index];
+    let after = tokens.get(index + 1);
+    let mut window = before.iter().map(|token| token.text()).collect::<String>();
+    window.push_str(&text);
+    if let Some(after) = after {
+        window.push_str(after.text());
+    }
+
+    let lexemes = Lexer::new(window).scan_lexemes();
+    let unchanged = before.iter().chain(after);
+    let expected = before.len() + 1 + after.iter().len();
+    if lexemes.len() != expected
+        || !lexemes
+            .iter()
+            .enumerate()
+            .filter(|(position, _)| *position != before.len())
+            .zip(unchanged)
+            .all(|((_, (lexeme, text)), token)| {
+                text == token.text() && syntax_kind(lexeme) == Some(token.kind().clone())
+            })
+    {
+        return None;
+    }
+
+    let (lexeme, relexed) = &lexemes[before.len()];
+    if relexed != &text || syntax_kind(lexeme).as_ref() != Some(token.kind()) {
+        return None;
+    }
+
+    let green = token.replace_with(GreenToken::new(token.kind().clone(), text));
+    Some(Parse::new(green, Vec::new(), Vec::new()).with_max_depth(previous.max_depth()))
+}
+
+fn reparse_grouping(previous: &Parse, edit: &TextEdit) -> Option<Parse> {
+    let mut node = match previous.syntax().covering_element(edit.range.clone()) {
+        SyntaxElement::Node(node) => node,
+        SyntaxElement::Token(token) => token.parent(),
+    };
+
+    // Find the innermost group with the edit strictly between its parens
+    let grouping = loop {
+        if let Some(grouping) = GroupingNode::cast(node.clone())
+            && let (Some(left), Some(right)) = (grouping.left_paren(), grouping.right_paren())
+            && left.text_range().end <= edit.range.start
+            && edit.range.end <= right.text_range().start
+        {
+            break grouping;
+        }
+        node = node.parent()?;
+    };
+
+    // Parsed on its own the group is one level down, but in the document it
+    // is as deep as it was before, and may not nest any further than that
+    // allows
+    let range = grouping.syntax().text_range();
+    let text = edit
+        .shifted(range.start)
+        .apply(&grouping.syntax().to_string())
+        .ok()?;
+    let max_depth = previous
+        .max_depth()
+        .checked_sub(operand_depth(grouping.syntax()) - 1)?;
+    let parse = parse_syntax_with_max_depth(&text, max_depth);
+    if !parse.errors.is_empty() || !parse.lexer_errors.is_empty() {
+        return None;
+    }
+
+    let [GreenElement::Node(replacement)] = parse.green().children() else {
+        return None;
+    };
+    if replacement.kind() != &SyntaxKind::Grouping {
+        return None;
+    }
+
+    let green = grouping.syntax().replace_with(replacement.clone());
+    Some(Parse::new(green, Vec::new(), Vec::new()).with_max_depth(previous.max_depth()))
+}
+
+// The depth the parser was at when it reached `node`. The root expression is
+// at depth one, and each unary operand, grouped expression and right operand
+// is one level further in than the node it belongs to. A left operand is at
+// the same depth as its operator, since a chain is built by a loop.
+fn operand_depth(node: &SyntaxNode) -> usize {
+    let mut node = node.clone();
+    let mut depth = 1;
+    while let Some(parent) = node.parent() {
+        match parent.kind() {
+            SyntaxKind::Binary if parent.children().next().as_ref() == Some(&node) => {}
+            SyntaxKind::Unary | SyntaxKind::Binary | SyntaxKind::Grouping => depth += 1,
+            _ => break,
+        }
+        node = parent;
+    }
+    depth
+}
+
+fn syntax_kind(lexeme: &Lexeme) -> Option<SyntaxKind> {
+    match lexeme {
+        Lexeme::Token(Ok(token)) => Some(SyntaxKind::Token(token.kind.clone())),
+        Lexeme::Token(Err(_)) => None,
+        Lexeme::Whitespace => Some(SyntaxKind::Whitespace),
+        Lexeme::Comment => Some(SyntaxKind::Comment),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use crate::{
+        cst::GreenElement,
+        cst_parser::{Parse, parse_syntax, parse_syntax_with_max_depth},
+        incremental::{EditError, TextEdit, reparse},
+        parser::DEFAULT_MAX_DEPTH,
+        testing::Rng,
+    };
+
+    const FRAGMENTS: &[&str] = &[
+        "1", "23", "4.5", " ", "  ", "\n", "+", "-", "*", "/", "!", "=", "==", "<", ">=", "(", ")",
+        "\"s\"", "\"", "true", "nil", "x", "// c\n", ".", "@",
+    ];
+
+    fn random_expression(rng: &mut Rng, depth: usize) -> String {
+        match if depth == 0 { 0 } else { rng.below(4) } {
+            0 => rng
+                .pick(&["1", "23", "4.5", "\"str\"", "true", "false", "nil"])
+                .to_string(),
+            1 => format!(
+                "{}{}",
+                rng.pick(&["-", "!", "- "]),
+                random_expression(rng, depth - 1)
+            ),
+            2 => format!(
+                "{}{}{}{}{}",
+                random_expression(rng, depth - 1),
+                rng.pick(&[" ", "", "  "]),
+                rng.pick(&["+", "-", "*", "/", "==", "!=", "<", "<=", ">", ">="]),
+                rng.pick(&[" ", "", " // note\n"]),
+                random_expression(rng, depth - 1)
+            ),
+            _ => format!("( {} )", random_expression(rng, depth - 1)),
+        }
+    }
+
+    fn random_edit(rng: &mut Rng, text: &str) -> TextEdit {
+        // Mostly edits that keep the document valid, like a user typing, so
+        // that the incremental paths get exercised as well as the fallback
+        let is_number = |char: char| char.is_ascii_digit() || char == '.';
+        let numbers = text
+            .char_indices()
+            .filter(|(start, char)| char.is_ascii_digit() && !text[..*start].ends_with(is_number))
+            .map(|(start, _)| {
+                let len = text[start..].find(|char| !is_number(char));
+                start..len.map_or(text.len(), |len| start + len)
+            })
+            .collect::<Vec<_>>();
+        if !numbers.is_empty() && rng.below(4) != 0 {
+            let range = numbers[rng.below(numbers.len())].clone();
+            let replacement = match rng.below(3) {
+                0 => rng.pick(&["7", "89", "0.5"]).to_string(),
+                1 => format!("({})", random_expression(rng, 2)),
+                _ => format!("{} ", rng.pick(&["5", "6"])),
+            };
+            return TextEdit::new(range, &replacement);
+        }
+
+        let start = rng.below(text.len() + 1);
+        let end = (start + rng.below(3)).min(text.len());
+        let replacement = if rng.below(4) == 0 {
+            String::new()
+        } else {
+            rng.pick(FRAGMENTS).to_string()
+        };
+        TextEdit::new(start..end, &replacement)
+    }
+
+    fn assert_same(incremental: &Parse, full: &Parse) {
+        assert_eq!(incremental.green(), full.green());
+        assert_eq!(
+            format!("{:?}", incremental.errors),
+            format!("{:?}", full.errors)
+        );
+        assert_eq!(incremental.lexer_errors, full.lexer_errors);
+    }
+
+    #[test]
+    fn test_randomised_edits_match_full_reparse() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for _ in 0..200 {
+            let mut text = random_expression(&mut rng, 4);
+            let mut parse = parse_syntax(&text);
+            for _ in 0..10 {
+                let edit = random_edit(&mut rng, &text);
+                text = edit.apply(&text).expect("edit is in bounds");
+                parse = reparse(&parse, &edit).expect("edit is in bounds");
+                assert_same(&parse, &parse_syntax(&text));
+            }
+        }
+    }
+
+    #[test]
+    fn test_token_edit_reuses_siblings() {
+        let previous = parse_syntax("(1 + 2) * 3");
+        let edit = TextEdit::new(10..11, "45");
+        let parse = reparse(&previous, &edit).expect("edit is in bounds");
+        assert_same(&parse, &parse_syntax("(1 + 2) * 45"));
+
+        let [GreenElement::Node(old)] = previous.green().children() else {
+            panic!("expected a single expression");
+        };
+        let [GreenElement::Node(new)] = parse.green().children() else {
+            panic!("expected a single expression");
+        };
+        assert!(matches!(
+            (&old.children()[0], &new.children()[0]),
+            (GreenElement::Node(old), GreenElement::Node(new)) if Rc::ptr_eq(old, new)
+        ));
+    }
+
+    #[test]
+    fn test_grouping_edit_reuses_siblings() {
+        let previous = parse_syntax("3 * (1 + 2)");
+        let edit = TextEdit::new(7..8, "* 4 -");
+        let parse = reparse(&previous, &edit).expect("edit is in bounds");
+        assert_same(&parse, &parse_syntax("3 * (1 * 4 - 2)"));
+
+        let [GreenElement::Node(old)] = previous.green().children() else {
+            panic!("expected a single expression");
+        };
+        let [GreenElement::Node(new)] = parse.green().children() else {
+            panic!("expected a single expression");
+        };
+        assert!(matches!(
+            (&old.children()[0], &new.children()[0]),
+            (GreenElement::Node(old), GreenElement::Node(new)) if Rc::ptr_eq(old, new)
+        ));
+    }
+
+    #[test]
+    fn test_grouping_edit_keeps_its_depth() {
+        // The innermost group sits two levels below the limit, so the
+        // reparse only matches if it starts from the group's own depth
+        let groups = DEFAULT_MAX_DEPTH - 2;
+        let text = format!("{}1{}", "(".repeat(groups), ")".repeat(groups));
+        let previous = parse_syntax(&text);
+        assert!(previous.errors.is_empty());
+
+        for replacement in ["-1", "--1", "1 + 2", "1 + 2 + 3"] {
+            let edit = TextEdit::new(groups..groups + 1, replacement);
+            let expected = parse_syntax(&edit.apply(&text).expect("edit is in bounds"));
+            let parse = reparse(&previous, &edit).expect("edit is in bounds");
+            assert_same(&parse, &expected);
+        }
+        let edit = TextEdit::new(groups..groups + 1, "--1");
+        assert!(
+            !parse_syntax(&edit.apply(&text).expect("edit is in bounds"))
+                .errors
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_edits_keep_the_nesting_limit() {
+        let text = "(1) + 2";
+        let previous = parse_syntax_with_max_depth(text, 3);
+        for edit in [
+            // Within the group, first on its own and then in full
+            TextEdit::new(1..2, "-1"),
+            TextEdit::new(1..2, "--1"),
+            // Within a token
+            TextEdit::new(6..7, "23"),
+            // Anywhere else
+            TextEdit::new(6..7, "-2"),
+            TextEdit::new(6..7, "--2"),
+        ] {
+            let text = edit.apply(text).expect("edit is in bounds");
+            let parse = reparse(&previous, &edit).expect("edit is in bounds");
+            assert_same(&parse, &parse_syntax_with_max_depth(&text, 3));
+            assert_eq!(parse.max_depth(), 3);
+        }
+
+        let edit = TextEdit::new(1..2, "--1");
+        assert!(
+            !reparse(&previous, &edit)
+                .expect("edit is in bounds")
+                .errors
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_long_chain_edit() {
+        let text = format!("(1{})", " + 1".repeat(200_000));
+        let previous = parse_syntax(&text);
+        for edit in [TextEdit::new(1..2, "23"), TextEdit::new(1..2, "2 * 3")] {
+            let text = edit.apply(&text).expect("edit is in bounds");
+            let parse = reparse(&previous, &edit).expect("edit is in bounds");
+            assert!(parse.errors.is_empty());
+            assert_eq!(parse.syntax().to_string(), text);
+        }
+    }
+
+    #[test]
+    fn test_edit_off_a_char_boundary_is_an_error() {
+        let previous = parse_syntax("\"é\" + 1");
+        for range in [2..2, 1..2, 3..20] {
+            let edit = TextEdit::new(range.clone(), "x");
+            assert_eq!(
+                reparse(&previous, &edit).err(),
+                Some(EditError::InvalidRange(range))
+            );
+        }
+    }
+}
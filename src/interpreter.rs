@@ -0,0 +1,443 @@
+use std::{cell::RefCell, collections::HashMap, io::Write, mem, rc::Rc};
+
+use crate::{
+    expression::{AssignExpr, BinaryExpr, CallExpr, Expr, UnaryExpr},
+    statement::{FunctionStmt, Stmt},
+    token::Token,
+    value::{self, Function, MAX_CALL_DEPTH, RuntimeError, Value},
+};
+
+// Evaluates an expression on its own, with no variables defined
+pub fn evaluate(expr: &Expr) -> Result<Value, RuntimeError> {
+    Interpreter::new(&mut std::io::sink()).evaluate(expr)
+}
+
+// Runs a program, writing what it prints to `output`
+pub fn execute(program: &[Stmt], output: &mut dyn Write) -> Result<(), RuntimeError> {
+    let mut interpreter = Interpreter::new(output);
+    for statement in program {
+        interpreter.execute(statement)?;
+    }
+    Ok(())
+}
+
+// A local variable, linked to the one declared before it. Each declaration
+// adds a link rather than changing a table, so a closure that holds on to
+// the innermost link sees exactly the variables in scope where it was
+// written, and not ones declared after it. That makes names resolve the same
+// way they do in the VM, where it is decided when compiling.
+struct Local {
+    name: String,
+    value: RefCell<Value>,
+    enclosing: Scope,
+}
+
+type Scope = Option<Rc<Local>>;
+
+// Releases the chain one link at a time, as a long one would overflow the
+// stack if dropped recursively
+impl Drop for Local {
+    fn drop(&mut self) {
+        let mut enclosing = self.enclosing.take();
+        while let Some(mut local) = enclosing.and_then(Rc::into_inner) {
+            enclosing = local.enclosing.take();
+        }
+    }
+}
+
+// What the interpreter's functions are made of
+struct Closure {
+    declaration: Rc<FunctionStmt>,
+    scope: Scope,
+}
+
+// How a statement finished
+enum Flow {
+    Next,
+    Return(Value),
+}
+
+struct Interpreter<'a> {
+    // Globals are looked up by name when used, so a function can refer to
+    // one declared after it
+    globals: HashMap<String, Value>,
+    scope: Scope,
+    // How many blocks enclose the current statement. Outside of any,
+    // variables are global.
+    blocks: usize,
+    calls: usize,
+    output: &'a mut dyn Write,
+}
+
+impl<'a> Interpreter<'a> {
+    fn new(output: &'a mut dyn Write) -> Interpreter<'a> {
+        Interpreter {
+            globals: HashMap::new(),
+            scope: None,
+            blocks: 0,
+            calls: 0,
+            output,
+        }
+    }
+
+    fn execute(&mut self, statement: &Stmt) -> Result<Flow, RuntimeError> {
+        match statement {
+            Stmt::Expression(expression) => {
+                self.evaluate(expression)?;
+            }
+            Stmt::Print(print) => {
+                let value = self.evaluate(&print.expression)?;
+                writeln!(self.output, "{}", value)
+                    .map_err(|_| RuntimeError::Output(print.keyword.clone()))?;
+            }
+            Stmt::Var(var) => {
+                let value = match &var.initializer {
+                    Some(initializer) => self.evaluate(initializer)?,
+                    None => Value::Nil,
+                };
+                self.define(&var.name, value);
+            }
+            Stmt::Block(statements) => {
+                let scope = self.scope.clone();
+                self.blocks += 1;
+                let flow = self.execute_all(statements);
+                self.blocks -= 1;
+                self.scope = scope;
+                return flow;
+            }
+            Stmt::If(if_stmt) => {
+                if self.evaluate(&if_stmt.condition)?.is_truthy() {
+                    return self.execute(&if_stmt.then_branch);
+                } else if let Some(else_branch) = &if_stmt.else_branch {
+                    return self.execute(else_branch);
+                }
+            }
+            Stmt::While(while_stmt) => {
+                while self.evaluate(&while_stmt.condition)?.is_truthy() {
+                    if let Flow::Return(value) = self.execute(&while_stmt.body)? {
+                        return Ok(Flow::Return(value));
+                    }
+                }
+            }
+            Stmt::Function(declaration) => {
+                // Defined before the closure is made, so that a local
+                // function's closure includes it and it can call itself
+                self.define(&declaration.name, Value::Nil);
+                let closure = Closure {
+                    declaration: declaration.clone(),
+                    scope: self.scope.clone(),
+                };
+                let function = Function::new(declaration.name.name(), Rc::new(closure));
+                self.assign(&declaration.name, Value::Function(function))?;
+            }
+            Stmt::Return(return_stmt) => {
+                let value = match &return_stmt.value {
+                    Some(value) => self.evaluate(value)?,
+                    None => Value::Nil,
+                };
+                return Ok(Flow::Return(value));
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    fn execute_all(&mut self, statements: &[Stmt]) -> Result<Flow, RuntimeError> {
+        for statement in statements {
+            if let Flow::Return(value) = self.execute(statement)? {
+                return Ok(Flow::Return(value));
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    fn define(&mut self, name: &Token, value: Value) {
+        if self.blocks == 0 {
+            self.globals.insert(name.name().to_string(), value);
+        } else {
+            self.scope = Some(Rc::new(Local {
+                name: name.name().to_string(),
+                value: RefCell::new(value),
+                enclosing: self.scope.take(),
+            }));
+        }
+    }
+
+    fn local(&self, name: &str) -> Option<&Local> {
+        let mut scope = self.scope.as_deref();
+        while let Some(local) = scope {
+            if local.name == name {
+                return Some(local);
+            }
+            scope = local.enclosing.as_deref();
+        }
+        None
+    }
+
+    fn get(&self, name: &Token) -> Result<Value, RuntimeError> {
+        match self.local(name.name()) {
+            Some(local) => Ok(local.value.borrow().clone()),
+            None => self
+                .globals
+                .get(name.name())
+                .cloned()
+                .ok_or_else(|| RuntimeError::UndefinedVariable(name.clone())),
+        }
+    }
+
+    fn assign(&mut self, name: &Token, value: Value) -> Result<(), RuntimeError> {
+        if let Some(local) = self.local(name.name()) {
+            *local.value.borrow_mut() = value;
+        } else if let Some(global) = self.globals.get_mut(name.name()) {
+            *global = value;
+        } else {
+            return Err(RuntimeError::UndefinedVariable(name.clone()));
+        }
+        Ok(())
+    }
+
+    fn call(
+        &mut self,
+        callee: Value,
+        paren: &Token,
+        arguments: Vec<Value>,
+    ) -> Result<Value, RuntimeError> {
+        let closure = match callee {
+            Value::Function(function) => function.object().clone().downcast::<Closure>().ok(),
+            _ => None,
+        };
+        let Some(closure) = closure else {
+            return Err(RuntimeError::NotCallable(paren.clone()));
+        };
+        let params = &closure.declaration.params;
+        if arguments.len() != params.len() {
+            return Err(RuntimeError::WrongArity(
+                paren.clone(),
+                params.len(),
+                arguments.len(),
+            ));
+        }
+        if self.calls == MAX_CALL_DEPTH {
+            return Err(RuntimeError::StackOverflow(paren.clone()));
+        }
+
+        // The parameters and the body's variables are added to the scope
+        // the function was declared in
+        let mut scope = closure.scope.clone();
+        for (param, value) in params.iter().zip(arguments) {
+            scope = Some(Rc::new(Local {
+                name: param.name().to_string(),
+                value: RefCell::new(value),
+                enclosing: scope,
+            }));
+        }
+        let scope = mem::replace(&mut self.scope, scope);
+        let blocks = mem::replace(&mut self.blocks, 1);
+        self.calls += 1;
+        let flow = self.execute_all(&closure.declaration.body);
+        self.calls -= 1;
+        self.blocks = blocks;
+        self.scope = scope;
+
+        match flow? {
+            Flow::Return(value) => Ok(value),
+            Flow::Next => Ok(Value::Nil),
+        }
+    }
+
+    // Walks the tree with an explicit stack rather than recursion, since a
+    // chain of binary operators can be far deeper than the parser's nesting
+    // limit
+    fn evaluate(&mut self, expr: &Expr) -> Result<Value, RuntimeError> {
+        enum Step<'a> {
+            Evaluate(&'a Expr),
+            Unary(&'a UnaryExpr),
+            Binary(&'a BinaryExpr),
+            Assign(&'a AssignExpr),
+            Call(&'a CallExpr),
+        }
+
+        let mut steps = vec![Step::Evaluate(expr)];
+        let mut values = Vec::new();
+        while let Some(step) = steps.pop() {
+            match step {
+                Step::Evaluate(expr) => match expr {
+                    Expr::BooleanLiteral(_)
+                    | Expr::NumberLiteral(_)
+                    | Expr::StringLiteral(_)
+                    | Expr::NilLiteral => {
+                        values.push(Value::from_literal(expr).expect("literals have a value"))
+                    }
+                    Expr::Unary(unary) => {
+                        steps.push(Step::Unary(unary));
+                        steps.push(Step::Evaluate(&unary.right));
+                    }
+                    Expr::Binary(binary) => {
+                        steps.push(Step::Binary(binary));
+                        steps.push(Step::Evaluate(&binary.right));
+                        steps.push(Step::Evaluate(&binary.left));
+                    }
+                    Expr::Grouping(grouping) => steps.push(Step::Evaluate(&grouping.expression)),
+                    Expr::Variable(name) => values.push(self.get(name)?),
+                    Expr::Assign(assign) => {
+                        steps.push(Step::Assign(assign));
+                        steps.push(Step::Evaluate(&assign.value));
+                    }
+                    Expr::Call(call) => {
+                        steps.push(Step::Call(call));
+                        steps.extend(call.arguments.iter().rev().map(Step::Evaluate));
+                        steps.push(Step::Evaluate(&call.callee));
+                    }
+                },
+                Step::Unary(unary) => {
+                    let right = values.pop().expect("the operand was evaluated");
+                    values.push(value::unary(&unary.operator, right)?);
+                }
+                Step::Binary(binary) => {
+                    let right = values.pop().expect("the right operand was evaluated");
+                    let left = values.pop().expect("the left operand was evaluated");
+                    values.push(value::binary(left, &binary.operator, right)?);
+                }
+                Step::Assign(assign) => {
+                    let value = values.last().expect("the value was evaluated").clone();
+                    self.assign(&assign.name, value)?;
+                }
+                Step::Call(call) => {
+                    let arguments = values.split_off(values.len() - call.arguments.len());
+                    let callee = values.pop().expect("the callee was evaluated");
+                    values.push(self.call(callee, &call.paren, arguments)?);
+                }
+            }
+        }
+        Ok(values.pop().expect("the expression has a value"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        interpreter::{evaluate, execute},
+        optimizer::optimize,
+        parse, parse_program,
+        token::{Token, TokenKind},
+        value::{MAX_CALL_DEPTH, RuntimeError, Value},
+    };
+
+    fn output(source: &str) -> Result<String, RuntimeError> {
+        let program = parse_program(source).expect("source parses");
+        let mut output = Vec::new();
+        execute(&program, &mut output)?;
+        Ok(String::from_utf8(output).expect("output is UTF-8"))
+    }
+
+    #[test]
+    fn test_evaluates() {
+        for (source, expected) in [
+            ("1 + 2 * 3", Value::Number(7.0)),
+            ("(1 + 2) * 3", Value::Number(9.0)),
+            ("10 - 4 - 3", Value::Number(3.0)),
+            ("\"a\" + \"b\"", Value::String("ab".to_string())),
+            ("!nil", Value::Boolean(true)),
+            ("1 < 2 == true", Value::Boolean(true)),
+            ("nil == false", Value::Boolean(false)),
+        ] {
+            assert_eq!(
+                evaluate(&parse(source).expect("source parses")),
+                Ok(expected)
+            );
+        }
+    }
+
+    #[test]
+    fn test_runtime_errors() {
+        assert_eq!(
+            evaluate(&parse("1 + -\"x\"").expect("source parses")),
+            Err(RuntimeError::OperandMustBeNumber(Token::from(
+                TokenKind::Minus
+            )))
+        );
+        assert_eq!(
+            evaluate(&parse("true * 2").expect("source parses")),
+            Err(RuntimeError::OperandsMustBeNumbers(Token::from(
+                TokenKind::Star
+            )))
+        );
+    }
+
+    #[test]
+    fn test_optimizer_preserves_meaning() {
+        for source in [
+            "1 + 2 * 3 - -4 / 2",
+            "!(1 >= 2) == !nil",
+            "\"a\" + \"b\" == \"ab\"",
+            "(1 + 2) * -\"x\"",
+            "\"a\" + 1",
+        ] {
+            let expr = parse(source).expect("source parses");
+            assert_eq!(evaluate(&optimize(expr.clone())), evaluate(&expr));
+        }
+    }
+
+    #[test]
+    fn test_long_chain() {
+        let source = format!("1{}", " + 1".repeat(199_999));
+        assert_eq!(
+            evaluate(&parse(&source).expect("source parses")),
+            Ok(Value::Number(200_000.0))
+        );
+    }
+
+    #[test]
+    fn test_executes_statements() {
+        let source = "
+            var a = 1;
+            { var b = a + 1; print b; }
+            print a;
+            if (a == 1) print \"then\"; else print \"else\";
+            var i = 0;
+            while (i < 3) i = i + 1;
+            print i;
+            fun add(x, y) { return x + y; }
+            print add(i, 4);
+            print add;
+        ";
+        assert_eq!(
+            output(source),
+            Ok("2\n1\nthen\n3\n7\n<fn add>\n".to_string())
+        );
+    }
+
+    #[test]
+    fn test_closures_are_statically_scoped() {
+        // The closure sees the global, as the local declared after it isn't
+        // in scope where it was written
+        let source = "
+            var a = \"global\";
+            {
+                fun show() { print a; }
+                show();
+                var a = \"block\";
+                show();
+            }
+        ";
+        assert_eq!(output(source), Ok("global\nglobal\n".to_string()));
+    }
+
+    #[test]
+    fn test_call_errors() {
+        let error = |source| output(source).expect_err("program fails").to_string();
+        assert_eq!(error("print x;"), "Line 1:7: Undefined variable 'x'");
+        assert_eq!(error("y = 1;"), "Line 1:1: Undefined variable 'y'");
+        assert_eq!(error("\"f\"();"), "Line 1:5: Can only call functions");
+        assert_eq!(
+            error("fun f(a) {} f(1, 2);"),
+            "Line 1:19: Expected 1 arguments but got 2"
+        );
+        assert_eq!(error("fun f() { f(); } f();"), "Line 1:13: Stack overflow");
+
+        // `f(n)` makes n + 1 calls, which may all be in progress at once up
+        // to the limit
+        let source = |n: usize| format!("fun f(n) {{ if (n > 0) f(n - 1); }} f({});", n);
+        assert!(output(&source(MAX_CALL_DEPTH - 1)).is_ok());
+        assert!(output(&source(MAX_CALL_DEPTH)).is_err());
+    }
+}
@@ -1,5 +1,5 @@
-use crate::token::Token;
 use crate::token::TokenKind;
+use crate::token::{Span, Token};
 
 macro_rules! scan_operator {
     ($self:ident, $char:literal, $token1:ident, $token2:ident) => {
@@ -12,11 +12,24 @@ macro_rules! scan_operator {
     };
 }
 
+fn is_whitespace(char: char) -> bool {
+    matches!(char, ' ' | '\t' | '\r' | '\n')
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Lexeme {
+    Token(Result<Token, LexerError>),
+    Whitespace,
+    Comment,
+}
+
 pub struct Lexer {
     source: Vec<char>,
 
     position: usize,
     line_count: usize,
+    // Position of the first character on the current line
+    line_start: usize,
 }
 
 impl Lexer {
@@ -25,6 +38,7 @@ impl Lexer {
             source: source.chars().collect(),
             position: 0,
             line_count: 1,
+            line_start: 0,
         }
     }
 
@@ -39,56 +53,96 @@ impl Lexer {
     }
 
     fn next(&mut self) -> Option<char> {
-        self.peek().inspect(|_char| {
+        self.peek().inspect(|char| {
             self.position += 1;
+            if *char == '\n' {
+                self.line_count += 1;
+                self.line_start = self.position;
+            }
         })
     }
 
     pub fn scan_tokens(&mut self) -> Vec<Result<Token, LexerError>> {
         let mut tokens = Vec::<Result<Token, LexerError>>::new();
 
-        while let Some(char) = self.next() {
-            match char {
-                '(' => tokens.push(Ok(Token::from(TokenKind::LeftParen))),
-                ')' => tokens.push(Ok(Token::from(TokenKind::RightParen))),
-                '{' => tokens.push(Ok(Token::from(TokenKind::LeftBrace))),
-                '}' => tokens.push(Ok(Token::from(TokenKind::RightBrace))),
-                ',' => tokens.push(Ok(Token::from(TokenKind::Comma))),
-                '.' => tokens.push(Ok(Token::from(TokenKind::Dot))),
-                '-' => tokens.push(Ok(Token::from(TokenKind::Minus))),
-                '+' => tokens.push(Ok(Token::from(TokenKind::Plus))),
-                ';' => tokens.push(Ok(Token::from(TokenKind::Semicolon))),
-                '*' => tokens.push(Ok(Token::from(TokenKind::Star))),
-
-                '!' => tokens.push(scan_operator!(self, '=', BangEqual, Bang)),
-                '=' => tokens.push(scan_operator!(self, '=', EqualEqual, Equal)),
-                '<' => tokens.push(scan_operator!(self, '=', LessEqual, Less)),
-                '>' => tokens.push(scan_operator!(self, '=', GreaterEqual, Greater)),
-
-                '/' => match self.peek() {
-                    Some('/') => self.scan_comment(),
-                    _ => tokens.push(Ok(Token::from(TokenKind::Slash))),
-                },
-
-                ' ' | '\t' | '\r' => {}
-
-                '\n' => {
-                    self.line_count += 1;
-                }
+        while let Some(lexeme) = self.scan_lexeme() {
+            if let Lexeme::Token(token) = lexeme {
+                tokens.push(token);
+            }
+        }
 
-                '"' => tokens.push(self.scan_string()),
+        tokens.push(Ok(Token::from(TokenKind::EoF)));
 
-                char if char.is_ascii_digit() => tokens.push(self.scan_number()),
+        tokens
+    }
 
-                char if char.is_alphabetic() || char == '_' => tokens.push(self.scan_word()),
+    // Every character of the source belongs to exactly one lexeme, so joining
+    // the text of the returned lexemes reproduces the source.
+    pub fn scan_lexemes(&mut self) -> Vec<(Lexeme, String)> {
+        let mut lexemes = Vec::new();
+
+        loop {
+            let start = self.position;
+            let Some(lexeme) = self.scan_lexeme() else {
+                break;
+            };
+            lexemes.push((lexeme, self.source[start..self.position].iter().collect()));
+        }
+
+        lexemes
+    }
 
-                _ => tokens.push(Err(LexerError::UnexpectedChar(char, self.line_count))),
+    fn scan_lexeme(&mut self) -> Option<Lexeme> {
+        let span = Span::new(self.line_count, self.position - self.line_start + 1);
+        let char = self.next()?;
+        let token = match char {
+            '(' => Ok(Token::from(TokenKind::LeftParen)),
+            ')' => Ok(Token::from(TokenKind::RightParen)),
+            '{' => Ok(Token::from(TokenKind::LeftBrace)),
+            '}' => Ok(Token::from(TokenKind::RightBrace)),
+            ',' => Ok(Token::from(TokenKind::Comma)),
+            '.' => Ok(Token::from(TokenKind::Dot)),
+            '-' => Ok(Token::from(TokenKind::Minus)),
+            '+' => Ok(Token::from(TokenKind::Plus)),
+            ';' => Ok(Token::from(TokenKind::Semicolon)),
+            '*' => Ok(Token::from(TokenKind::Star)),
+
+            '!' => scan_operator!(self, '=', BangEqual, Bang),
+            '=' => scan_operator!(self, '=', EqualEqual, Equal),
+            '<' => scan_operator!(self, '=', LessEqual, Less),
+            '>' => scan_operator!(self, '=', GreaterEqual, Greater),
+
+            '/' => match self.peek() {
+                Some('/') => {
+                    self.scan_comment();
+                    return Some(Lexeme::Comment);
+                }
+                _ => Ok(Token::from(TokenKind::Slash)),
+            },
+
+            char if is_whitespace(char) => {
+                self.scan_whitespace();
+                return Some(Lexeme::Whitespace);
             }
-        }
 
-        tokens.push(Ok(Token::from(TokenKind::EoF)));
+            '"' => self.scan_string(),
 
-        tokens
+            char if char.is_ascii_digit() => self.scan_number(),
+
+            char if char.is_alphabetic() || char == '_' => self.scan_word(),
+
+            _ => Err(LexerError::UnexpectedChar(char, self.line_count)),
+        };
+
+        Some(Lexeme::Token(token.map(|token| token.with_span(span))))
+    }
+
+    fn scan_whitespace(&mut self) {
+        while let Some(char) = self.peek()
+            && is_whitespace(char)
+        {
+            self.next();
+        }
     }
 
     fn scan_comment(&mut self) {
@@ -104,9 +158,6 @@ impl Lexer {
         while let Some(char) = self.peek()
             && char != '"'
         {
-            if self.source[self.position] == '\n' {
-                self.line_count += 1;
-            }
             self.next();
         }
 
@@ -293,4 +344,65 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_lexemes_are_lossless() {
+        let source = "1 +\t// comment\n  (\"two\" @)\n";
+        let mut lexer = Lexer::new(source.to_string());
+        let lexemes = lexer.scan_lexemes();
+        assert_eq!(
+            lexemes
+                .iter()
+                .map(|(_, text)| text.as_str())
+                .collect::<String>(),
+            source
+        );
+        assert_eq!(
+            lexemes,
+            vec![
+                (
+                    Lexeme::Token(Ok(Token::from((TokenKind::Number, 1.0)))),
+                    "1".to_string()
+                ),
+                (Lexeme::Whitespace, " ".to_string()),
+                (
+                    Lexeme::Token(Ok(Token::from(TokenKind::Plus))),
+                    "+".to_string()
+                ),
+                (Lexeme::Whitespace, "\t".to_string()),
+                (Lexeme::Comment, "// comment".to_string()),
+                (Lexeme::Whitespace, "\n  ".to_string()),
+                (
+                    Lexeme::Token(Ok(Token::from(TokenKind::LeftParen))),
+                    "(".to_string()
+                ),
+                (
+                    Lexeme::Token(Ok(Token::from((TokenKind::String, "two".to_string())))),
+                    "\"two\"".to_string()
+                ),
+                (Lexeme::Whitespace, " ".to_string()),
+                (
+                    Lexeme::Token(Err(LexerError::UnexpectedChar('@', 2))),
+                    "@".to_string()
+                ),
+                (
+                    Lexeme::Token(Ok(Token::from(TokenKind::RightParen))),
+                    ")".to_string()
+                ),
+                (Lexeme::Whitespace, "\n".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_spans() {
+        let source = "1 +\n  (\"two\nlines\" // note\n\t)";
+        let spans = Lexer::new(source.to_string())
+            .scan_tokens()
+            .into_iter()
+            .map(|token| token.expect("source lexes"))
+            .map(|token| (token.span.line, token.span.column))
+            .collect::<Vec<_>>();
+        assert_eq!(spans, vec![(1, 1), (1, 3), (2, 3), (2, 4), (4, 2), (0, 0)]);
+    }
 }
@@ -0,0 +1,159 @@
+use std::fmt::Display;
+
+// The stable API is the functions and types at the root, and the
+// `expression`, `statement`, `token` and `value` modules they are built from. The other
+// modules are public only for the `lox-rs` binary and tests, and may change.
+#[doc(hidden)]
+pub mod arena;
+#[doc(hidden)]
+pub mod ast_display;
+#[doc(hidden)]
+pub mod bytecode;
+#[doc(hidden)]
+pub mod chunk;
+#[doc(hidden)]
+pub mod compiler;
+#[doc(hidden)]
+pub mod cst;
+#[doc(hidden)]
+pub mod cst_parser;
+#[doc(hidden)]
+pub mod disassembler;
+pub mod expression;
+#[doc(hidden)]
+pub mod formatter;
+#[doc(hidden)]
+pub mod gc;
+#[doc(hidden)]
+pub mod incremental;
+pub(crate) mod interpreter;
+#[doc(hidden)]
+pub mod lexer;
+#[doc(hidden)]
+pub mod optimizer;
+#[doc(hidden)]
+pub mod parser;
+#[doc(hidden)]
+pub mod peephole;
+#[doc(hidden)]
+pub mod printer;
+#[cfg(feature = "serde")]
+#[doc(hidden)]
+pub mod serialize;
+pub(crate) mod slot;
+pub mod statement;
+#[doc(hidden)]
+pub mod table;
+#[cfg(test)]
+mod testing;
+pub mod token;
+pub mod value;
+#[doc(hidden)]
+pub mod vm;
+
+pub use crate::{
+    bytecode::LoadError,
+    compiler::CompileError,
+    expression::Expr,
+    lexer::LexerError,
+    parser::ParserError,
+    statement::Stmt,
+    token::{Token, TokenKind},
+    value::{RuntimeError, Value},
+};
+
+#[derive(Debug)]
+pub enum Error {
+    Lexer(LexerError),
+    Parser(ParserError),
+    Compile(CompileError),
+    Runtime(RuntimeError),
+    Load(LoadError),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Lexer(error) => write!(f, "{}", error),
+            Error::Parser(error) => write!(f, "{}", error),
+            Error::Compile(error) => write!(f, "{}", error),
+            Error::Runtime(error) => write!(f, "{}", error),
+            Error::Load(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Lexer(error) => Some(error),
+            Error::Parser(error) => Some(error),
+            Error::Compile(error) => Some(error),
+            Error::Runtime(error) => Some(error),
+            Error::Load(error) => Some(error),
+        }
+    }
+}
+
+impl From<LexerError> for Error {
+    fn from(value: LexerError) -> Self {
+        Error::Lexer(value)
+    }
+}
+
+impl From<ParserError> for Error {
+    fn from(value: ParserError) -> Self {
+        Error::Parser(value)
+    }
+}
+
+impl From<CompileError> for Error {
+    fn from(value: CompileError) -> Self {
+        Error::Compile(value)
+    }
+}
+
+impl From<RuntimeError> for Error {
+    fn from(value: RuntimeError) -> Self {
+        Error::Runtime(value)
+    }
+}
+
+impl From<LoadError> for Error {
+    fn from(value: LoadError) -> Self {
+        Error::Load(value)
+    }
+}
+
+impl From<Error> for String {
+    fn from(value: Error) -> Self {
+        value.to_string()
+    }
+}
+
+// Scans `source` into tokens, ending with `EoF`, stopping at the first error
+pub fn lex(source: &str) -> Result<Vec<Token>, LexerError> {
+    lexer::Lexer::new(source.to_string())
+        .scan_tokens()
+        .into_iter()
+        .collect()
+}
+
+pub fn parse(source: &str) -> Result<Expr, Error> {
+    Ok(parser::Parser::new(lex(source)?).parse()?)
+}
+
+// Parses a whole program, reporting the first error
+pub fn parse_program(source: &str) -> Result<Vec<Stmt>, Error> {
+    Ok(parser::Parser::new(lex(source)?).parse_program()?)
+}
+
+pub fn interpret(expr: &Expr) -> Result<Value, RuntimeError> {
+    interpreter::evaluate(expr)
+}
+
+// Runs a program with the tree-walking interpreter, writing what it prints
+// to `output`
+pub fn execute(program: &[Stmt], output: &mut impl std::io::Write) -> Result<(), RuntimeError> {
+    interpreter::execute(program, output)
+}
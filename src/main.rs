@@ -1,18 +1,26 @@
-use clap::Parser as ClapParser;
-
-mod token;
-use token::{Token, TokenKind};
-
-mod lexer;
-use lexer::Lexer;
-
-mod parser;
-use parser::Parser;
+use std::{io::Read, rc::Rc};
 
-use crate::{ast_display::AstDisplay, expression::{BinaryExpr, Expr, GroupingExpr,  UnaryExpr}};
-
-mod ast_display;
-mod expression;
+use clap::Parser as ClapParser;
+use lox::{
+    Value,
+    arena::ExprArena,
+    ast_display::AstDisplay,
+    bytecode,
+    chunk::Chunk,
+    compiler, cst_parser, disassembler,
+    expression::Expr,
+    formatter,
+    gc::{self, GcMode, Heap},
+    lexer::Lexer,
+    optimizer,
+    parser::{self, Parser},
+    peephole,
+    printer,
+    statement::Stmt,
+    vm::Vm,
+};
+#[cfg(feature = "serde")]
+use lox::serialize;
 
 #[derive(clap::Parser)]
 struct Args {
@@ -24,74 +32,610 @@ struct Args {
 #[derive(clap::Subcommand)]
 enum Commands {
     Lex {
-        file: String,
+        /// Files to read, `-` for stdin
+        #[arg(required_unless_present = "eval")]
+        files: Vec<String>,
+
+        /// Source to use before any files
+        #[arg(short, long)]
+        eval: Option<String>,
     },
     Parse {
+        /// Files to read, `-` for stdin
+        #[arg(required_unless_present = "eval")]
+        files: Vec<String>,
+
+        /// Source to use before any files
+        #[arg(short, long)]
+        eval: Option<String>,
+
+        /// Print the lossless concrete syntax tree instead of the AST
+        #[arg(long)]
+        cst: bool,
+
+        /// Maximum nesting depth of expressions
+        #[arg(long, default_value_t = parser::DEFAULT_MAX_DEPTH)]
+        max_depth: usize,
+
+        #[arg(long, value_enum, default_value_t)]
+        style: AstStyle,
+
+        /// Fold constant expressions before printing the AST
+        #[arg(long)]
+        optimize: bool,
+    },
+    /// Evaluate an expression and print its value
+    Eval {
+        /// The expression, read from stdin when missing or `-`
+        expression: Option<String>,
+
+        /// Print the AST before evaluating it
+        #[arg(long)]
+        ast: bool,
+
+        #[arg(long, value_enum, default_value_t)]
+        style: AstStyle,
+
+        /// Fold constant expressions before evaluating
+        #[arg(long)]
+        optimize: bool,
+    },
+    /// Compile a program to a bytecode file that `run` can load
+    Compile {
+        /// File to read, `-` for stdin
         file: String,
+
+        /// Where to write the bytecode, by default the file with a `.loxc` extension
+        #[arg(short, long)]
+        output: Option<String>,
+
+        /// Fold constant expressions before compiling, and imply --peephole
+        #[arg(short = 'O', long)]
+        optimize: bool,
+
+        /// Fuse bytecode instructions into superinstructions, without folding constants
+        #[arg(long)]
+        peephole: bool,
     },
-    PrintAst,
+    /// Run programs, printing the value of any that is a single expression
+    Run {
+        /// Files to read, `-` for stdin. Compiled bytecode always runs on the VM.
+        #[arg(required_unless_present = "eval")]
+        files: Vec<String>,
+
+        /// Source to use before any files
+        #[arg(short, long)]
+        eval: Option<String>,
+
+        #[arg(long, value_enum, default_value_t)]
+        backend: Backend,
+
+        /// Fold constant expressions before running, and on the VM imply --peephole
+        #[arg(short = 'O', long)]
+        optimize: bool,
+
+        /// Fuse bytecode instructions into superinstructions, for expressions with --backend vm
+        #[arg(long)]
+        peephole: bool,
+
+        /// Print the stack and each instruction as the VM runs them, with --backend vm
+        #[arg(long)]
+        trace: bool,
+
+        /// Collect garbage before every allocation, with --backend vm
+        #[arg(long)]
+        gc_stress: bool,
+
+        /// How the VM collects garbage
+        #[arg(long, value_enum, default_value_t)]
+        gc: Gc,
+
+        /// How much the heap may grow after a collection before the next one
+        #[arg(long, default_value_t = gc::DEFAULT_GROWTH_FACTOR)]
+        gc_growth_factor: f64,
+
+        /// Print garbage collection statistics to stderr when done
+        #[arg(long)]
+        gc_stats: bool,
+    },
+    /// Print the bytecode programs compile to
+    Disasm {
+        /// Files to read, `-` for stdin
+        #[arg(required_unless_present = "eval")]
+        files: Vec<String>,
+
+        /// Source to use before any files
+        #[arg(short, long)]
+        eval: Option<String>,
+
+        /// Fold constant expressions before compiling, and imply --peephole
+        #[arg(short = 'O', long)]
+        optimize: bool,
+
+        /// Fuse bytecode instructions into superinstructions, without folding constants
+        #[arg(long)]
+        peephole: bool,
+    },
+    /// Reformat Lox files in place
+    Fmt {
+        #[arg(required = true)]
+        files: Vec<String>,
+
+        /// Only report files that need formatting, failing if there are any
+        #[arg(long)]
+        check: bool,
+
+        /// Lines longer than this are wrapped at binary operators
+        #[arg(long, default_value_t = formatter::DEFAULT_MAX_WIDTH)]
+        max_width: usize,
+    },
+}
+
+#[derive(Clone, Copy, Default, clap::ValueEnum)]
+enum AstStyle {
+    /// Lisp-style S-expressions
+    #[default]
+    Lisp,
+    /// Reverse Polish notation
+    Rpn,
+    /// Indented tree
+    Tree,
+    /// Graphviz DOT graph
+    Dot,
+    /// Lox source code
+    Lox,
+    /// JSON, for tools outside Rust
+    #[cfg(feature = "serde")]
+    Json,
+}
+
+impl AstStyle {
+    fn render(self, expression: &Expr) -> String {
+        match self {
+            AstStyle::Lisp => expression.ast(),
+            AstStyle::Rpn => expression.rpn(),
+            AstStyle::Tree => expression.tree(),
+            AstStyle::Dot => expression.dot(),
+            AstStyle::Lox => printer::print(expression),
+            #[cfg(feature = "serde")]
+            AstStyle::Json => serialize::to_json(expression),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, clap::ValueEnum)]
+enum Backend {
+    /// Tree walking interpreter
+    #[default]
+    Tree,
+    /// Bytecode virtual machine
+    Vm,
+}
+
+impl Backend {
+    // Gives back the value of an expression, or nothing for a program, which
+    // prints to stdout as it runs
+    fn run(
+        self,
+        source: &str,
+        optimize: bool,
+        fuse: bool,
+        vm: &mut Vm,
+    ) -> Result<Option<Value>, lox::Error> {
+        if is_program(source) {
+            let program = parse_program_optimized(source, optimize)?;
+            let mut stdout = std::io::stdout();
+            match self {
+                Backend::Tree => lox::execute(&program, &mut stdout)?,
+                Backend::Vm => {
+                    let script = compiler::compile_program(&program)?;
+                    vm.run_program(Rc::new(script), &mut stdout)?;
+                }
+            }
+            return Ok(None);
+        }
+
+        let value = match self {
+            Backend::Tree => lox::interpret(&parse_optimized(source, optimize)?)?,
+            Backend::Vm => vm.run(&compile_source(source, optimize, fuse)?)?,
+        };
+        Ok(Some(value))
+    }
+}
+
+#[derive(Clone, Copy, Default, clap::ValueEnum)]
+enum Gc {
+    /// Mark and sweep the whole heap at once
+    #[default]
+    StopTheWorld,
+    /// Spread each collection over many allocations to keep pauses short
+    Incremental,
+}
+
+impl From<Gc> for GcMode {
+    fn from(value: Gc) -> Self {
+        match value {
+            Gc::StopTheWorld => GcMode::StopTheWorld,
+            Gc::Incremental => GcMode::Incremental,
+        }
+    }
+}
+
+// Whether `source` is a program rather than a single expression. Source that
+// doesn't lex is neither, and is left to fail as an expression.
+fn is_program(source: &str) -> bool {
+    matches!(lox::parse(source), Err(lox::Error::Parser(_)))
+}
+
+fn parse_program_optimized(source: &str, optimize: bool) -> Result<Vec<Stmt>, lox::Error> {
+    let program = lox::parse_program(source)?;
+    if optimize {
+        Ok(optimizer::optimize_program(program))
+    } else {
+        Ok(program)
+    }
+}
+
+fn parse_optimized(source: &str, optimize: bool) -> Result<Expr, lox::Error> {
+    let expression = lox::parse(source)?;
+    if optimize {
+        Ok(optimizer::optimize(expression))
+    } else {
+        Ok(expression)
+    }
+}
+
+// `optimize` folds constants in the AST and implies `fuse`, which runs the
+// peephole pass over the bytecode of an expression. Programs have jumps,
+// which the peephole pass can't relocate, so they are never fused.
+fn compile_source(source: &str, optimize: bool, fuse: bool) -> Result<Chunk, lox::Error> {
+    let chunk = if optimize {
+        compiler::compile(&parse_optimized(source, optimize)?)?
+    } else {
+        // Nothing rewrites the tree, so it's parsed straight into an arena
+        // for the compiler
+        let mut arena = ExprArena::new();
+        let root = Parser::new(lox::lex(source)?).parse_into(&mut arena)?;
+        compiler::compile_arena(&arena, root)?
+    };
+
+    if optimize || fuse {
+        Ok(peephole::optimize(&chunk))
+    } else {
+        Ok(chunk)
+    }
+}
+
+// The inputs named on the command line, in order, with `--eval` source
+// first. Each is read when it's reached, and one that can't be read is
+// reported and given as `None`.
+fn read_inputs(
+    files: Vec<String>,
+    eval: Option<String>,
+) -> impl Iterator<Item = (String, Option<Vec<u8>>)> {
+    let eval = eval.map(|source| ("<eval>".to_string(), Some(source.into_bytes())));
+    // Read on the first `-` and reused for any later one, which would
+    // otherwise find stdin already at its end
+    let mut stdin = None;
+    eval.into_iter().chain(files.into_iter().map(move |file| {
+        if file == "-" {
+            let input = stdin.get_or_insert_with(|| {
+                let mut input = Vec::new();
+                let read = std::io::stdin().read_to_end(&mut input);
+                if read.is_err() {
+                    println!("Failed to read stdin");
+                }
+                read.ok().map(|_| input)
+            });
+            ("<stdin>".to_string(), input.clone())
+        } else {
+            let input = std::fs::read(&file).ok();
+            if input.is_none() {
+                println!("Failed to read file {}", file);
+            }
+            (file, input)
+        }
+    }))
+}
+
+// Like `read_inputs`, for inputs that must be source text
+fn read_sources(
+    files: Vec<String>,
+    eval: Option<String>,
+) -> impl Iterator<Item = (String, Option<String>)> {
+    read_inputs(files, eval).map(|(name, input)| {
+        let source = input.and_then(|input| {
+            let source = String::from_utf8(input).ok();
+            if source.is_none() {
+                println!("Failed to read {} as UTF-8", name);
+            }
+            source
+        });
+        (name, source)
+    })
 }
 
 fn main() -> Result<(), String> {
     let args = Args::parse();
 
-    if let Commands::Lex { file } = args.cmd {
-        println!("Lexing '{}'", file);
+    if let Commands::Lex { files, eval } = args.cmd {
+        let mut failed = 0;
+        for (name, source) in read_sources(files, eval) {
+            println!("Lexing '{}'", name);
+            let Some(source) = source else {
+                failed += 1;
+                continue;
+            };
+
+            println!("Tokens:");
 
-        let Ok(file_contents) = std::fs::read_to_string(&file) else {
-            println!("Failed to read file {}", file);
-            return Err("Failed to read file".to_string());
+            let mut ok = true;
+            for token in Lexer::new(source).scan_tokens() {
+                match token {
+                    Ok(token) => println!(" {:?}", token),
+                    Err(error) => {
+                        println!("{}: {}", name, error);
+                        ok = false;
+                    }
+                }
+            }
+            failed += usize::from(!ok);
+        }
+
+        if failed > 0 {
+            return Err(format!("{} input(s) had errors", failed));
+        }
+    } else if let Commands::Parse {
+        files,
+        eval,
+        cst,
+        max_depth,
+        style,
+        optimize,
+    } = args.cmd
+    {
+        let mut failed = 0;
+        for (name, source) in read_sources(files, eval) {
+            println!("Parsing '{}'", name);
+            let Some(source) = source else {
+                failed += 1;
+                continue;
+            };
+
+            if cst {
+                println!("CST:");
+                let parse = cst_parser::parse_syntax_with_max_depth(&source, max_depth);
+                print!("{}", parse.syntax().debug_tree());
+                for error in &parse.lexer_errors {
+                    println!("{}: {}", name, error);
+                }
+                for error in &parse.errors {
+                    println!("{}: {}", name, error);
+                }
+                failed += usize::from(!parse.lexer_errors.is_empty() || !parse.errors.is_empty());
+                continue;
+            }
+
+            println!("AST:");
+            let mut tokens = Vec::new();
+            let mut ok = true;
+            for token in Lexer::new(source).scan_tokens() {
+                match token {
+                    Ok(token) => tokens.push(token),
+                    Err(error) => {
+                        println!("{}: {}", name, error);
+                        ok = false;
+                    }
+                }
+            }
+            if !ok {
+                failed += 1;
+                continue;
+            }
+
+            let mut parser = Parser::new(tokens).with_max_depth(max_depth);
+            match parser.parse() {
+                Ok(mut expression) => {
+                    if optimize {
+                        expression = optimizer::optimize(expression);
+                    }
+                    println!("{}", style.render(&expression));
+                }
+                Err(error) => {
+                    println!("{}: {}", name, error);
+                    failed += 1;
+                }
+            }
+        }
+
+        if failed > 0 {
+            return Err(format!("{} input(s) had errors", failed));
+        }
+    } else if let Commands::Eval {
+        expression,
+        ast,
+        style,
+        optimize,
+    } = args.cmd
+    {
+        let source = match expression {
+            Some(expression) if expression != "-" => expression,
+            _ => {
+                let mut source = String::new();
+                if std::io::stdin().read_to_string(&mut source).is_err() {
+                    return Err("Failed to read stdin".to_string());
+                }
+                source
+            }
         };
 
-        println!("Tokens:");
+        let mut expression = lox::parse(&source)?;
+        if optimize {
+            expression = optimizer::optimize(expression);
+        }
+        if ast {
+            println!("{}", style.render(&expression));
+        }
+        println!("{}", lox::interpret(&expression)?);
+    } else if let Commands::Run {
+        files,
+        eval,
+        backend,
+        optimize,
+        peephole,
+        trace,
+        gc_stress,
+        gc,
+        gc_growth_factor,
+        gc_stats,
+    } = args.cmd
+    {
+        let heap = Heap::new()
+            .with_mode(gc.into())
+            .with_growth_factor(gc_growth_factor)
+            .with_stress(gc_stress);
+        let mut vm = Vm::new().with_trace(trace).with_heap(heap);
+        let mut failed = 0;
+        for (name, input) in read_inputs(files, eval) {
+            let Some(input) = input else {
+                failed += 1;
+                continue;
+            };
 
-        let tokens = Lexer::new(file_contents).scan_tokens();
-        for token in tokens {
-            println!(" {:?}", token?);
+            let result = if bytecode::is_bytecode(&input) {
+                bytecode::read(&input)
+                    .map_err(lox::Error::from)
+                    .and_then(|chunk| Ok(Some(vm.run(&chunk)?)))
+            } else if let Ok(source) = std::str::from_utf8(&input) {
+                backend.run(source, optimize, peephole, &mut vm)
+            } else {
+                println!("Failed to read {} as UTF-8", name);
+                failed += 1;
+                continue;
+            };
+            match result {
+                Ok(Some(value)) => println!("{}", value),
+                Ok(None) => {}
+                Err(error) => {
+                    println!("{}: {}", name, error);
+                    failed += 1;
+                }
+            }
         }
-    } else if let Commands::Parse { file } = args.cmd {
-        println!("Parsing '{}'", file);
 
-        let Ok(file_contents) = std::fs::read_to_string(&file) else {
-            println!("Failed to read file {}", file);
-            return Err("Failed to read file".to_string());
+        if gc_stats {
+            eprintln!("GC: {}", vm.heap().stats());
+        }
+        if failed > 0 {
+            return Err(format!("{} input(s) had errors", failed));
+        }
+    } else if let Commands::Compile {
+        file,
+        output,
+        optimize,
+        peephole,
+    } = args.cmd
+    {
+        let output = match output {
+            Some(output) => output,
+            None if file == "-" => {
+                return Err("--output is required when compiling stdin".to_string());
+            }
+            None => std::path::Path::new(&file)
+                .with_extension("loxc")
+                .to_string_lossy()
+                .into_owned(),
+        };
+        let Some((name, Some(source))) = read_sources(vec![file], None).next() else {
+            return Err("Failed to read input".to_string());
         };
+        if is_program(&source) {
+            return Err(format!(
+                "{}: Only expressions can be compiled to a bytecode file",
+                name
+            ));
+        }
+
+        let chunk = compile_source(&source, optimize, peephole)
+            .map_err(|error| format!("{}: {}", name, error))?;
+        if std::fs::write(&output, bytecode::write(&chunk)).is_err() {
+            println!("Failed to write file {}", output);
+            return Err("Failed to write file".to_string());
+        }
+    } else if let Commands::Disasm {
+        files,
+        eval,
+        optimize,
+        peephole,
+    } = args.cmd
+    {
+        let mut failed = 0;
+        for (name, source) in read_sources(files, eval) {
+            let Some(source) = source else {
+                failed += 1;
+                continue;
+            };
+
+            let chunk = if is_program(&source) {
+                parse_program_optimized(&source, optimize)
+                    .and_then(|program| Ok(compiler::compile_program(&program)?.chunk))
+            } else {
+                compile_source(&source, optimize, peephole)
+            };
+            match chunk {
+                Ok(chunk) => print!("{}", disassembler::disassemble(&chunk, &name)),
+                Err(error) => {
+                    println!("{}: {}", name, error);
+                    failed += 1;
+                }
+            }
+        }
+
+        if failed > 0 {
+            return Err(format!("{} input(s) had errors", failed));
+        }
+    } else if let Commands::Fmt {
+        files,
+        check,
+        max_width,
+    } = args.cmd
+    {
+        let mut unformatted = 0;
+        let mut failed = 0;
+        for file in files {
+            let Ok(file_contents) = std::fs::read_to_string(&file) else {
+                println!("Failed to read file {}", file);
+                failed += 1;
+                continue;
+            };
+
+            let formatted = match formatter::format(&file_contents, max_width) {
+                Ok(formatted) => formatted,
+                Err(error) => {
+                    println!("{}: {}", file, error);
+                    failed += 1;
+                    continue;
+                }
+            };
+            if formatted == file_contents {
+                continue;
+            }
+
+            if check {
+                println!("Would reformat '{}'", file);
+                unformatted += 1;
+            } else if std::fs::write(&file, formatted).is_err() {
+                println!("Failed to write file {}", file);
+                failed += 1;
+            }
+        }
 
-        println!("AST:");
-        let tokens = Lexer::new(file_contents).scan_tokens();
-        let errors = tokens.iter().filter_map(|token| token.as_ref().err()).collect::<Vec<_>>();
-        dbg!(&errors);
-        let tokens: Vec<token::Token> = tokens.into_iter().filter_map(|token| token.ok()).collect();
-        dbg!(&tokens);
-        let mut parser = Parser::new(tokens);
-        let expression = parser.parse()?;
-        println!("{}", expression.ast());
-
-    } else if let Commands::PrintAst = args.cmd {
-        let expression = Expr::Binary(BinaryExpr::new(
-            Expr::StringLiteral("one".to_string()),
-            Token::from(TokenKind::Plus),
-            Expr::StringLiteral("two".to_string()),
-        ));
-        println!("{}", expression.ast());
-
-        let expression = Expr::Unary(UnaryExpr::new(
-            Token::from(TokenKind::Minus),
-            Expr::NumberLiteral(1.0),
-        ));
-        println!("{}", expression.ast());
-
-        let expression = Expr::Binary(BinaryExpr::new(
-            Expr::Unary(UnaryExpr::new(
-                Token::from(TokenKind::Minus),
-                Expr::NumberLiteral(123.0),
-            )),
-            Token::from(TokenKind::Star),
-            Expr::Grouping(GroupingExpr::new(
-                Expr::NumberLiteral(45.67),
-            )),
-        ));
-        println!("{}", expression.ast());
+        if failed > 0 {
+            return Err(format!("{} file(s) could not be formatted", failed));
+        }
+        if unformatted > 0 {
+            return Err(format!("{} file(s) need formatting", unformatted));
+        }
     }
 
     Ok(())
@@ -0,0 +1,176 @@
+use std::rc::Rc;
+
+use crate::{
+    expression::{BinaryExpr, CallExpr, Expr, Fold, GroupingExpr, UnaryExpr},
+    statement::{FunctionStmt, IfStmt, PrintStmt, ReturnStmt, Stmt, VarStmt, WhileStmt},
+    token::Token,
+    value::{self, Value},
+};
+
+// Folds every constant subtree down to a literal and drops groupings, which
+// only matter to the parser. An operation that would fail at runtime, like
+// `-"x"`, is kept as it is so the error is still raised when it runs.
+pub fn optimize(expr: Expr) -> Expr {
+    ConstantFolder.fold_expr(expr)
+}
+
+// Optimizes every expression in a program. Statements nest no deeper than
+// the parser allows, so they are folded recursively.
+pub fn optimize_program(program: Vec<Stmt>) -> Vec<Stmt> {
+    program.into_iter().map(optimize_statement).collect()
+}
+
+fn optimize_statement(statement: Stmt) -> Stmt {
+    let boxed = |statement: Box<Stmt>| Box::new(optimize_statement(*statement));
+    match statement {
+        Stmt::Expression(expression) => Stmt::Expression(optimize(expression)),
+        Stmt::Print(print) => Stmt::Print(PrintStmt {
+            expression: optimize(print.expression),
+            ..print
+        }),
+        Stmt::Var(var) => Stmt::Var(VarStmt {
+            initializer: var.initializer.map(optimize),
+            ..var
+        }),
+        Stmt::Block(statements) => Stmt::Block(optimize_program(statements)),
+        Stmt::If(if_stmt) => Stmt::If(IfStmt {
+            condition: optimize(if_stmt.condition),
+            then_branch: boxed(if_stmt.then_branch),
+            else_branch: if_stmt.else_branch.map(boxed),
+        }),
+        Stmt::While(while_stmt) => Stmt::While(WhileStmt {
+            condition: optimize(while_stmt.condition),
+            body: boxed(while_stmt.body),
+        }),
+        Stmt::Function(declaration) => {
+            let declaration = Rc::unwrap_or_clone(declaration);
+            Stmt::Function(Rc::new(FunctionStmt {
+                body: optimize_program(declaration.body),
+                ..declaration
+            }))
+        }
+        Stmt::Return(return_stmt) => Stmt::Return(ReturnStmt {
+            value: return_stmt.value.map(optimize),
+            ..return_stmt
+        }),
+    }
+}
+
+struct ConstantFolder;
+
+impl Fold for ConstantFolder {
+    fn fold_unary(&mut self, unary: UnaryExpr) -> Expr {
+        let right = self.fold_expr(*unary.right);
+        if let Some(value) = Value::from_literal(&right)
+            && let Ok(result) = value::unary(&unary.operator, value)
+        {
+            return result.into_literal();
+        }
+        Expr::Unary(UnaryExpr::new(unary.operator, right))
+    }
+
+    // A chain like `1 + 2 + 3` is unwound into a list first and folded from
+    // the innermost operator out, since chains are not limited by the
+    // parser's nesting depth and recursing down them could overflow the stack
+    fn fold_binary(&mut self, mut binary: BinaryExpr) -> Expr {
+        let mut links = Vec::new();
+        let mut left = loop {
+            links.push((binary.operator, binary.right));
+            let mut left = binary.left;
+            match &mut *left {
+                Expr::Binary(inner) => binary = inner.take(),
+                _ => break self.fold_expr(*left),
+            }
+        };
+
+        for (operator, right) in links.into_iter().rev() {
+            let right = self.fold_expr(*right);
+            left = fold_operation(left, operator, right);
+        }
+        left
+    }
+
+    fn fold_grouping(&mut self, grouping: GroupingExpr) -> Expr {
+        self.fold_expr(*grouping.expression)
+    }
+
+    // Calls are never folded, but their arguments are. A chain of calls like
+    // `f()()()` is unwound the same way as a chain of binary operators.
+    fn fold_call(&mut self, mut call: CallExpr) -> Expr {
+        let mut links = Vec::new();
+        let mut callee = loop {
+            links.push((call.paren, call.arguments));
+            let mut callee = call.callee;
+            match &mut *callee {
+                Expr::Call(inner) => call = inner.take(),
+                _ => break self.fold_expr(*callee),
+            }
+        };
+
+        for (paren, arguments) in links.into_iter().rev() {
+            let arguments = arguments
+                .into_iter()
+                .map(|argument| self.fold_expr(argument))
+                .collect();
+            callee = Expr::Call(CallExpr::new(callee, paren, arguments));
+        }
+        callee
+    }
+}
+
+// Folds `left operator right` to a literal when both operands are constants
+// and the operation succeeds
+fn fold_operation(left: Expr, operator: Token, right: Expr) -> Expr {
+    if let (Some(left), Some(right)) = (Value::from_literal(&left), Value::from_literal(&right))
+        && let Ok(result) = value::binary(left, &operator, right)
+    {
+        return result.into_literal();
+    }
+    Expr::Binary(BinaryExpr::new(left, operator, right))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{expression::Expr, optimizer::optimize, parse, printer::print};
+
+    fn optimized(source: &str) -> String {
+        print(&optimize(parse(source).expect("source parses")))
+    }
+
+    #[test]
+    fn test_folds_constants() {
+        assert_eq!(optimized("1 + 2 * 3"), "7");
+        assert_eq!(optimized("!true"), "false");
+        assert_eq!(optimized("\"a\" + \"b\""), "\"ab\"");
+        assert_eq!(optimized("(1 + 2) * (4 - 1) == 9"), "true");
+        assert_eq!(optimized("!nil == !!0"), "true");
+        assert_eq!(optimized("1 / 0 > 1000"), "true");
+    }
+
+    #[test]
+    fn test_removes_groupings() {
+        assert_eq!(
+            optimize(parse("((nil))").expect("source parses")),
+            Expr::NilLiteral
+        );
+        assert_eq!(optimized("(-\"x\")"), "-\"x\"");
+    }
+
+    #[test]
+    fn test_keeps_runtime_errors() {
+        assert_eq!(optimized("-\"x\""), "-\"x\"");
+        assert_eq!(optimized("(1 + 2) * -\"x\""), "3 * -\"x\"");
+        assert_eq!(optimized("\"a\" + 1 == nil"), "\"a\" + 1 == nil");
+        assert_eq!(optimized("1 < \"2\""), "1 < \"2\"");
+    }
+
+    #[test]
+    fn test_long_chain() {
+        let terms = " - 1".repeat(199_999);
+        assert_eq!(optimized(&format!("1{terms}")), "-199998");
+
+        // `"a" - 1` fails, so nothing in the chain can be folded
+        let source = format!("\"a\"{terms}");
+        assert_eq!(optimized(&source), source);
+    }
+}
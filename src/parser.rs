@@ -1,18 +1,44 @@
-use std::fmt::Display;
+use std::{fmt::Display, rc::Rc};
 
 use crate::{
-    expression::{BinaryExpr, Expr, GroupingExpr, UnaryExpr},
+    arena::{ExprArena, ExprId},
+    expression::{AssignExpr, BinaryExpr, CallExpr, Expr, GroupingExpr, UnaryExpr},
+    statement::{FunctionStmt, IfStmt, PrintStmt, ReturnStmt, Stmt, VarStmt, WhileStmt},
     token::{Token, TokenKind, TokenValue},
 };
 
+pub const DEFAULT_MAX_DEPTH: usize = 256;
+// Arguments and parameters are counted in a byte of bytecode
+pub const MAX_ARGUMENTS: usize = 255;
+
 pub struct Parser {
     tokens: Vec<Token>,
     current: usize,
+    depth: usize,
+    max_depth: usize,
+    // The local variables of each enclosing block or function, innermost
+    // last, and whether each is initialized yet. Globals aren't tracked.
+    scopes: Vec<Vec<(String, bool)>>,
+    // How many function bodies enclose the current statement
+    functions: usize,
 }
 
 impl Parser {
     pub fn new(tokens: Vec<Token>) -> Parser {
-        Parser { tokens, current: 0 }
+        Parser {
+            tokens,
+            current: 0,
+            depth: 0,
+            max_depth: DEFAULT_MAX_DEPTH,
+            scopes: Vec::new(),
+            functions: 0,
+        }
+    }
+
+    // Limits how deeply expressions and statements may nest, so pathological
+    // input is reported as an error rather than overflowing the stack
+    pub fn with_max_depth(self, max_depth: usize) -> Parser {
+        Parser { max_depth, ..self }
     }
 
     fn peek(&self) -> Option<Token> {
@@ -25,17 +51,56 @@ impl Parser {
         })
     }
 
-    fn match_next(&mut self, kinds: &[TokenKind]) -> Option<Token> {
-        if let Some(token) = self.peek()
-            && kinds.contains(&token.kind)
-        {
-            return self.next();
+    fn check(&self, kind: TokenKind) -> bool {
+        self.tokens
+            .get(self.current)
+            .is_some_and(|token| token.kind == kind)
+    }
+
+    // Consumes the next token if it is a `kind`
+    fn next_if(&mut self, kind: TokenKind) -> Option<Token> {
+        if self.check(kind) { self.next() } else { None }
+    }
+
+    fn consume(&mut self, kind: TokenKind) -> Result<Token, ParserError> {
+        match self.peek() {
+            Some(token) if token.kind == kind => {
+                self.current += 1;
+                Ok(token)
+            }
+            token => Err(ParserError::Expected(
+                kind,
+                token.unwrap_or(Token::from(TokenKind::EoF)),
+            )),
+        }
+    }
+
+    // Runs `parse` one level deeper, failing if that is past the limit
+    fn nested<T>(
+        &mut self,
+        parse: impl FnOnce(&mut Parser) -> Result<T, ParserError>,
+    ) -> Result<T, ParserError> {
+        if self.depth == self.max_depth {
+            return Err(ParserError::TooDeeplyNested);
         }
-        None
+
+        self.depth += 1;
+        let result = parse(self);
+        self.depth -= 1;
+        result
     }
 
     pub fn parse(&mut self) -> Result<Expr, ParserError> {
-        match self.expression() {
+        self.parse_with(&mut Boxed)
+    }
+
+    // Parses into `arena`, returning the id of the root node
+    pub fn parse_into(&mut self, arena: &mut ExprArena) -> Result<ExprId, ParserError> {
+        self.parse_with(arena)
+    }
+
+    fn parse_with<B: ExprBuilder>(&mut self, builder: &mut B) -> Result<B::Node, ParserError> {
+        match self.expression(builder).and_then(|expr| self.end(expr)) {
             Ok(expr) => Ok(expr),
             Err(err) => {
                 self.synchronise();
@@ -44,112 +109,404 @@ impl Parser {
         }
     }
 
-    fn expression(&mut self) -> Result<Expr, ParserError> {
-        self.equality()
+    // Parses the whole input as a list of declarations
+    pub fn parse_program(&mut self) -> Result<Vec<Stmt>, ParserError> {
+        let mut statements = Vec::new();
+        while self
+            .peek()
+            .is_some_and(|token| token.kind != TokenKind::EoF)
+        {
+            match self.declaration() {
+                Ok(statement) => statements.push(statement),
+                Err(err) => {
+                    self.synchronise();
+                    return Err(err);
+                }
+            }
+        }
+        Ok(statements)
+    }
+
+    fn declaration(&mut self) -> Result<Stmt, ParserError> {
+        if self.next_if(TokenKind::Fun).is_some() {
+            self.function()
+        } else if self.next_if(TokenKind::Var).is_some() {
+            self.var_declaration()
+        } else {
+            self.statement()
+        }
     }
 
-    fn equality(&mut self) -> Result<Expr, ParserError> {
-        let mut expr = self.comparison()?;
+    fn function(&mut self) -> Result<Stmt, ParserError> {
+        let name = self.consume(TokenKind::Identifier)?;
+        // Initialized straight away, so the function can call itself
+        self.declare(&name)?;
+        self.define();
 
-        while let Some(operator) = self.match_next(&[TokenKind::BangEqual, TokenKind::EqualEqual]) {
-            expr = Expr::Binary(BinaryExpr::new(expr, operator, self.comparison()?));
+        self.consume(TokenKind::LeftParen)?;
+        self.scopes.push(Vec::new());
+        self.functions += 1;
+        let function = self.function_rest(name);
+        self.functions -= 1;
+        self.scopes.pop();
+        Ok(Stmt::Function(Rc::new(function?)))
+    }
+
+    // The parameters and body, in the function's own scope
+    fn function_rest(&mut self, name: Token) -> Result<FunctionStmt, ParserError> {
+        let mut params = Vec::new();
+        if !self.check(TokenKind::RightParen) {
+            loop {
+                if params.len() == MAX_ARGUMENTS {
+                    return Err(ParserError::TooManyParameters);
+                }
+                let param = self.consume(TokenKind::Identifier)?;
+                self.declare(&param)?;
+                self.define();
+                params.push(param);
+                if self.next_if(TokenKind::Comma).is_none() {
+                    break;
+                }
+            }
         }
+        self.consume(TokenKind::RightParen)?;
+        self.consume(TokenKind::LeftBrace)?;
+        let body = self.nested(Parser::block_rest)?;
+        Ok(FunctionStmt { name, params, body })
+    }
 
-        Ok(expr)
+    fn var_declaration(&mut self) -> Result<Stmt, ParserError> {
+        let name = self.consume(TokenKind::Identifier)?;
+        self.declare(&name)?;
+        let initializer = match self.next_if(TokenKind::Equal) {
+            Some(_) => Some(self.expression(&mut Boxed)?),
+            None => None,
+        };
+        self.define();
+        self.consume(TokenKind::Semicolon)?;
+        Ok(Stmt::Var(VarStmt { name, initializer }))
     }
 
-    fn comparison(&mut self) -> Result<Expr, ParserError> {
-        let mut expr = self.term()?;
+    fn statement(&mut self) -> Result<Stmt, ParserError> {
+        let Some(token) = self.peek() else {
+            return Err(ParserError::ExpectedExpression);
+        };
+        // Each kind of statement is parsed by a function of its own, which
+        // keeps this frame small, as nested blocks recurse through it
+        match token.kind {
+            TokenKind::Print => self.print_statement(),
+            TokenKind::LeftBrace => {
+                self.next();
+                Ok(Stmt::Block(self.block()?))
+            }
+            TokenKind::If => self.if_statement(),
+            TokenKind::While => self.while_statement(),
+            TokenKind::For => {
+                self.next();
+                self.scopes.push(Vec::new());
+                let statement = self.nested(Parser::for_rest);
+                self.scopes.pop();
+                statement
+            }
+            TokenKind::Return => self.return_statement(),
+            _ => {
+                let expression = self.expression(&mut Boxed)?;
+                self.consume(TokenKind::Semicolon)?;
+                Ok(Stmt::Expression(expression))
+            }
+        }
+    }
 
-        while let Some(operator) = self.match_next(&[
-            TokenKind::Greater,
-            TokenKind::GreaterEqual,
-            TokenKind::Less,
-            TokenKind::LessEqual,
-        ]) {
-            expr = Expr::Binary(BinaryExpr::new(expr, operator, self.term()?));
+    fn print_statement(&mut self) -> Result<Stmt, ParserError> {
+        let keyword = self.consume(TokenKind::Print)?;
+        let expression = self.expression(&mut Boxed)?;
+        self.consume(TokenKind::Semicolon)?;
+        Ok(Stmt::Print(PrintStmt {
+            keyword,
+            expression,
+        }))
+    }
+
+    fn if_statement(&mut self) -> Result<Stmt, ParserError> {
+        self.consume(TokenKind::If)?;
+        let condition = self.condition()?;
+        let then_branch = Box::new(self.nested(Parser::statement)?);
+        let else_branch = match self.next_if(TokenKind::Else) {
+            Some(_) => Some(Box::new(self.nested(Parser::statement)?)),
+            None => None,
+        };
+        Ok(Stmt::If(IfStmt {
+            condition,
+            then_branch,
+            else_branch,
+        }))
+    }
+
+    fn while_statement(&mut self) -> Result<Stmt, ParserError> {
+        self.consume(TokenKind::While)?;
+        let condition = self.condition()?;
+        let body = Box::new(self.nested(Parser::statement)?);
+        Ok(Stmt::While(WhileStmt { condition, body }))
+    }
+
+    fn return_statement(&mut self) -> Result<Stmt, ParserError> {
+        let keyword = self.consume(TokenKind::Return)?;
+        if self.functions == 0 {
+            return Err(ParserError::ReturnOutsideFunction);
         }
+        let value = match self.check(TokenKind::Semicolon) {
+            true => None,
+            false => Some(self.expression(&mut Boxed)?),
+        };
+        self.consume(TokenKind::Semicolon)?;
+        Ok(Stmt::Return(ReturnStmt { keyword, value }))
+    }
 
-        Ok(expr)
+    fn condition(&mut self) -> Result<Expr, ParserError> {
+        self.consume(TokenKind::LeftParen)?;
+        let condition = self.expression(&mut Boxed)?;
+        self.consume(TokenKind::RightParen)?;
+        Ok(condition)
     }
 
-    fn term(&mut self) -> Result<Expr, ParserError> {
-        let mut expr = self.factor()?;
+    // `for (initializer; condition; increment) body` becomes
+    //
+    //     { initializer; while (condition) { body; increment; } }
+    //
+    // with `true` for a missing condition
+    fn for_rest(&mut self) -> Result<Stmt, ParserError> {
+        self.consume(TokenKind::LeftParen)?;
+        let initializer = if self.next_if(TokenKind::Semicolon).is_some() {
+            None
+        } else if self.next_if(TokenKind::Var).is_some() {
+            Some(self.var_declaration()?)
+        } else {
+            let expression = self.expression(&mut Boxed)?;
+            self.consume(TokenKind::Semicolon)?;
+            Some(Stmt::Expression(expression))
+        };
+        let condition = match self.check(TokenKind::Semicolon) {
+            true => Expr::BooleanLiteral(true),
+            false => self.expression(&mut Boxed)?,
+        };
+        self.consume(TokenKind::Semicolon)?;
+        let increment = match self.check(TokenKind::RightParen) {
+            true => None,
+            false => Some(self.expression(&mut Boxed)?),
+        };
+        self.consume(TokenKind::RightParen)?;
 
-        while let Some(operator) = self.match_next(&[TokenKind::Minus, TokenKind::Plus]) {
-            expr = Expr::Binary(BinaryExpr::new(expr, operator, self.factor()?));
+        let mut body = self.nested(Parser::statement)?;
+        if let Some(increment) = increment {
+            body = Stmt::Block(vec![body, Stmt::Expression(increment)]);
         }
+        let body = Stmt::While(WhileStmt {
+            condition,
+            body: Box::new(body),
+        });
+        Ok(Stmt::Block(initializer.into_iter().chain([body]).collect()))
+    }
 
-        Ok(expr)
+    // The statements of a block, after its opening brace
+    fn block(&mut self) -> Result<Vec<Stmt>, ParserError> {
+        self.scopes.push(Vec::new());
+        let statements = self.nested(Parser::block_rest);
+        self.scopes.pop();
+        statements
     }
 
-    fn factor(&mut self) -> Result<Expr, ParserError> {
-        let mut expr = self.unary()?;
+    // Declarations up to the closing brace, in the current scope
+    fn block_rest(&mut self) -> Result<Vec<Stmt>, ParserError> {
+        let mut statements = Vec::new();
+        while !self.check(TokenKind::RightBrace) && !self.check(TokenKind::EoF) {
+            statements.push(self.declaration()?);
+        }
+        self.consume(TokenKind::RightBrace)?;
+        Ok(statements)
+    }
 
-        while let Some(operator) = self.match_next(&[TokenKind::Slash, TokenKind::Star]) {
-            expr = Expr::Binary(BinaryExpr::new(expr, operator, self.unary()?));
+    // Adds `name` to the innermost scope, if there is one, as not yet
+    // initialized
+    fn declare(&mut self, name: &Token) -> Result<(), ParserError> {
+        let Some(scope) = self.scopes.last_mut() else {
+            return Ok(());
+        };
+        if scope.iter().any(|(declared, _)| declared == name.name()) {
+            return Err(ParserError::AlreadyDeclared(name.clone()));
         }
+        scope.push((name.name().to_string(), false));
+        Ok(())
+    }
 
-        Ok(expr)
+    // Marks the variable declared last as initialized
+    fn define(&mut self) {
+        if let Some((_, initialized)) = self.scopes.last_mut().and_then(|scope| scope.last_mut()) {
+            *initialized = true;
+        }
     }
 
-    fn unary(&mut self) -> Result<Expr, ParserError> {
-        if let Some(operator) = self.match_next(&[TokenKind::Bang, TokenKind::Minus]) {
-            return Ok(Expr::Unary(UnaryExpr::new(operator, self.unary()?)));
+    // Whether the innermost local called `name` is initialized, or `None`
+    // for a global
+    fn lookup(&self, name: &str) -> Option<bool> {
+        self.scopes.iter().rev().find_map(|scope| {
+            scope
+                .iter()
+                .rev()
+                .find(|(declared, _)| declared == name)
+                .map(|(_, initialized)| *initialized)
+        })
+    }
+
+    // The expression has to take up the whole input, up to the `EoF`
+    fn end<N>(&mut self, expr: N) -> Result<N, ParserError> {
+        match self.peek() {
+            Some(token) if token.kind != TokenKind::EoF => Err(ParserError::UnexpectedToken(token)),
+            _ => Ok(expr),
         }
+    }
+
+    fn expression<B: ExprBuilder>(&mut self, builder: &mut B) -> Result<B::Node, ParserError> {
+        self.parse_precedence(builder, Precedence::Assignment)
+    }
+
+    fn parse_precedence<B: ExprBuilder>(
+        &mut self,
+        builder: &mut B,
+        precedence: Precedence,
+    ) -> Result<B::Node, ParserError> {
+        self.nested(|parser| parser.parse_nested(builder, precedence))
+    }
+
+    fn parse_nested<B: ExprBuilder>(
+        &mut self,
+        builder: &mut B,
+        precedence: Precedence,
+    ) -> Result<B::Node, ParserError> {
+        let Some(token) = self.next() else {
+            return Err(ParserError::ExpectedExpression);
+        };
+
+        let Some(prefix) = rule(&token.kind).prefix else {
+            return Err(ParserError::ExpectedPrimaryExpressionGot(token));
+        };
+        let expr = match prefix {
+            Prefix::Grouping => self.parenthesis(builder, token)?,
+            Prefix::Unary => {
+                let expr = self.unary(builder, token)?;
+                return self.infix(builder, expr, precedence);
+            }
+            Prefix::Literal => builder.literal(self.literal(token)?),
+            Prefix::Variable => {
+                // Only a bare name can be assigned to, and an assignment
+                // can't be an operand
+                if precedence == Precedence::Assignment && self.check(TokenKind::Equal) {
+                    self.next();
+                    let value = self.parse_precedence(builder, Precedence::Assignment)?;
+                    return Ok(builder.assign(token, value));
+                }
+                if self.lookup(token.name()) == Some(false) {
+                    return Err(ParserError::VariableInOwnInitializer(token));
+                }
+                builder.variable(token)
+            }
+        };
+
+        let expr = self.calls(builder, expr)?;
+        self.infix(builder, expr, precedence)
+    }
 
-        self.primary()
-    }
-
-    fn primary(&mut self) -> Result<Expr, ParserError> {
-        if let Some(Token { kind, value }) = self.next() {
-            match kind {
-                TokenKind::False => Ok(Expr::BooleanLiteral(false)),
-                TokenKind::True => Ok(Expr::BooleanLiteral(true)),
-                TokenKind::Nil => Ok(Expr::NilLiteral),
-                TokenKind::Number | TokenKind::String => {
-                    if let Some(value) = value {
-                        match value {
-                            TokenValue::String(value) => Ok(Expr::StringLiteral(value)),
-                            TokenValue::Number(value) => Ok(Expr::NumberLiteral(value)),
-                        }
-                    } else {
-                        Err(ParserError::ExpectedPrimaryExpressionGot(Token {
-                            kind,
-                            value,
-                        }))
+    // Any calls following an operand, as in `f(1)(2)`. Like a chain of
+    // binary operators, only the arguments count towards the depth.
+    fn calls<B: ExprBuilder>(
+        &mut self,
+        builder: &mut B,
+        mut callee: B::Node,
+    ) -> Result<B::Node, ParserError> {
+        while self.next_if(TokenKind::LeftParen).is_some() {
+            let mut arguments = Vec::new();
+            if !self.check(TokenKind::RightParen) {
+                loop {
+                    if arguments.len() == MAX_ARGUMENTS {
+                        return Err(ParserError::TooManyArguments);
+                    }
+                    arguments.push(self.expression(builder)?);
+                    if self.next_if(TokenKind::Comma).is_none() {
+                        break;
                     }
                 }
-                TokenKind::LeftParen => self.parenthesis(),
-                _ => Err(ParserError::ExpectedPrimaryExpressionGot(Token {
-                    kind,
-                    value,
-                })),
             }
-        } else {
-            Err(ParserError::ExpectedExpression)
+            let paren = self.consume(TokenKind::RightParen)?;
+            callee = builder.call(callee, paren, arguments);
         }
+        Ok(callee)
     }
 
-    fn parenthesis(&mut self) -> Result<Expr, ParserError> {
-        let expr = self.expression()?;
-
-        if self.next()
-            != Some(Token {
-                kind: TokenKind::RightParen,
-                value: None,
-            })
+    // A chain like `1 + 2 + 3` is built by this loop rather than by recursion,
+    // so only its right operands count towards the depth
+    fn infix<B: ExprBuilder>(
+        &mut self,
+        builder: &mut B,
+        mut expr: B::Node,
+        precedence: Precedence,
+    ) -> Result<B::Node, ParserError> {
+        while let Some(token) = self.peek()
+            && let Some(infix) = rule(&token.kind).infix
+            && infix.precedence >= precedence
         {
+            self.next();
+            let right = self.parse_precedence(builder, infix.right_precedence)?;
+            expr = builder.binary(expr, token, right);
+        }
+
+        Ok(expr)
+    }
+
+    fn unary<B: ExprBuilder>(
+        &mut self,
+        builder: &mut B,
+        operator: Token,
+    ) -> Result<B::Node, ParserError> {
+        let right = self.parse_precedence(builder, Precedence::Unary)?;
+        Ok(builder.unary(operator, right))
+    }
+
+    fn literal(&mut self, token: Token) -> Result<Expr, ParserError> {
+        let Token { kind, value, span } = token;
+        match (kind, value) {
+            (TokenKind::False, _) => Ok(Expr::BooleanLiteral(false)),
+            (TokenKind::True, _) => Ok(Expr::BooleanLiteral(true)),
+            (TokenKind::Nil, _) => Ok(Expr::NilLiteral),
+            (TokenKind::Number | TokenKind::String, Some(TokenValue::String(value))) => {
+                Ok(Expr::StringLiteral(value))
+            }
+            (TokenKind::Number | TokenKind::String, Some(TokenValue::Number(value))) => {
+                Ok(Expr::NumberLiteral(value))
+            }
+            (kind, value) => Err(ParserError::ExpectedPrimaryExpressionGot(Token {
+                kind,
+                value,
+                span,
+            })),
+        }
+    }
+
+    fn parenthesis<B: ExprBuilder>(
+        &mut self,
+        builder: &mut B,
+        _paren: Token,
+    ) -> Result<B::Node, ParserError> {
+        let expr = self.expression(builder)?;
+
+        if self.next() != Some(Token::from(TokenKind::RightParen)) {
             return Err(ParserError::UnclosedParenthesis);
         }
 
-        Ok(Expr::Grouping(GroupingExpr::new(expr)))
+        Ok(builder.grouping(expr))
     }
 
     fn synchronise(&mut self) {
         while let Some(Token {
-            kind: token_kind,
-            value: _,
+            kind: token_kind, ..
         }) = self.peek()
         {
             match token_kind {
@@ -173,11 +530,180 @@ impl Parser {
     }
 }
 
+// Where the parser puts the nodes it builds, so the same parser makes either
+// boxed `Expr` trees or nodes in an `ExprArena`
+pub trait ExprBuilder {
+    type Node;
+
+    // Always one of the literal variants of `Expr`
+    fn literal(&mut self, literal: Expr) -> Self::Node;
+    fn unary(&mut self, operator: Token, right: Self::Node) -> Self::Node;
+    fn binary(&mut self, left: Self::Node, operator: Token, right: Self::Node) -> Self::Node;
+    fn grouping(&mut self, expression: Self::Node) -> Self::Node;
+    fn variable(&mut self, name: Token) -> Self::Node;
+    fn assign(&mut self, name: Token, value: Self::Node) -> Self::Node;
+    fn call(&mut self, callee: Self::Node, paren: Token, arguments: Vec<Self::Node>) -> Self::Node;
+}
+
+struct Boxed;
+
+impl ExprBuilder for Boxed {
+    type Node = Expr;
+
+    fn literal(&mut self, literal: Expr) -> Expr {
+        literal
+    }
+
+    fn unary(&mut self, operator: Token, right: Expr) -> Expr {
+        Expr::Unary(UnaryExpr::new(operator, right))
+    }
+
+    fn binary(&mut self, left: Expr, operator: Token, right: Expr) -> Expr {
+        Expr::Binary(BinaryExpr::new(left, operator, right))
+    }
+
+    fn grouping(&mut self, expression: Expr) -> Expr {
+        Expr::Grouping(GroupingExpr::new(expression))
+    }
+
+    fn variable(&mut self, name: Token) -> Expr {
+        Expr::Variable(name)
+    }
+
+    fn assign(&mut self, name: Token, value: Expr) -> Expr {
+        Expr::Assign(AssignExpr::new(name, value))
+    }
+
+    fn call(&mut self, callee: Expr, paren: Token, arguments: Vec<Expr>) -> Expr {
+        Expr::Call(CallExpr::new(callee, paren, arguments))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Precedence {
+    Assignment,
+    Equality,
+    Comparison,
+    Term,
+    Factor,
+    Unary,
+    Call,
+    Primary,
+}
+
+impl Precedence {
+    fn next(self) -> Precedence {
+        match self {
+            Precedence::Assignment => Precedence::Equality,
+            Precedence::Equality => Precedence::Comparison,
+            Precedence::Comparison => Precedence::Term,
+            Precedence::Term => Precedence::Factor,
+            Precedence::Factor => Precedence::Unary,
+            Precedence::Unary => Precedence::Call,
+            Precedence::Call | Precedence::Primary => Precedence::Primary,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Prefix {
+    Grouping,
+    Unary,
+    Literal,
+    Variable,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Infix {
+    // How tightly the operator binds to its left operand, and the minimum
+    // precedence of its right operand. Left associative operators parse the
+    // right operand one level higher, right associative ones at the same level.
+    pub precedence: Precedence,
+    pub right_precedence: Precedence,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseRule {
+    pub prefix: Option<Prefix>,
+    pub infix: Option<Infix>,
+}
+
+impl ParseRule {
+    const NONE: ParseRule = ParseRule {
+        prefix: None,
+        infix: None,
+    };
+
+    const fn prefix(prefix: Prefix) -> ParseRule {
+        ParseRule {
+            prefix: Some(prefix),
+            infix: None,
+        }
+    }
+
+    fn infix_left(precedence: Precedence) -> ParseRule {
+        ParseRule {
+            prefix: None,
+            infix: Some(Infix {
+                precedence,
+                right_precedence: precedence.next(),
+            }),
+        }
+    }
+
+    fn with_prefix(self, prefix: Prefix) -> ParseRule {
+        ParseRule {
+            prefix: Some(prefix),
+            infix: self.infix,
+        }
+    }
+}
+
+// The operator table: every token that can start or continue an expression
+// has an entry here, so new operators only need a new row.
+pub fn rule(kind: &TokenKind) -> ParseRule {
+    match kind {
+        TokenKind::LeftParen => ParseRule::prefix(Prefix::Grouping),
+
+        TokenKind::BangEqual | TokenKind::EqualEqual => ParseRule::infix_left(Precedence::Equality),
+
+        TokenKind::Greater | TokenKind::GreaterEqual | TokenKind::Less | TokenKind::LessEqual => {
+            ParseRule::infix_left(Precedence::Comparison)
+        }
+
+        TokenKind::Minus => ParseRule::infix_left(Precedence::Term).with_prefix(Prefix::Unary),
+        TokenKind::Plus => ParseRule::infix_left(Precedence::Term),
+
+        TokenKind::Slash | TokenKind::Star => ParseRule::infix_left(Precedence::Factor),
+
+        TokenKind::Bang => ParseRule::prefix(Prefix::Unary),
+
+        TokenKind::False
+        | TokenKind::True
+        | TokenKind::Nil
+        | TokenKind::Number
+        | TokenKind::String => ParseRule::prefix(Prefix::Literal),
+
+        TokenKind::Identifier => ParseRule::prefix(Prefix::Variable),
+
+        _ => ParseRule::NONE,
+    }
+}
+
 #[derive(Debug)]
 pub enum ParserError {
     ExpectedExpression,
     ExpectedPrimaryExpressionGot(Token),
     UnclosedParenthesis,
+    UnexpectedToken(Token),
+    TooDeeplyNested,
+    // What was expected, and the token found instead
+    Expected(TokenKind, Token),
+    TooManyArguments,
+    TooManyParameters,
+    ReturnOutsideFunction,
+    AlreadyDeclared(Token),
+    VariableInOwnInitializer(Token),
 }
 
 impl From<&ParserError> for String {
@@ -188,6 +714,25 @@ impl From<&ParserError> for String {
                 format!("Expected primary expression got {}", token)
             }
             ParserError::UnclosedParenthesis => "Unclosed parenthesis".to_string(),
+            ParserError::UnexpectedToken(token) => format!("Unexpected token {}", token),
+            ParserError::TooDeeplyNested => "Expression is too deeply nested".to_string(),
+            ParserError::Expected(kind, token) => format!("Expected {} got {}", kind, token),
+            ParserError::TooManyArguments => {
+                format!("Can't have more than {} arguments", MAX_ARGUMENTS)
+            }
+            ParserError::TooManyParameters => {
+                format!("Can't have more than {} parameters", MAX_ARGUMENTS)
+            }
+            ParserError::ReturnOutsideFunction => "Can't return from top-level code".to_string(),
+            ParserError::AlreadyDeclared(name) => {
+                format!("Already a variable called '{}' in this scope", name.name())
+            }
+            ParserError::VariableInOwnInitializer(name) => {
+                format!(
+                    "Can't read local variable '{}' in its own initializer",
+                    name.name()
+                )
+            }
         }
     }
 }
@@ -204,14 +749,67 @@ impl Display for ParserError {
     }
 }
 
+impl std::error::Error for ParserError {}
+
 #[cfg(test)]
 mod tests {
+    use std::{fs, path::Path};
+
     use crate::{
+        ast_display::AstDisplay,
         expression::{BinaryExpr, Expr, GroupingExpr, UnaryExpr},
+        parse, parse_program,
         parser::{Parser, ParserError},
+        statement::Stmt,
         token::{Token, TokenKind},
     };
 
+    // Runs every `<source> => <expected ast>` case in tests/parser/*.txt
+    #[test]
+    fn test_fixtures() {
+        let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/parser");
+        let mut paths = fs::read_dir(&directory)
+            .expect("fixture directory exists")
+            .map(|entry| entry.expect("fixture directory is readable").path())
+            .filter(|path| path.extension().is_some_and(|extension| extension == "txt"))
+            .collect::<Vec<_>>();
+        paths.sort();
+
+        let mut cases = 0;
+        let mut failures = Vec::new();
+        for path in paths {
+            let contents = fs::read_to_string(&path).expect("fixture is readable");
+            for (number, line) in contents.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+
+                let Some((source, expected)) = line.split_once("=>") else {
+                    panic!("{}:{}: missing '=>'", path.display(), number + 1);
+                };
+                let actual = match parse(source.trim()) {
+                    Ok(expression) => expression.ast(),
+                    Err(error) => format!("error: {}", error),
+                };
+                if actual != expected.trim() {
+                    failures.push(format!(
+                        "{}:{}: '{}'\n  expected: {}\n  actual:   {}",
+                        path.display(),
+                        number + 1,
+                        source.trim(),
+                        expected.trim(),
+                        actual
+                    ));
+                }
+                cases += 1;
+            }
+        }
+
+        assert!(cases > 0, "no fixtures found in {}", directory.display());
+        assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+    }
+
     #[test]
     fn test_equality() -> Result<(), ParserError> {
         let tokens: Vec<Token> = vec![
@@ -350,4 +948,219 @@ mod tests {
         );
         Ok(())
     }
+
+    fn left_associative(operators: [TokenKind; 2]) -> Result<(), ParserError> {
+        // 1 a 2 b 3 parses as ((1 a 2) b 3)
+        let [first, second] = operators;
+        let tokens: Vec<Token> = vec![
+            Token::from((TokenKind::Number, 1.0)),
+            Token::from(first.clone()),
+            Token::from((TokenKind::Number, 2.0)),
+            Token::from(second.clone()),
+            Token::from((TokenKind::Number, 3.0)),
+        ];
+        let mut parser = Parser::new(tokens);
+        let expression = parser.parse();
+        assert_eq!(
+            expression?,
+            Expr::Binary(BinaryExpr::new(
+                Expr::Binary(BinaryExpr::new(
+                    Expr::NumberLiteral(1.0),
+                    Token::from(first),
+                    Expr::NumberLiteral(2.0)
+                )),
+                Token::from(second),
+                Expr::NumberLiteral(3.0)
+            ))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_equality_associativity() -> Result<(), ParserError> {
+        left_associative([TokenKind::EqualEqual, TokenKind::BangEqual])?;
+        left_associative([TokenKind::BangEqual, TokenKind::EqualEqual])
+    }
+
+    #[test]
+    fn test_comparison_associativity() -> Result<(), ParserError> {
+        left_associative([TokenKind::Less, TokenKind::Greater])?;
+        left_associative([TokenKind::GreaterEqual, TokenKind::LessEqual])
+    }
+
+    #[test]
+    fn test_term_associativity() -> Result<(), ParserError> {
+        left_associative([TokenKind::Minus, TokenKind::Plus])?;
+        left_associative([TokenKind::Plus, TokenKind::Minus])
+    }
+
+    #[test]
+    fn test_factor_associativity() -> Result<(), ParserError> {
+        left_associative([TokenKind::Slash, TokenKind::Star])?;
+        left_associative([TokenKind::Star, TokenKind::Slash])
+    }
+
+    #[test]
+    fn test_unary_associativity() -> Result<(), ParserError> {
+        // - ! 1 parses as (- (! 1))
+        let tokens: Vec<Token> = vec![
+            Token::from(TokenKind::Minus),
+            Token::from(TokenKind::Bang),
+            Token::from((TokenKind::Number, 1.0)),
+        ];
+        let mut parser = Parser::new(tokens);
+        let expression = parser.parse();
+        assert_eq!(
+            expression?,
+            Expr::Unary(UnaryExpr::new(
+                Token::from(TokenKind::Minus),
+                Expr::Unary(UnaryExpr::new(
+                    Token::from(TokenKind::Bang),
+                    Expr::NumberLiteral(1.0)
+                ))
+            ))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_unary_binds_tighter_than_factor() -> Result<(), ParserError> {
+        // -1 * 2 parses as ((- 1) * 2)
+        let tokens: Vec<Token> = vec![
+            Token::from(TokenKind::Minus),
+            Token::from((TokenKind::Number, 1.0)),
+            Token::from(TokenKind::Star),
+            Token::from((TokenKind::Number, 2.0)),
+        ];
+        let mut parser = Parser::new(tokens);
+        let expression = parser.parse();
+        assert_eq!(
+            expression?,
+            Expr::Binary(BinaryExpr::new(
+                Expr::Unary(UnaryExpr::new(
+                    Token::from(TokenKind::Minus),
+                    Expr::NumberLiteral(1.0)
+                )),
+                Token::from(TokenKind::Star),
+                Expr::NumberLiteral(2.0)
+            ))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_deep_nesting_is_an_error() {
+        for kind in [TokenKind::LeftParen, TokenKind::Minus, TokenKind::Bang] {
+            let mut tokens = vec![Token::from(kind); 100_000];
+            tokens.push(Token::from((TokenKind::Number, 1.0)));
+            let mut parser = Parser::new(tokens);
+            assert!(matches!(parser.parse(), Err(ParserError::TooDeeplyNested)));
+        }
+    }
+
+    #[test]
+    fn test_long_chain_is_accepted() -> Result<(), ParserError> {
+        for kind in [TokenKind::Plus, TokenKind::Star, TokenKind::EqualEqual] {
+            let mut tokens = vec![Token::from((TokenKind::Number, 1.0))];
+            for _ in 0..200_000 {
+                tokens.push(Token::from(kind.clone()));
+                tokens.push(Token::from((TokenKind::Number, 1.0)));
+            }
+            tokens.push(Token::from(TokenKind::EoF));
+            Parser::new(tokens).parse()?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_depth() -> Result<(), ParserError> {
+        // - - 1 needs three levels, one per operator and one for the literal
+        let tokens: Vec<Token> = vec![
+            Token::from(TokenKind::Minus),
+            Token::from(TokenKind::Minus),
+            Token::from((TokenKind::Number, 1.0)),
+        ];
+        let mut parser = Parser::new(tokens.clone()).with_max_depth(2);
+        assert!(matches!(parser.parse(), Err(ParserError::TooDeeplyNested)));
+
+        let mut parser = Parser::new(tokens).with_max_depth(3);
+        parser.parse()?;
+
+        // 1 + 2 + 3 needs two, one for the expression and one for the right
+        // operands, however long the chain is
+        let tokens: Vec<Token> = vec![
+            Token::from((TokenKind::Number, 1.0)),
+            Token::from(TokenKind::Plus),
+            Token::from((TokenKind::Number, 2.0)),
+            Token::from(TokenKind::Plus),
+            Token::from((TokenKind::Number, 3.0)),
+        ];
+        let mut parser = Parser::new(tokens.clone()).with_max_depth(1);
+        assert!(matches!(parser.parse(), Err(ParserError::TooDeeplyNested)));
+
+        let mut parser = Parser::new(tokens).with_max_depth(2);
+        parser.parse()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_for_is_desugared() {
+        let program =
+            parse_program("for (var i = 0; i < 2; i = i + 1) print i;").expect("source parses");
+        let [Stmt::Block(statements)] = program.as_slice() else {
+            panic!("expected a block, got {:?}", program);
+        };
+        let [Stmt::Var(_), Stmt::While(while_stmt)] = statements.as_slice() else {
+            panic!("expected a variable and a loop, got {:?}", statements);
+        };
+        assert!(matches!(
+            &*while_stmt.body,
+            Stmt::Block(body) if matches!(body.as_slice(), [Stmt::Print(_), Stmt::Expression(_)])
+        ));
+    }
+
+    #[test]
+    fn test_program_errors() {
+        let parameters = (0..256)
+            .map(|index| format!("p{}", index))
+            .collect::<Vec<_>>()
+            .join(", ");
+        for (source, expected) in [
+            ("print 1", "Expected ; got EOF"),
+            (
+                "{ var a; var a; }",
+                "Already a variable called 'a' in this scope",
+            ),
+            (
+                "fun f(a, a) {}",
+                "Already a variable called 'a' in this scope",
+            ),
+            (
+                "{ var a = a; }",
+                "Can't read local variable 'a' in its own initializer",
+            ),
+            ("return 1;", "Can't return from top-level code"),
+            (
+                &format!("fun f({}) {{}}", parameters),
+                "Can't have more than 255 parameters",
+            ),
+        ] {
+            let error = parse_program(source).expect_err("source is invalid");
+            assert_eq!(error.to_string(), expected, "{}", source);
+        }
+
+        // Globals may be redeclared, and read in their own initializer
+        for source in ["var a; var a;", "var a = a;", "{ var a; { var a = 1; } }"] {
+            parse_program(source).expect("source parses");
+        }
+    }
+
+    #[test]
+    fn test_deeply_nested_statements_are_an_error() {
+        let source = format!("{}{}", "{".repeat(100_000), "}".repeat(100_000));
+        assert!(matches!(
+            parse_program(&source),
+            Err(crate::Error::Parser(ParserError::TooDeeplyNested))
+        ));
+    }
 }
@@ -0,0 +1,161 @@
+use crate::chunk::{Chunk, OpCode};
+
+// Rewrites a compiled chunk into one that does the same in fewer
+// instructions, by fusing common pairs into superinstructions
+//
+//     Equal Not         => NotEqual
+//     Constant Return   => ReturnConstant
+//
+// and dropping anything after a `Return`, which can never run. There are no
+// jumps yet, so instructions can move without anything needing relocation,
+// and there is no compare and jump fusion or jump threading to do.
+//
+// `-O` runs this after folding constants in the AST, which leaves few of
+// these pairs behind, so `--peephole` runs it on its own.
+pub fn optimize(chunk: &Chunk) -> Chunk {
+    let mut optimized = Chunk {
+        constants: chunk.constants.clone(),
+        ..Chunk::default()
+    };
+
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let op = OpCode::try_from(chunk.code[offset]).expect("valid opcode");
+        let next = offset + 1 + op.operand_len();
+        let operands = &chunk.code[offset + 1..next];
+        let following = chunk
+            .code
+            .get(next)
+            .and_then(|byte| OpCode::try_from(*byte).ok());
+
+        // A fused instruction takes the position of the first of the pair
+        let span = chunk.span(offset);
+        let fused = match (op, following) {
+            (OpCode::Equal, Some(OpCode::Not)) => Some(OpCode::NotEqual),
+            (OpCode::Constant, Some(OpCode::Return)) => Some(OpCode::ReturnConstant),
+            _ => None,
+        };
+        let op = fused.unwrap_or(op);
+        optimized.write_op(op, span);
+        for byte in operands {
+            optimized.write_byte(*byte, span);
+        }
+
+        if matches!(op, OpCode::Return | OpCode::ReturnConstant) {
+            break;
+        }
+        offset = if fused.is_some() { next + 1 } else { next };
+    }
+    optimized
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        chunk::{
+            Chunk,
+            OpCode::{self, *},
+        },
+        compiler::compile,
+        peephole::optimize,
+        printer::print,
+        testing::Rng,
+        token::Span,
+        value::Value,
+        vm::Vm,
+    };
+
+    fn compiled(source: &str) -> Chunk {
+        compile(&crate::parse(source).expect("source parses")).expect("expression compiles")
+    }
+
+    fn bytes(ops: &[OpCode]) -> Vec<u8> {
+        ops.iter().map(|op| *op as u8).collect()
+    }
+
+    #[test]
+    fn test_fuses_superinstructions() {
+        assert_eq!(
+            optimize(&compiled("true != !nil")).code,
+            bytes(&[True, Nil, Not, NotEqual, Return])
+        );
+        assert_eq!(
+            optimize(&compiled("(\"a\")")).code,
+            [ReturnConstant as u8, 0]
+        );
+        // Both operands of `!=` are constants, but only the last is returned
+        assert_eq!(
+            optimize(&compiled("1 != 2")).code,
+            [
+                Constant as u8,
+                0,
+                Constant as u8,
+                1,
+                NotEqual as u8,
+                Return as u8
+            ]
+        );
+    }
+
+    #[test]
+    fn test_drops_code_after_return() {
+        let span = Span::new(1, 1);
+        let mut chunk = Chunk::new();
+        chunk.write_op(True, span);
+        chunk.write_op(Return, span);
+        chunk.write_constant(Value::Nil, span);
+        chunk.write_op(Return, span);
+        assert_eq!(optimize(&chunk).code, bytes(&[True, Return]));
+    }
+
+    #[test]
+    fn test_keeps_error_positions() {
+        let chunk = optimize(&compiled("nil !=\n  -\"x\""));
+        let error = Vm::new().run(&chunk).expect_err("negating a string fails");
+        assert_eq!(error.to_string(), "Line 2:3: Operand of - must be a number");
+        assert_eq!(chunk.span(chunk.code.len() - 2), Span::new(1, 5));
+    }
+
+    fn random_source(rng: &mut Rng, depth: usize) -> String {
+        const LEAVES: [&str; 6] = ["1", "2.5", "\"a\"", "\"b\"", "nil", "true"];
+        const BINARY: [&str; 10] = ["==", "!=", "<", "<=", ">", ">=", "+", "-", "*", "/"];
+        match rng.below(if depth == 0 { 1 } else { 4 }) {
+            0 => LEAVES[rng.below(LEAVES.len())].to_string(),
+            1 => format!(
+                "{}{}",
+                ["!", "-"][rng.below(2)],
+                random_source(rng, depth - 1)
+            ),
+            _ => format!(
+                "({} {} {})",
+                random_source(rng, depth - 1),
+                BINARY[rng.below(BINARY.len())],
+                random_source(rng, depth - 1)
+            ),
+        }
+    }
+
+    // Optimized and unoptimized chunks give the same value or error, on
+    // random programs as well as the fixtures in tests/run
+    #[test]
+    fn test_matches_unoptimized() {
+        let mut rng = Rng(0x5eed);
+        for _ in 0..2000 {
+            let source = random_source(&mut rng, 5);
+            let chunk = compiled(&source);
+            let optimized = optimize(&chunk);
+            assert!(optimized.code.len() <= chunk.code.len());
+
+            let show = |result: Result<Value, _>| match result {
+                Ok(value) => print(&value.into_literal()),
+                Err(error) => format!("error: {}", error),
+            };
+            assert_eq!(
+                show(Vm::new().run(&optimized)),
+                show(Vm::new().run(&chunk)),
+                "{}",
+                source
+            );
+        }
+    }
+}
@@ -0,0 +1,335 @@
+use crate::{
+    expression::{BinaryExpr, Expr, UnaryExpr},
+    parser::{Precedence, rule},
+    token::{Token, TokenKind},
+};
+
+// Prints `expr` as Lox source. Groupings are always printed as parentheses,
+// and parentheses are added wherever the tree structure would otherwise be
+// lost to precedence or associativity, so parsing the output gives back the
+// same tree.
+pub fn print(expr: &Expr) -> String {
+    let mut output = String::new();
+    print_expr(expr, Precedence::Assignment, &mut output);
+    output
+}
+
+fn precedence(expr: &Expr) -> Precedence {
+    match expr {
+        Expr::Binary(binary) => rule(&binary.operator.kind)
+            .infix
+            .map_or(Precedence::Primary, |infix| infix.precedence),
+        Expr::Unary(_) => Precedence::Unary,
+        Expr::Assign(_) => Precedence::Assignment,
+        Expr::Call(_) => Precedence::Call,
+        Expr::BooleanLiteral(_)
+        | Expr::NumberLiteral(_)
+        | Expr::StringLiteral(_)
+        | Expr::NilLiteral
+        | Expr::Grouping(_)
+        | Expr::Variable(_) => Precedence::Primary,
+    }
+}
+
+// Lox only has literals for finite, non-negative numbers. Others, which
+// constant folding can produce, are printed as an expression that evaluates
+// to the same number: `-1`, `0 / 0` for NaN and `1 / 0` for infinity.
+fn spelled_number(value: f64) -> Option<Expr> {
+    let number = |value| Expr::NumberLiteral(value);
+    if value.is_nan() {
+        Some(Expr::Binary(BinaryExpr::new(
+            number(0.0),
+            Token::from(TokenKind::Slash),
+            number(0.0),
+        )))
+    } else if value.is_sign_negative() {
+        Some(Expr::Unary(UnaryExpr::new(
+            Token::from(TokenKind::Minus),
+            number(-value),
+        )))
+    } else if value.is_infinite() {
+        Some(Expr::Binary(BinaryExpr::new(
+            number(1.0),
+            Token::from(TokenKind::Slash),
+            number(0.0),
+        )))
+    } else {
+        None
+    }
+}
+
+// Prints `expr` where the grammar expects an operand of at least `minimum`
+// precedence. Uses an explicit stack, since a chain of binary operators can
+// be far deeper than the parser's nesting limit.
+fn print_expr(expr: &Expr, minimum: Precedence, output: &mut String) {
+    enum Step<'a> {
+        Print(&'a Expr, Precedence),
+        Operator(&'a Token),
+        Text(&'static str),
+        Close,
+    }
+
+    let mut steps = vec![Step::Print(expr, minimum)];
+    while let Some(step) = steps.pop() {
+        let (expr, minimum) = match step {
+            Step::Print(expr, minimum) => (expr, minimum),
+            Step::Operator(operator) => {
+                output.push_str(&format!(" {} ", operator.kind));
+                continue;
+            }
+            Step::Text(text) => {
+                output.push_str(text);
+                continue;
+            }
+            Step::Close => {
+                output.push(')');
+                continue;
+            }
+        };
+
+        if let Expr::NumberLiteral(value) = expr
+            && let Some(spelled) = spelled_number(*value)
+        {
+            // Only ever a couple of levels deep
+            print_expr(&spelled, minimum, output);
+            continue;
+        }
+        if precedence(expr) < minimum {
+            output.push('(');
+            steps.push(Step::Close);
+            steps.push(Step::Print(expr, Precedence::Assignment));
+            continue;
+        }
+
+        match expr {
+            Expr::BooleanLiteral(value) => output.push_str(&value.to_string()),
+            Expr::NumberLiteral(value) => output.push_str(&value.to_string()),
+            Expr::StringLiteral(value) => output.push_str(&format!("\"{}\"", value)),
+            Expr::NilLiteral => output.push_str("nil"),
+            Expr::Unary(unary) => {
+                output.push_str(&unary.operator.kind.to_string());
+                steps.push(Step::Print(&unary.right, Precedence::Unary));
+            }
+            Expr::Binary(binary) => {
+                let (left, right) = rule(&binary.operator.kind)
+                    .infix
+                    .map_or((Precedence::Primary, Precedence::Primary), |infix| {
+                        (infix.precedence, infix.right_precedence)
+                    });
+                steps.push(Step::Print(&binary.right, right));
+                steps.push(Step::Operator(&binary.operator));
+                steps.push(Step::Print(&binary.left, left));
+            }
+            Expr::Grouping(grouping) => {
+                output.push('(');
+                steps.push(Step::Close);
+                steps.push(Step::Print(&grouping.expression, Precedence::Assignment));
+            }
+            Expr::Variable(name) => output.push_str(name.name()),
+            Expr::Assign(assign) => {
+                output.push_str(&format!("{} = ", assign.name.name()));
+                steps.push(Step::Print(&assign.value, Precedence::Assignment));
+            }
+            Expr::Call(call) => {
+                steps.push(Step::Close);
+                for (index, argument) in call.arguments.iter().enumerate().rev() {
+                    steps.push(Step::Print(argument, Precedence::Assignment));
+                    if index > 0 {
+                        steps.push(Step::Text(", "));
+                    }
+                }
+                steps.push(Step::Text("("));
+                steps.push(Step::Print(&call.callee, Precedence::Call));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        Error,
+        expression::{BinaryExpr, Expr, Fold, GroupingExpr, UnaryExpr},
+        optimizer::optimize,
+        parse,
+        printer::{print, spelled_number},
+        testing::Rng,
+        token::{Token, TokenKind},
+    };
+
+    struct Ungroup;
+
+    impl Fold for Ungroup {
+        fn fold_grouping(&mut self, grouping: GroupingExpr) -> Expr {
+            self.fold_expr(*grouping.expression)
+        }
+    }
+
+    const BINARY: [TokenKind; 10] = [
+        TokenKind::EqualEqual,
+        TokenKind::BangEqual,
+        TokenKind::Less,
+        TokenKind::LessEqual,
+        TokenKind::Greater,
+        TokenKind::GreaterEqual,
+        TokenKind::Plus,
+        TokenKind::Minus,
+        TokenKind::Star,
+        TokenKind::Slash,
+    ];
+
+    // Including the ones folding makes that no literal can spell
+    const NUMBERS: [f64; 9] = [
+        0.0,
+        1.0,
+        2.5,
+        10.0,
+        -1.0,
+        -0.0,
+        f64::NAN,
+        f64::INFINITY,
+        f64::NEG_INFINITY,
+    ];
+
+    // The tree printing a literal actually gives
+    struct Spell;
+
+    impl Fold for Spell {
+        fn fold_literal(&mut self, literal: Expr) -> Expr {
+            match literal {
+                Expr::NumberLiteral(value) => match spelled_number(value) {
+                    Some(spelled) => self.fold_expr(spelled),
+                    None => literal,
+                },
+                literal => literal,
+            }
+        }
+    }
+
+    // Arbitrary trees, including shapes the parser only produces with groupings
+    fn random_expr(rng: &mut Rng, depth: usize) -> Expr {
+        let choice = if depth == 0 {
+            rng.below(4)
+        } else {
+            rng.below(7)
+        };
+        match choice {
+            0 => Expr::NumberLiteral(NUMBERS[rng.below(NUMBERS.len())]),
+            1 => Expr::StringLiteral(["", "a", "two words"][rng.below(3)].to_string()),
+            2 => Expr::BooleanLiteral(rng.below(2) == 0),
+            3 => Expr::NilLiteral,
+            4 => Expr::Unary(UnaryExpr::new(
+                Token::from([TokenKind::Minus, TokenKind::Bang][rng.below(2)].clone()),
+                random_expr(rng, depth - 1),
+            )),
+            5 => Expr::Grouping(GroupingExpr::new(random_expr(rng, depth - 1))),
+            _ => Expr::Binary(BinaryExpr::new(
+                random_expr(rng, depth - 1),
+                Token::from(BINARY[rng.below(BINARY.len())].clone()),
+                random_expr(rng, depth - 1),
+            )),
+        }
+    }
+
+    #[test]
+    fn test_minimal_parentheses() -> Result<(), Error> {
+        for (source, expected) in [
+            ("1 + 2 * 3", "1 + 2 * 3"),
+            ("(1 + 2) * 3", "(1 + 2) * 3"),
+            ("1 - (2 - 3)", "1 - (2 - 3)"),
+            ("-  -1", "--1"),
+            ("!(1 == 2)", "!(1 == 2)"),
+            ("\"a\"+nil", "\"a\" + nil"),
+        ] {
+            assert_eq!(print(&parse(source)?), expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_numbers_without_literals() {
+        for (value, expected) in [
+            (-1.5, "-1.5"),
+            (-0.0, "-0"),
+            (f64::NAN, "0 / 0"),
+            (f64::INFINITY, "1 / 0"),
+            (f64::NEG_INFINITY, "-(1 / 0)"),
+        ] {
+            assert_eq!(print(&Expr::NumberLiteral(value)), expected);
+        }
+
+        // They take the precedence of what they're printed as
+        let expr = Expr::Binary(BinaryExpr::new(
+            Expr::NumberLiteral(-2.0),
+            Token::from(TokenKind::Star),
+            Expr::NumberLiteral(f64::NAN),
+        ));
+        assert_eq!(print(&expr), "-2 * (0 / 0)");
+    }
+
+    #[test]
+    fn test_folded_trees_print_as_source() -> Result<(), Error> {
+        for source in ["-(1 + 2)", "0 / 0", "-1 / 0", "2 * (0 - 0.5)"] {
+            let folded = optimize(parse(source)?);
+            let printed = print(&folded);
+            assert_eq!(print(&optimize(parse(&printed)?)), printed, "{}", source);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_adds_parentheses_the_tree_needs() {
+        // 1 - (2 - 3) without the grouping node
+        let expr = Expr::Binary(BinaryExpr::new(
+            Expr::NumberLiteral(1.0),
+            Token::from(TokenKind::Minus),
+            Expr::Binary(BinaryExpr::new(
+                Expr::NumberLiteral(2.0),
+                Token::from(TokenKind::Minus),
+                Expr::NumberLiteral(3.0),
+            )),
+        ));
+        assert_eq!(print(&expr), "1 - (2 - 3)");
+
+        // -(1 * 2)
+        let expr = Expr::Unary(UnaryExpr::new(
+            Token::from(TokenKind::Minus),
+            Expr::Binary(BinaryExpr::new(
+                Expr::NumberLiteral(1.0),
+                Token::from(TokenKind::Star),
+                Expr::NumberLiteral(2.0),
+            )),
+        ));
+        assert_eq!(print(&expr), "-(1 * 2)");
+    }
+
+    #[test]
+    fn test_round_trip_random_trees() -> Result<(), Error> {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..2_000 {
+            let expr = random_expr(&mut rng, 6);
+            let source = print(&expr);
+
+            // Parsing the output recovers the operator structure, with
+            // groupings added only where the printer needed parentheses
+            let parsed = parse(&source)?;
+            assert_eq!(
+                Ungroup.fold_expr(parse(&source)?),
+                Ungroup.fold_expr(Spell.fold_expr(expr)),
+                "{}",
+                source
+            );
+
+            // Trees that already have those groupings round trip exactly
+            assert_eq!(parse(&print(&parsed))?, parsed);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_long_chain() -> Result<(), Error> {
+        let source = format!("1{}", " - 1".repeat(199_999));
+        assert_eq!(print(&parse(&source)?), source);
+        Ok(())
+    }
+}
@@ -0,0 +1,290 @@
+use std::fmt::Display;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de::Error};
+
+use crate::{
+    arena::{ArenaExpr, ExprArena, ExprId},
+    expression::Expr,
+    parser::DEFAULT_MAX_DEPTH,
+};
+
+// JSON is meant for tools outside Rust, the binary encoding for caching
+// parsed programs. Both describe the same tree, so either can be loaded back
+// without reparsing.
+//
+// A tree is written as the flat list of nodes an `ExprArena` would hold for
+// it, children before parents and the root last. Nothing in the encoding
+// nests however deep the tree is, so neither format's recursion limits come
+// into play, and a long chain of operators loads as easily as it parses.
+// Loaded trees are instead held to the parser's own nesting limit.
+
+#[derive(Debug)]
+pub enum SerializeError {
+    Json(serde_json::Error),
+    Binary(bincode::Error),
+}
+
+impl Display for SerializeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SerializeError::Json(error) => write!(f, "Invalid JSON AST: {}", error),
+            SerializeError::Binary(error) => write!(f, "Invalid binary AST: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for SerializeError {}
+
+pub fn to_json(expr: &Expr) -> String {
+    serde_json::to_string_pretty(expr).expect("an AST always serializes")
+}
+
+pub fn from_json(json: &str) -> Result<Expr, SerializeError> {
+    serde_json::from_str(json).map_err(SerializeError::Json)
+}
+
+pub fn to_bytes(expr: &Expr) -> Vec<u8> {
+    bincode::serialize(expr).expect("an AST always serializes")
+}
+
+pub fn from_bytes(bytes: &[u8]) -> Result<Expr, SerializeError> {
+    bincode::deserialize(bytes).map_err(SerializeError::Binary)
+}
+
+impl Serialize for Expr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut arena = ExprArena::new();
+        arena.lower(self);
+        arena.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Expr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Expr, D::Error> {
+        let arena = ExprArena::deserialize(deserializer)?;
+        let root = check_tree(&arena).map_err(D::Error::custom)?;
+        Ok(arena.to_expr(root))
+    }
+}
+
+// Makes sure the nodes form a single tree the parser could have produced,
+// and returns its root. Every node but the root must be the operand of
+// exactly one later node.
+fn check_tree(arena: &ExprArena) -> Result<ExprId, String> {
+    let (root, _) = arena.iter().next_back().ok_or("the tree has no nodes")?;
+
+    // Measured as the parser does, so a left operand or a callee is as deep
+    // as its parent. Filled in from the root down, 0 until a node is reached.
+    let mut depths = vec![0; arena.len()];
+    depths[root.index()] = 1;
+    for (id, node) in arena.iter().rev() {
+        let depth = depths[id.index()];
+        if depth == 0 {
+            return Err(format!("node {} is not part of the tree", id.index()));
+        }
+        if depth > DEFAULT_MAX_DEPTH {
+            return Err(format!(
+                "the tree is nested more than {} levels deep",
+                DEFAULT_MAX_DEPTH
+            ));
+        }
+
+        let left = match node {
+            ArenaExpr::Binary { left, .. } => Some(*left),
+            ArenaExpr::Call { callee, .. } => Some(*callee),
+            _ => None,
+        };
+        for child in node.children() {
+            if child >= id {
+                return Err(format!(
+                    "node {} refers to node {}, which does not come before it",
+                    id.index(),
+                    child.index()
+                ));
+            }
+            if depths[child.index()] != 0 {
+                return Err(format!("node {} is used more than once", child.index()));
+            }
+            depths[child.index()] = if Some(child) == left {
+                depth
+            } else {
+                depth + 1
+            };
+        }
+    }
+    Ok(root)
+}
+
+// JSON has no way to write NaN or the infinities, so in human readable
+// formats they are written as the strings "NaN", "inf" and "-inf" instead.
+// Used for every number in the tree, literals and token values alike.
+pub(crate) mod number {
+    use serde::{Deserialize, Deserializer, Serializer, de::Error, de::Unexpected};
+
+    pub fn serialize<S: Serializer>(value: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+        if value.is_finite() || !serializer.is_human_readable() {
+            serializer.serialize_f64(*value)
+        } else if value.is_nan() {
+            serializer.serialize_str("NaN")
+        } else if *value > 0.0 {
+            serializer.serialize_str("inf")
+        } else {
+            serializer.serialize_str("-inf")
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Number {
+            Finite(f64),
+            NonFinite(String),
+        }
+
+        if !deserializer.is_human_readable() {
+            return f64::deserialize(deserializer);
+        }
+        match Number::deserialize(deserializer)? {
+            Number::Finite(value) => Ok(value),
+            Number::NonFinite(text) => match text.as_str() {
+                "NaN" => Ok(f64::NAN),
+                "inf" => Ok(f64::INFINITY),
+                "-inf" => Ok(f64::NEG_INFINITY),
+                _ => Err(D::Error::invalid_value(
+                    Unexpected::Str(&text),
+                    &"a number, \"NaN\", \"inf\" or \"-inf\"",
+                )),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        arena::{ArenaExpr, ExprArena},
+        expression::{BinaryExpr, Expr, GroupingExpr},
+        parse,
+        parser::DEFAULT_MAX_DEPTH,
+        printer::print,
+        serialize::{from_bytes, from_json, to_bytes, to_json},
+        token::{Token, TokenKind},
+    };
+
+    const SOURCES: [&str; 4] = [
+        "1 + 2 * 3",
+        "(1 + 2) * -3 >= 4 == !false",
+        "\"a\" != nil",
+        "-(\"back\\slash {}\")",
+    ];
+
+    #[test]
+    fn test_json_round_trip() {
+        for source in SOURCES {
+            let expr = parse(source).expect("source parses");
+            assert_eq!(from_json(&to_json(&expr)).expect("valid JSON"), expr);
+        }
+    }
+
+    #[test]
+    fn test_binary_round_trip() {
+        for source in SOURCES {
+            let expr = parse(source).expect("source parses");
+            let bytes = to_bytes(&expr);
+            assert!(bytes.len() < to_json(&expr).len());
+            assert_eq!(from_bytes(&bytes).expect("valid bytes"), expr);
+        }
+    }
+
+    #[test]
+    fn test_json_shape() {
+        let json = to_json(&parse("-1").expect("source parses"));
+        let value: serde_json::Value = serde_json::from_str(&json).expect("valid JSON");
+        assert_eq!(value["nodes"][0]["NumberLiteral"], 1.0);
+        assert_eq!(value["nodes"][1]["Unary"]["operator"]["kind"], "Minus");
+        assert_eq!(value["nodes"][1]["Unary"]["right"], 0);
+    }
+
+    #[test]
+    fn test_invalid_input() {
+        assert!(from_json("{\"Unary\": 1}").is_err());
+        assert!(from_bytes(&[0xff, 0xff]).is_err());
+        assert!(from_json("{\"nodes\": []}").is_err());
+        assert!(from_json("{\"nodes\": [{\"Grouping\": {\"expression\": 0}}]}").is_err());
+        assert!(from_json("{\"nodes\": [\"NilLiteral\", \"NilLiteral\"]}").is_err());
+        assert!(from_json("{\"nodes\": [{\"NumberLiteral\": \"one\"}]}").is_err());
+
+        // A node shared between two operands
+        let mut arena = ExprArena::new();
+        let one = arena.alloc(ArenaExpr::NumberLiteral(1.0));
+        arena.alloc(ArenaExpr::Binary {
+            left: one,
+            operator: Token::from(TokenKind::Plus),
+            right: one,
+        });
+        let json = serde_json::to_string(&arena).expect("arena serializes");
+        assert!(from_json(&json).is_err());
+    }
+
+    #[test]
+    fn test_deep_round_trip() {
+        // As deep as the parser allows, the innermost literal being at depth
+        // DEFAULT_MAX_DEPTH
+        let groups = DEFAULT_MAX_DEPTH - 1;
+        let source = format!("{}1{}", "(".repeat(groups), ")".repeat(groups));
+        let expr = parse(&source).expect("source parses");
+        assert_eq!(from_json(&to_json(&expr)).expect("valid JSON"), expr);
+        assert_eq!(from_bytes(&to_bytes(&expr)).expect("valid bytes"), expr);
+
+        let expr = Expr::Grouping(GroupingExpr::new(expr));
+        assert!(from_json(&to_json(&expr)).is_err());
+        assert!(from_bytes(&to_bytes(&expr)).is_err());
+    }
+
+    #[test]
+    fn test_long_chain_round_trip() {
+        let source = format!("1{}", " - 1".repeat(200_000));
+        let expr = parse(&source).expect("source parses");
+        let loaded = from_json(&to_json(&expr)).expect("valid JSON");
+        assert_eq!(print(&loaded), source);
+        let loaded = from_bytes(&to_bytes(&expr)).expect("valid bytes");
+        assert_eq!(print(&loaded), source);
+    }
+
+    #[test]
+    fn test_non_finite_round_trip() {
+        for value in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            let expr = Expr::Binary(BinaryExpr::new(
+                Expr::NumberLiteral(value),
+                Token::from(TokenKind::Plus),
+                Expr::NumberLiteral(1.0),
+            ));
+            for loaded in [
+                from_json(&to_json(&expr)).expect("valid JSON"),
+                from_bytes(&to_bytes(&expr)).expect("valid bytes"),
+            ] {
+                let Expr::Binary(binary) = &loaded else {
+                    panic!("expected binary expression");
+                };
+                let Expr::NumberLiteral(loaded) = *binary.left else {
+                    panic!("expected number literal");
+                };
+                assert_eq!(loaded.to_bits(), value.to_bits());
+            }
+        }
+
+        let token = Token::from((TokenKind::Number, f64::INFINITY));
+        let json = serde_json::to_string(&token).expect("token serializes");
+        let loaded: Token = serde_json::from_str(&json).expect("valid JSON");
+        assert_eq!(loaded.value, token.value);
+    }
+
+    #[test]
+    fn test_arena_round_trip() {
+        let mut arena = ExprArena::new();
+        arena.lower(&parse("(1 + 2) * -3").expect("source parses"));
+        let json = serde_json::to_string(&arena).expect("arena serializes");
+        let loaded: ExprArena = serde_json::from_str(&json).expect("valid JSON");
+        assert_eq!(loaded, arena);
+    }
+}
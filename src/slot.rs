@@ -0,0 +1,142 @@
+use crate::gc::ObjRef;
+
+// A value as the VM holds it. Strings live on the heap so that slots can be
+// copied. How a slot is stored depends on the `nan-boxing` feature, so the
+// VM builds them from and takes them apart into this.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Unpacked {
+    Nil,
+    Boolean(bool),
+    Number(f64),
+    Obj(ObjRef),
+}
+
+#[cfg(not(feature = "nan-boxing"))]
+#[derive(Debug, Clone, Copy)]
+pub struct Slot(Unpacked);
+
+#[cfg(not(feature = "nan-boxing"))]
+impl Slot {
+    pub fn new(value: Unpacked) -> Slot {
+        Slot(value)
+    }
+
+    pub fn unpack(self) -> Unpacked {
+        self.0
+    }
+}
+
+// Every value in one 64 bit word. A number is stored as itself. Anything
+// else is a quiet NaN no arithmetic produces, with the sign bit set for an
+// object and the low bits holding the object's index or a tag.
+#[cfg(feature = "nan-boxing")]
+#[derive(Debug, Clone, Copy)]
+pub struct Slot(u64);
+
+#[cfg(feature = "nan-boxing")]
+const QUIET_NAN: u64 = 0x7ffc_0000_0000_0000;
+#[cfg(feature = "nan-boxing")]
+const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+#[cfg(feature = "nan-boxing")]
+const TAG_NIL: u64 = 1;
+#[cfg(feature = "nan-boxing")]
+const TAG_FALSE: u64 = 2;
+#[cfg(feature = "nan-boxing")]
+const TAG_TRUE: u64 = 3;
+
+#[cfg(feature = "nan-boxing")]
+impl Slot {
+    pub fn new(value: Unpacked) -> Slot {
+        let bits = match value {
+            // NaNs may carry any payload, including one that looks boxed,
+            // so they are all stored as the standard one
+            Unpacked::Number(number) if number.is_nan() => f64::NAN.to_bits(),
+            Unpacked::Number(number) => number.to_bits(),
+            Unpacked::Nil => QUIET_NAN | TAG_NIL,
+            Unpacked::Boolean(false) => QUIET_NAN | TAG_FALSE,
+            Unpacked::Boolean(true) => QUIET_NAN | TAG_TRUE,
+            Unpacked::Obj(obj) => SIGN_BIT | QUIET_NAN | obj.index() as u64,
+        };
+        Slot(bits)
+    }
+
+    pub fn unpack(self) -> Unpacked {
+        if self.0 & QUIET_NAN != QUIET_NAN {
+            return Unpacked::Number(f64::from_bits(self.0));
+        }
+        if self.0 & SIGN_BIT != 0 {
+            return Unpacked::Obj(ObjRef::from_index((self.0 as u32) as usize));
+        }
+        match self.0 & !QUIET_NAN {
+            TAG_NIL => Unpacked::Nil,
+            TAG_FALSE => Unpacked::Boolean(false),
+            TAG_TRUE => Unpacked::Boolean(true),
+            tag => unreachable!("invalid slot tag {}", tag),
+        }
+    }
+}
+
+// Compares what the slots hold, so a NaN is unequal to itself whichever way
+// it is stored
+impl PartialEq for Slot {
+    fn eq(&self, other: &Slot) -> bool {
+        self.unpack() == other.unpack()
+    }
+}
+
+impl Slot {
+    pub fn as_obj(self) -> Option<ObjRef> {
+        match self.unpack() {
+            Unpacked::Obj(obj) => Some(obj),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        gc::ObjRef,
+        slot::{Slot, Unpacked},
+    };
+
+    #[test]
+    fn test_round_trip() {
+        for value in [
+            Unpacked::Nil,
+            Unpacked::Boolean(false),
+            Unpacked::Boolean(true),
+            Unpacked::Number(0.0),
+            Unpacked::Number(-0.0),
+            Unpacked::Number(1.5e300),
+            Unpacked::Number(f64::INFINITY),
+            Unpacked::Number(f64::NEG_INFINITY),
+            Unpacked::Number(f64::MIN_POSITIVE / 2.0),
+            Unpacked::Obj(ObjRef::from_index(0)),
+            Unpacked::Obj(ObjRef::from_index(u32::MAX as usize)),
+        ] {
+            assert_eq!(Slot::new(value).unpack(), value);
+        }
+        if let Unpacked::Number(number) = Slot::new(Unpacked::Number(-0.0)).unpack() {
+            assert!(number.is_sign_negative());
+        }
+    }
+
+    #[test]
+    fn test_nans_stay_numbers() {
+        // Including one whose bits look like a boxed nil
+        let odd_nan = f64::from_bits(0x7ffc_0000_0000_0001);
+        for nan in [f64::NAN, -f64::NAN, odd_nan] {
+            assert!(matches!(
+                Slot::new(Unpacked::Number(nan)).unpack(),
+                Unpacked::Number(number) if number.is_nan()
+            ));
+        }
+    }
+
+    #[test]
+    fn test_size() {
+        let expected = if cfg!(feature = "nan-boxing") { 8 } else { 16 };
+        assert_eq!(size_of::<Slot>(), expected);
+    }
+}
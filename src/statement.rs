@@ -0,0 +1,58 @@
+use std::rc::Rc;
+
+use crate::{expression::Expr, token::Token};
+
+// A program is a list of these. `for` loops have no node of their own: the
+// parser turns them into a block holding the initializer and a `while`, so
+// the loop variable is a single variable shared by every iteration.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stmt {
+    Expression(Expr),
+    Print(PrintStmt),
+    Var(VarStmt),
+    Block(Vec<Stmt>),
+    If(IfStmt),
+    While(WhileStmt),
+    // Shared with the closures made from it
+    Function(Rc<FunctionStmt>),
+    Return(ReturnStmt),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrintStmt {
+    // Where a failure to write the output is reported
+    pub keyword: Token,
+    pub expression: Expr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VarStmt {
+    pub name: Token,
+    pub initializer: Option<Expr>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IfStmt {
+    pub condition: Expr,
+    pub then_branch: Box<Stmt>,
+    pub else_branch: Option<Box<Stmt>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WhileStmt {
+    pub condition: Expr,
+    pub body: Box<Stmt>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionStmt {
+    pub name: Token,
+    pub params: Vec<Token>,
+    pub body: Vec<Stmt>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReturnStmt {
+    pub keyword: Token,
+    pub value: Option<Expr>,
+}
@@ -0,0 +1,236 @@
+use crate::gc::ObjRef;
+
+// FNV-1a, which is quick on the short strings programs use as keys
+pub fn hash_string(string: &str) -> u32 {
+    let mut hash = 0x811c_9dc5u32;
+    for byte in string.bytes() {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+    hash
+}
+
+#[derive(Debug, Clone)]
+enum Bucket<V> {
+    Empty,
+    // Left by a removal so probing carries on past it
+    Tombstone,
+    Full { key: ObjRef, hash: u32, value: V },
+}
+
+// A hash table keyed by interned strings. Interning makes equal strings the
+// same object, so keys are compared by handle, and the caller passes in the
+// hash the string object caches rather than the table hashing anything.
+// Collisions are resolved by linear probing.
+#[derive(Debug, Clone)]
+pub struct Table<V> {
+    buckets: Vec<Bucket<V>>,
+    len: usize,
+    // Full buckets and tombstones, which both lengthen probes
+    used: usize,
+}
+
+impl<V> Default for Table<V> {
+    fn default() -> Table<V> {
+        Table::new()
+    }
+}
+
+impl<V> Table<V> {
+    pub fn new() -> Table<V> {
+        Table {
+            buckets: Vec::new(),
+            len: 0,
+            used: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, key: ObjRef, hash: u32) -> Option<&V> {
+        match self.buckets.get(self.find(key, hash)?) {
+            Some(Bucket::Full { value, .. }) => Some(value),
+            _ => None,
+        }
+    }
+
+    // Gives back the value `key` had, if any
+    pub fn insert(&mut self, key: ObjRef, hash: u32, value: V) -> Option<V> {
+        if let Some(index) = self.find(key, hash) {
+            let Bucket::Full { value: old, .. } = &mut self.buckets[index] else {
+                unreachable!("found keys are in full buckets");
+            };
+            return Some(std::mem::replace(old, value));
+        }
+
+        // Rebuild at three quarters full, counting tombstones, leaving the
+        // live entries at most half the buckets
+        if (self.used + 1) * 4 > self.buckets.len() * 3 {
+            self.resize(((self.len + 1) * 2).next_power_of_two().max(8));
+        }
+        let index = self.probe(hash, |bucket| !matches!(bucket, Bucket::Full { .. }));
+        if matches!(self.buckets[index], Bucket::Empty) {
+            self.used += 1;
+        }
+        self.buckets[index] = Bucket::Full { key, hash, value };
+        self.len += 1;
+        None
+    }
+
+    pub fn remove(&mut self, key: ObjRef, hash: u32) -> Option<V> {
+        let index = self.find(key, hash)?;
+        self.len -= 1;
+        match std::mem::replace(&mut self.buckets[index], Bucket::Tombstone) {
+            Bucket::Full { value, .. } => Some(value),
+            _ => unreachable!("found keys are in full buckets"),
+        }
+    }
+
+    // The first key with `hash` that `matches`, for looking a string up by
+    // its contents when interning it
+    pub fn find_key(&self, hash: u32, matches: impl Fn(ObjRef) -> bool) -> Option<ObjRef> {
+        let index = self.probe_full(hash, matches)?;
+        match self.buckets[index] {
+            Bucket::Full { key, .. } => Some(key),
+            _ => None,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (ObjRef, &V)> {
+        self.buckets.iter().filter_map(|bucket| match bucket {
+            Bucket::Full { key, value, .. } => Some((*key, value)),
+            _ => None,
+        })
+    }
+
+    fn find(&self, key: ObjRef, hash: u32) -> Option<usize> {
+        self.probe_full(hash, |candidate| candidate == key)
+    }
+
+    // Walks the probe sequence for `hash` to the full bucket whose key
+    // matches, stopping at an empty bucket as the key would have gone there
+    fn probe_full(&self, hash: u32, matches: impl Fn(ObjRef) -> bool) -> Option<usize> {
+        if self.buckets.is_empty() {
+            return None;
+        }
+        let mask = self.buckets.len() - 1;
+        let mut index = hash as usize & mask;
+        loop {
+            match &self.buckets[index] {
+                Bucket::Empty => return None,
+                Bucket::Full {
+                    key,
+                    hash: candidate,
+                    ..
+                } if *candidate == hash && matches(*key) => return Some(index),
+                _ => index = (index + 1) & mask,
+            }
+        }
+    }
+
+    // The first bucket in the probe sequence for `hash` that `accepts`. The
+    // table always has an empty bucket, so one is found.
+    fn probe(&self, hash: u32, accepts: impl Fn(&Bucket<V>) -> bool) -> usize {
+        let mask = self.buckets.len() - 1;
+        let mut index = hash as usize & mask;
+        while !accepts(&self.buckets[index]) {
+            index = (index + 1) & mask;
+        }
+        index
+    }
+
+    // Moves every entry into `capacity` buckets, which drops the tombstones
+    fn resize(&mut self, capacity: usize) {
+        let buckets = std::mem::replace(
+            &mut self.buckets,
+            (0..capacity).map(|_| Bucket::Empty).collect(),
+        );
+        self.used = self.len;
+        for bucket in buckets {
+            if let Bucket::Full { key, hash, value } = bucket {
+                let index = self.probe(hash, |bucket| matches!(bucket, Bucket::Empty));
+                self.buckets[index] = Bucket::Full { key, hash, value };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::{
+        gc::ObjRef,
+        table::{Table, hash_string},
+        testing::Rng,
+    };
+
+    fn key(index: usize) -> ObjRef {
+        ObjRef::from_index(index)
+    }
+
+    #[test]
+    fn test_hash_string() {
+        assert_eq!(hash_string(""), 0x811c_9dc5);
+        assert_eq!(hash_string("a"), 0xe40c_292c);
+        assert_eq!(hash_string("foobar"), 0xbf9c_f968);
+    }
+
+    #[test]
+    fn test_find_key() {
+        let mut table = Table::new();
+        let names = ["a", "b", "c"];
+        for (index, name) in names.iter().enumerate() {
+            table.insert(key(index), hash_string(name), ());
+        }
+        let find = |name: &str| table.find_key(hash_string(name), |key| names[key.index()] == name);
+        assert_eq!(find("b"), Some(key(1)));
+        assert_eq!(find("d"), None);
+    }
+
+    // Runs random operations on a table and a `HashMap` side by side. Hashes
+    // are deliberately weak so that keys collide and probe sequences run
+    // through tombstones.
+    #[test]
+    fn test_agrees_with_hash_map() {
+        for seed in 1..=50 {
+            let mut rng = Rng(seed);
+            let keys = 1 + rng.below(200);
+            let buckets = 1 + rng.below(16) as u32;
+            let hash = |index: usize| index as u32 % buckets;
+
+            let mut table = Table::new();
+            let mut map = HashMap::new();
+            for _ in 0..2000 {
+                let index = rng.below(keys);
+                match rng.below(3) {
+                    0 => {
+                        let value = rng.next();
+                        assert_eq!(
+                            table.insert(key(index), hash(index), value),
+                            map.insert(index, value)
+                        );
+                    }
+                    1 => assert_eq!(table.remove(key(index), hash(index)), map.remove(&index)),
+                    _ => assert_eq!(table.get(key(index), hash(index)), map.get(&index)),
+                }
+                assert_eq!(table.len(), map.len());
+            }
+
+            let mut entries = table
+                .iter()
+                .map(|(key, value)| (key.index(), *value))
+                .collect::<Vec<_>>();
+            entries.sort();
+            let mut expected = map.into_iter().collect::<Vec<_>>();
+            expected.sort();
+            assert_eq!(entries, expected, "seed {}", seed);
+        }
+    }
+}
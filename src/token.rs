@@ -1,14 +1,66 @@
 use std::fmt::Display;
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Token {
     pub kind: TokenKind,
     pub value: Option<TokenValue>,
+    pub span: Span,
+}
+
+impl Token {
+    pub fn with_span(self, span: Span) -> Token {
+        Token { span, ..self }
+    }
+
+    // The name an identifier stands for
+    pub fn name(&self) -> &str {
+        match &self.value {
+            Some(TokenValue::String(name)) if self.kind == TokenKind::Identifier => name,
+            _ => "",
+        }
+    }
+}
+
+// Where a token starts in the source. Both are counted from 1, and are 0 for
+// tokens that didn't come from source.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Span {
+    pub fn new(line: usize, column: usize) -> Span {
+        Span { line, column }
+    }
+
+    pub fn is_known(&self) -> bool {
+        self.line > 0
+    }
+}
+
+impl Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+// Where a token came from doesn't change what it is
+impl PartialEq for Token {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind && self.value == other.value
+    }
 }
 
 impl From<TokenKind> for Token {
     fn from(kind: TokenKind) -> Token {
-        Token { kind, value: None }
+        Token {
+            kind,
+            value: None,
+            span: Span::default(),
+        }
     }
 }
 
@@ -17,6 +69,7 @@ impl From<(TokenKind, String)> for Token {
         Token {
             kind,
             value: Some(TokenValue::String(value)),
+            span: Span::default(),
         }
     }
 }
@@ -26,6 +79,7 @@ impl From<(TokenKind, f64)> for Token {
         Token {
             kind,
             value: Some(TokenValue::Number(value)),
+            span: Span::default(),
         }
     }
 }
@@ -40,8 +94,8 @@ impl Display for Token {
     }
 }
 
-
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum TokenKind {
     LeftParen,
     RightParen,
@@ -141,9 +195,10 @@ impl Display for TokenKind {
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum TokenValue {
     String(String),
-    Number(f64),
+    Number(#[cfg_attr(feature = "serde", serde(with = "crate::serialize::number"))] f64),
 }
 
 impl Display for TokenValue {
@@ -153,4 +208,4 @@ impl Display for TokenValue {
             TokenValue::Number(value) => write!(f, "{}", value),
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,311 @@
+use std::{any::Any, fmt::Display, rc::Rc};
+
+use crate::{
+    expression::Expr,
+    token::{Span, Token, TokenKind},
+};
+
+// How deeply calls may nest before the program is stopped with a stack
+// overflow, the same on every backend
+pub const MAX_CALL_DEPTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Boolean(bool),
+    Number(f64),
+    String(String),
+    Nil,
+    Function(Function),
+}
+
+// A function as a value. Calling one is up to the backend that made it, so
+// here it only has a name to print and an identity: the backend's own
+// function object, which makes a function equal only to itself.
+#[derive(Clone)]
+pub struct Function {
+    name: String,
+    object: Rc<dyn Any>,
+}
+
+impl Function {
+    pub fn new(name: &str, object: Rc<dyn Any>) -> Function {
+        Function {
+            name: name.to_string(),
+            object,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn object(&self) -> &Rc<dyn Any> {
+        &self.object
+    }
+}
+
+impl PartialEq for Function {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.object, &other.object)
+    }
+}
+
+impl std::fmt::Debug for Function {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Function({})", self.name)
+    }
+}
+
+impl Display for Function {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<fn {}>", self.name)
+    }
+}
+
+impl Value {
+    pub fn from_literal(expr: &Expr) -> Option<Value> {
+        match expr {
+            Expr::BooleanLiteral(value) => Some(Value::Boolean(*value)),
+            Expr::NumberLiteral(value) => Some(Value::Number(*value)),
+            Expr::StringLiteral(value) => Some(Value::String(value.clone())),
+            Expr::NilLiteral => Some(Value::Nil),
+            Expr::Unary(_)
+            | Expr::Binary(_)
+            | Expr::Grouping(_)
+            | Expr::Variable(_)
+            | Expr::Assign(_)
+            | Expr::Call(_) => None,
+        }
+    }
+
+    // Functions have no literal, so they become the name they were declared
+    // with
+    pub fn into_literal(self) -> Expr {
+        match self {
+            Value::Boolean(value) => Expr::BooleanLiteral(value),
+            Value::Number(value) => Expr::NumberLiteral(value),
+            Value::String(value) => Expr::StringLiteral(value),
+            Value::Nil => Expr::NilLiteral,
+            Value::Function(function) => {
+                Expr::Variable(Token::from((TokenKind::Identifier, function.name)))
+            }
+        }
+    }
+
+    // `nil` and `false` are falsey, everything else is truthy
+    pub fn is_truthy(&self) -> bool {
+        !matches!(self, Value::Nil | Value::Boolean(false))
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Boolean(value) => write!(f, "{}", value),
+            Value::Number(value) => write!(f, "{}", value),
+            Value::String(value) => write!(f, "{}", value),
+            Value::Nil => write!(f, "nil"),
+            Value::Function(function) => write!(f, "{}", function),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuntimeError {
+    OperandMustBeNumber(Token),
+    OperandsMustBeNumbers(Token),
+    OperandsMustBeNumbersOrStrings(Token),
+    UnknownOperator(Token),
+    // The variable's name
+    UndefinedVariable(Token),
+    // The closing paren of the call
+    NotCallable(Token),
+    // The closing paren, and the number of parameters and arguments
+    WrongArity(Token, usize, usize),
+    StackOverflow(Token),
+    // A `print` that could not write its output
+    Output(Token),
+}
+
+impl RuntimeError {
+    pub fn token(&self) -> &Token {
+        match self {
+            RuntimeError::OperandMustBeNumber(token)
+            | RuntimeError::OperandsMustBeNumbers(token)
+            | RuntimeError::OperandsMustBeNumbersOrStrings(token)
+            | RuntimeError::UnknownOperator(token)
+            | RuntimeError::UndefinedVariable(token)
+            | RuntimeError::NotCallable(token)
+            | RuntimeError::WrongArity(token, ..)
+            | RuntimeError::StackOverflow(token)
+            | RuntimeError::Output(token) => token,
+        }
+    }
+
+    // The same error, reported at `span`
+    pub fn at(mut self, span: Span) -> RuntimeError {
+        match &mut self {
+            RuntimeError::OperandMustBeNumber(token)
+            | RuntimeError::OperandsMustBeNumbers(token)
+            | RuntimeError::OperandsMustBeNumbersOrStrings(token)
+            | RuntimeError::UnknownOperator(token)
+            | RuntimeError::UndefinedVariable(token)
+            | RuntimeError::NotCallable(token)
+            | RuntimeError::WrongArity(token, ..)
+            | RuntimeError::StackOverflow(token)
+            | RuntimeError::Output(token) => token.span = span,
+        }
+        self
+    }
+}
+
+impl From<&RuntimeError> for String {
+    fn from(value: &RuntimeError) -> Self {
+        let span = value.token().span;
+        let message = match value {
+            RuntimeError::OperandMustBeNumber(token) => {
+                format!("Operand of {} must be a number", token)
+            }
+            RuntimeError::OperandsMustBeNumbers(token) => {
+                format!("Operands of {} must be numbers", token)
+            }
+            RuntimeError::OperandsMustBeNumbersOrStrings(token) => {
+                format!("Operands of {} must be two numbers or two strings", token)
+            }
+            RuntimeError::UnknownOperator(token) => format!("Unknown operator {}", token),
+            RuntimeError::UndefinedVariable(name) => {
+                format!("Undefined variable '{}'", name.name())
+            }
+            RuntimeError::NotCallable(_) => "Can only call functions".to_string(),
+            RuntimeError::WrongArity(_, parameters, arguments) => {
+                format!("Expected {} arguments but got {}", parameters, arguments)
+            }
+            RuntimeError::StackOverflow(_) => "Stack overflow".to_string(),
+            RuntimeError::Output(_) => "Failed to write output".to_string(),
+        };
+        if span.is_known() {
+            format!("Line {}: {}", span, message)
+        } else {
+            message
+        }
+    }
+}
+
+impl From<RuntimeError> for String {
+    fn from(value: RuntimeError) -> Self {
+        String::from(&value)
+    }
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", String::from(self))
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+// The semantics of each operator live here, so that everything that evaluates
+// expressions, including constant folding, agrees on them.
+
+pub fn unary(operator: &Token, right: Value) -> Result<Value, RuntimeError> {
+    match (&operator.kind, right) {
+        (TokenKind::Minus, Value::Number(value)) => Ok(Value::Number(-value)),
+        (TokenKind::Minus, _) => Err(RuntimeError::OperandMustBeNumber(operator.clone())),
+        (TokenKind::Bang, value) => Ok(Value::Boolean(!value.is_truthy())),
+        _ => Err(RuntimeError::UnknownOperator(operator.clone())),
+    }
+}
+
+pub fn binary(left: Value, operator: &Token, right: Value) -> Result<Value, RuntimeError> {
+    let numbers = match (&left, &right) {
+        (Value::Number(left), Value::Number(right)) => Some((*left, *right)),
+        _ => None,
+    };
+
+    match (&operator.kind, numbers) {
+        (TokenKind::EqualEqual, _) => Ok(Value::Boolean(left == right)),
+        (TokenKind::BangEqual, _) => Ok(Value::Boolean(left != right)),
+        (TokenKind::Plus, Some((left, right))) => Ok(Value::Number(left + right)),
+        (TokenKind::Plus, None) => match (left, right) {
+            (Value::String(left), Value::String(right)) => Ok(Value::String(left + &right)),
+            _ => Err(RuntimeError::OperandsMustBeNumbersOrStrings(
+                operator.clone(),
+            )),
+        },
+        (TokenKind::Minus, Some((left, right))) => Ok(Value::Number(left - right)),
+        (TokenKind::Star, Some((left, right))) => Ok(Value::Number(left * right)),
+        (TokenKind::Slash, Some((left, right))) => Ok(Value::Number(left / right)),
+        (TokenKind::Greater, Some((left, right))) => Ok(Value::Boolean(left > right)),
+        (TokenKind::GreaterEqual, Some((left, right))) => Ok(Value::Boolean(left >= right)),
+        (TokenKind::Less, Some((left, right))) => Ok(Value::Boolean(left < right)),
+        (TokenKind::LessEqual, Some((left, right))) => Ok(Value::Boolean(left <= right)),
+        (
+            TokenKind::Minus
+            | TokenKind::Star
+            | TokenKind::Slash
+            | TokenKind::Greater
+            | TokenKind::GreaterEqual
+            | TokenKind::Less
+            | TokenKind::LessEqual,
+            None,
+        ) => Err(RuntimeError::OperandsMustBeNumbers(operator.clone())),
+        _ => Err(RuntimeError::UnknownOperator(operator.clone())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        token::{Token, TokenKind},
+        value::{RuntimeError, Value, binary, unary},
+    };
+
+    #[test]
+    fn test_truthiness() {
+        let minus = Token::from(TokenKind::Minus);
+        assert_eq!(
+            unary(&Token::from(TokenKind::Bang), Value::Nil),
+            Ok(Value::Boolean(true))
+        );
+        assert_eq!(
+            unary(&Token::from(TokenKind::Bang), Value::Number(0.0)),
+            Ok(Value::Boolean(false))
+        );
+        assert_eq!(
+            unary(&minus, Value::String("x".to_string())),
+            Err(RuntimeError::OperandMustBeNumber(minus))
+        );
+    }
+
+    #[test]
+    fn test_binary_operators() {
+        let plus = Token::from(TokenKind::Plus);
+        assert_eq!(
+            binary(
+                Value::String("a".to_string()),
+                &plus,
+                Value::String("b".to_string())
+            ),
+            Ok(Value::String("ab".to_string()))
+        );
+        assert_eq!(
+            binary(Value::String("a".to_string()), &plus, Value::Number(1.0)),
+            Err(RuntimeError::OperandsMustBeNumbersOrStrings(plus))
+        );
+        assert_eq!(
+            binary(
+                Value::Number(1.0),
+                &Token::from(TokenKind::EqualEqual),
+                Value::String("1".to_string())
+            ),
+            Ok(Value::Boolean(false))
+        );
+        assert_eq!(
+            binary(Value::Nil, &Token::from(TokenKind::Less), Value::Nil),
+            Err(RuntimeError::OperandsMustBeNumbers(Token::from(
+                TokenKind::Less
+            )))
+        );
+    }
+}
@@ -0,0 +1,730 @@
+use std::{io::Write, rc::Rc};
+
+use crate::{
+    chunk::{Chunk, OpCode, Prototype},
+    disassembler::disassemble_instruction,
+    gc::{Heap, Obj, ObjClosure, ObjFunction, ObjRef, ObjUpvalue},
+    slot::{Slot, Unpacked},
+    table::Table,
+    token::{Token, TokenKind},
+    value::{self, Function, MAX_CALL_DEPTH, RuntimeError, Value},
+};
+
+// A call in progress
+struct Frame {
+    closure: ObjRef,
+    // The closure's function, whose constants the code refers to
+    function: ObjRef,
+    prototype: Rc<Prototype>,
+    ip: usize,
+    // Where the call's window of the stack starts. The first slot holds the
+    // closure, followed by the arguments and then the other locals.
+    base: usize,
+}
+
+pub struct Vm {
+    stack: Vec<Slot>,
+    // The script's frame, then one for each call in progress
+    frames: Vec<Frame>,
+    // Upvalues still pointing at a stack slot, ordered by the slot
+    open_upvalues: Vec<ObjRef>,
+    // Keyed by the interned name
+    globals: Table<Slot>,
+    heap: Heap,
+    trace: bool,
+}
+
+impl Default for Vm {
+    fn default() -> Vm {
+        Vm::new()
+    }
+}
+
+impl Vm {
+    pub fn new() -> Vm {
+        Vm {
+            stack: Vec::new(),
+            frames: Vec::new(),
+            open_upvalues: Vec::new(),
+            globals: Table::new(),
+            heap: Heap::new(),
+            trace: false,
+        }
+    }
+
+    // Prints the stack and each instruction to stderr as it is executed
+    pub fn with_trace(self, trace: bool) -> Vm {
+        Vm { trace, ..self }
+    }
+
+    // Allocates on `heap`, for a collector configured other than by default
+    pub fn with_heap(self, heap: Heap) -> Vm {
+        Vm { heap, ..self }
+    }
+
+    // Collects garbage before every allocation
+    pub fn with_gc_stress(self, stress: bool) -> Vm {
+        Vm {
+            heap: self.heap.with_stress(stress),
+            ..self
+        }
+    }
+
+    pub fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    // Executes `chunk` up to its `Return` and gives back the returned value.
+    // Chunks come from the compiler or from `bytecode::read`, which verifies
+    // loaded bytecode before handing it over. Either way they are well formed,
+    // so malformed bytecode is a bug and panics rather than being reported as
+    // a runtime error.
+    pub fn run(&mut self, chunk: &Chunk) -> Result<Value, RuntimeError> {
+        let script = Prototype {
+            chunk: chunk.clone(),
+            ..Prototype::default()
+        };
+        self.run_program(Rc::new(script), &mut std::io::sink())
+    }
+
+    // Runs a compiled program, writing what it prints to `output`, and gives
+    // back what the script returns. Nothing is kept from an earlier run.
+    pub fn run_program(
+        &mut self,
+        script: Rc<Prototype>,
+        output: &mut dyn Write,
+    ) -> Result<Value, RuntimeError> {
+        self.stack.clear();
+        self.frames.clear();
+        self.open_upvalues.clear();
+        self.globals = Table::new();
+
+        // The script is called like any other function, with its closure
+        // rooted in the first slot
+        let function = self.load(script.clone());
+        self.push(Slot::new(Unpacked::Obj(function)));
+        let closure = self.alloc(Obj::Closure(ObjClosure {
+            function,
+            upvalues: Box::new([]),
+        }));
+        self.stack[0] = Slot::new(Unpacked::Obj(closure));
+        self.frames.push(Frame {
+            closure,
+            function,
+            prototype: script,
+            ip: 0,
+            base: 0,
+        });
+
+        loop {
+            if self.trace {
+                let frame = self.frame();
+                eprintln!("{}", self.trace_stack());
+                eprintln!(
+                    "{}",
+                    disassemble_instruction(&frame.prototype.chunk, frame.ip).0
+                );
+            }
+
+            let frame = self.frames.last_mut().expect("a call is running");
+            let code = &frame.prototype.chunk.code;
+            let start = frame.ip;
+            let op = OpCode::try_from(code[start]).expect("valid opcode");
+            let len = op.operand_len();
+            let mut operands = [0; 3];
+            operands[..len].copy_from_slice(&code[start + 1..start + 1 + len]);
+            frame.ip = start + 1 + len;
+
+            // Only a failing instruction needs its position looked up. Nothing
+            // fails after changing frames, so it is in the current one.
+            match self.step(op, &operands[..len], output) {
+                Ok(None) => {}
+                Ok(Some(value)) => return Ok(value),
+                Err(error) => return Err(error.at(self.frame().prototype.chunk.span(start))),
+            }
+        }
+    }
+
+    // Executes a single instruction, giving back the result when the script
+    // returns
+    fn step(
+        &mut self,
+        op: OpCode,
+        operands: &[u8],
+        output: &mut dyn Write,
+    ) -> Result<Option<Value>, RuntimeError> {
+        match op {
+            OpCode::Constant => self.push(self.constant(operands[0])),
+            OpCode::ConstantLong => {
+                let index = u32::from_le_bytes([operands[0], operands[1], operands[2], 0]);
+                self.push(self.constant(index));
+            }
+            OpCode::Nil => self.push(Slot::new(Unpacked::Nil)),
+            OpCode::True => self.push(Slot::new(Unpacked::Boolean(true))),
+            OpCode::False => self.push(Slot::new(Unpacked::Boolean(false))),
+            OpCode::Equal => self.equal(TokenKind::EqualEqual)?,
+            OpCode::NotEqual => self.equal(TokenKind::BangEqual)?,
+            OpCode::Greater => self.binary(TokenKind::Greater)?,
+            OpCode::GreaterEqual => self.binary(TokenKind::GreaterEqual)?,
+            OpCode::Less => self.binary(TokenKind::Less)?,
+            OpCode::LessEqual => self.binary(TokenKind::LessEqual)?,
+            OpCode::Add => self.binary(TokenKind::Plus)?,
+            OpCode::Subtract => self.binary(TokenKind::Minus)?,
+            OpCode::Multiply => self.binary(TokenKind::Star)?,
+            OpCode::Divide => self.binary(TokenKind::Slash)?,
+            OpCode::Not => self.unary(TokenKind::Bang)?,
+            OpCode::Negate => self.unary(TokenKind::Minus)?,
+            OpCode::Return => {
+                let result = self.pop();
+                return Ok(self.return_from_call(result));
+            }
+            OpCode::ReturnConstant => {
+                let result = self.constant(operands[0]);
+                return Ok(self.return_from_call(result));
+            }
+            OpCode::Pop => {
+                self.pop();
+            }
+            OpCode::Print => {
+                let value = self.pop();
+                writeln!(output, "{}", self.value(value))
+                    .map_err(|_| RuntimeError::Output(Token::from(TokenKind::Print)))?;
+            }
+            OpCode::DefineGlobal => {
+                let (name, hash) = self.global_name(operands[0]);
+                let value = self.pop();
+                self.globals.insert(name, hash, value);
+            }
+            OpCode::GetGlobal => {
+                let (name, hash) = self.global_name(operands[0]);
+                match self.globals.get(name, hash) {
+                    Some(value) => self.push(*value),
+                    None => return Err(self.undefined(name)),
+                }
+            }
+            OpCode::SetGlobal => {
+                let (name, hash) = self.global_name(operands[0]);
+                if self.globals.get(name, hash).is_none() {
+                    return Err(self.undefined(name));
+                }
+                self.globals.insert(name, hash, self.peek());
+            }
+            OpCode::GetLocal => {
+                let slot = self.frame().base + operands[0] as usize;
+                self.push(self.stack[slot]);
+            }
+            OpCode::SetLocal => {
+                let slot = self.frame().base + operands[0] as usize;
+                self.stack[slot] = self.peek();
+            }
+            OpCode::GetUpvalue => {
+                let value = match self.heap.get(self.upvalue(operands[0])) {
+                    Obj::Upvalue(ObjUpvalue::Open(slot)) => self.stack[*slot],
+                    Obj::Upvalue(ObjUpvalue::Closed(value)) => *value,
+                    _ => unreachable!("closures capture upvalues"),
+                };
+                self.push(value);
+            }
+            OpCode::SetUpvalue => {
+                let upvalue = self.upvalue(operands[0]);
+                let value = self.peek();
+                match self.heap.get(upvalue) {
+                    Obj::Upvalue(ObjUpvalue::Open(slot)) => self.stack[*slot] = value,
+                    _ => self.heap.set_upvalue(upvalue, value),
+                }
+            }
+            OpCode::Jump => self.frame_mut().ip += distance(operands),
+            OpCode::JumpIfFalse => {
+                let condition = self.pop();
+                if matches!(condition.unpack(), Unpacked::Nil | Unpacked::Boolean(false)) {
+                    self.frame_mut().ip += distance(operands);
+                }
+            }
+            OpCode::Loop => self.frame_mut().ip -= distance(operands),
+            OpCode::Call => self.call(operands[0] as usize)?,
+            OpCode::Closure => self.closure(operands[0]),
+            OpCode::CloseUpvalue => {
+                self.close_upvalues(self.stack.len() - 1);
+                self.pop();
+            }
+        }
+        Ok(None)
+    }
+
+    fn frame(&self) -> &Frame {
+        self.frames.last().expect("a call is running")
+    }
+
+    fn frame_mut(&mut self) -> &mut Frame {
+        self.frames.last_mut().expect("a call is running")
+    }
+
+    fn constant(&self, index: impl Into<u32>) -> Slot {
+        match self.heap.get(self.frame().function) {
+            Obj::Function(function) => function.constants[index.into() as usize],
+            _ => unreachable!("frames run functions"),
+        }
+    }
+
+    // The interned name a global instruction refers to, and its hash
+    fn global_name(&self, index: u8) -> (ObjRef, u32) {
+        let name = self.constant(index).as_obj().expect("names are strings");
+        match self.heap.get(name) {
+            Obj::String(string) => (name, string.hash),
+            _ => unreachable!("names are strings"),
+        }
+    }
+
+    fn undefined(&self, name: ObjRef) -> RuntimeError {
+        let Value::String(name) = self.value(Slot::new(Unpacked::Obj(name))) else {
+            unreachable!("names are strings");
+        };
+        RuntimeError::UndefinedVariable(Token::from((TokenKind::Identifier, name)))
+    }
+
+    // The running closure's upvalue at `index`
+    fn upvalue(&self, index: u8) -> ObjRef {
+        match self.heap.get(self.frame().closure) {
+            Obj::Closure(closure) => closure.upvalues[index as usize],
+            _ => unreachable!("frames run closures"),
+        }
+    }
+
+    // Calls the closure below the top `count` values, which are its
+    // arguments
+    fn call(&mut self, count: usize) -> Result<(), RuntimeError> {
+        let paren = || Token::from(TokenKind::RightParen);
+        let base = self.stack.len() - count - 1;
+        let callee = self.stack[base]
+            .as_obj()
+            .map(|obj| (obj, self.heap.get(obj)));
+        let Some((closure, Obj::Closure(ObjClosure { function, .. }))) = callee else {
+            return Err(RuntimeError::NotCallable(paren()));
+        };
+        let function = *function;
+        let Obj::Function(ObjFunction { prototype, .. }) = self.heap.get(function) else {
+            unreachable!("closures are made of functions");
+        };
+
+        if prototype.arity as usize != count {
+            return Err(RuntimeError::WrongArity(
+                paren(),
+                prototype.arity as usize,
+                count,
+            ));
+        }
+        // The script's frame doesn't count
+        if self.frames.len() > MAX_CALL_DEPTH {
+            return Err(RuntimeError::StackOverflow(paren()));
+        }
+        self.frames.push(Frame {
+            closure,
+            function,
+            prototype: prototype.clone(),
+            ip: 0,
+            base,
+        });
+        Ok(())
+    }
+
+    // Ends the current call with `result`, which is given back if it was
+    // the script's
+    fn return_from_call(&mut self, result: Slot) -> Option<Value> {
+        let frame = self.frames.pop().expect("a call is running");
+        self.close_upvalues(frame.base);
+        if self.frames.is_empty() {
+            self.stack.clear();
+            return Some(self.value(result));
+        }
+        self.stack.truncate(frame.base);
+        self.push(result);
+        None
+    }
+
+    // Makes a closure of the function constant at `index`, capturing the
+    // variables it refers to from the running call
+    fn closure(&mut self, index: u8) {
+        let function = self
+            .constant(index)
+            .as_obj()
+            .expect("closures are of functions");
+        let Obj::Function(ObjFunction { prototype, .. }) = self.heap.get(function) else {
+            unreachable!("closures are of functions");
+        };
+        let prototype = prototype.clone();
+
+        // Open upvalues are rooted while the others are allocated, and the
+        // enclosing closure roots those it passes on
+        let base = self.frame().base;
+        let upvalues = prototype
+            .upvalues
+            .iter()
+            .map(|capture| match capture.is_local {
+                true => self.capture_upvalue(base + capture.index as usize),
+                false => self.upvalue(capture.index),
+            })
+            .collect();
+        let closure = self.alloc(Obj::Closure(ObjClosure { function, upvalues }));
+        self.push(Slot::new(Unpacked::Obj(closure)));
+    }
+
+    // The upvalue for the variable in stack slot `slot`, shared with every
+    // closure that has already captured it
+    fn capture_upvalue(&mut self, slot: usize) -> ObjRef {
+        let position = self
+            .open_upvalues
+            .partition_point(|upvalue| self.open_slot(*upvalue) < slot);
+        if let Some(&upvalue) = self.open_upvalues.get(position)
+            && self.open_slot(upvalue) == slot
+        {
+            return upvalue;
+        }
+        let upvalue = self.alloc(Obj::Upvalue(ObjUpvalue::Open(slot)));
+        self.open_upvalues.insert(position, upvalue);
+        upvalue
+    }
+
+    // Moves the variables in stack slot `from` and above, which are going
+    // out of scope, into the upvalues that captured them
+    fn close_upvalues(&mut self, from: usize) {
+        while let Some(&upvalue) = self.open_upvalues.last()
+            && self.open_slot(upvalue) >= from
+        {
+            let slot = self.open_slot(upvalue);
+            self.heap.set_upvalue(upvalue, self.stack[slot]);
+            self.open_upvalues.pop();
+        }
+    }
+
+    fn open_slot(&self, upvalue: ObjRef) -> usize {
+        match self.heap.get(upvalue) {
+            Obj::Upvalue(ObjUpvalue::Open(slot)) => *slot,
+            _ => unreachable!("closed upvalues are no longer listed as open"),
+        }
+    }
+
+    fn trace_stack(&self) -> String {
+        let mut output = " ".repeat(10);
+        for slot in &self.stack {
+            output.push_str(&format!("[ {} ]", self.value(*slot)));
+        }
+        output
+    }
+
+    fn push(&mut self, slot: Slot) {
+        self.stack.push(slot);
+    }
+
+    fn pop(&mut self) -> Slot {
+        self.stack.pop().expect("stack underflow")
+    }
+
+    fn peek(&self) -> Slot {
+        *self.stack.last().expect("stack underflow")
+    }
+
+    // Everything the program can still reach: the stack, which holds the
+    // closure of every call in progress, the upvalues pointing into it and
+    // the globals. Constants are reached through the closures' functions.
+    fn roots(&self) -> impl Iterator<Item = ObjRef> {
+        self.stack
+            .iter()
+            .chain(self.globals.iter().map(|(_, value)| value))
+            .filter_map(|slot| slot.as_obj())
+            .chain(self.globals.iter().map(|(name, _)| name))
+            .chain(self.open_upvalues.iter().copied())
+    }
+
+    fn collect_garbage(&mut self) {
+        if self.heap.should_collect() {
+            let roots = self.roots().collect::<Vec<_>>();
+            self.heap.collect(roots);
+        }
+    }
+
+    // The object must only refer to objects that are rooted
+    fn alloc(&mut self, obj: Obj) -> ObjRef {
+        self.collect_garbage();
+        self.heap.alloc(obj)
+    }
+
+    fn intern(&mut self, chars: &str) -> ObjRef {
+        self.collect_garbage();
+        self.heap.intern(chars)
+    }
+
+    // Puts a compiled function on the heap, along with its constants and the
+    // functions among them. The constants wait on the stack, where they are
+    // rooted, until the function holding them has been allocated.
+    fn load(&mut self, prototype: Rc<Prototype>) -> ObjRef {
+        let start = self.stack.len();
+        for constant in &prototype.chunk.constants {
+            let constant = self.slot(constant.clone());
+            self.push(constant);
+        }
+        let constants = self.stack[start..].into();
+        let function = self.alloc(Obj::Function(ObjFunction {
+            prototype,
+            constants,
+        }));
+        self.stack.truncate(start);
+        function
+    }
+
+    fn slot(&mut self, value: Value) -> Slot {
+        Slot::new(match value {
+            Value::Nil => Unpacked::Nil,
+            Value::Boolean(value) => Unpacked::Boolean(value),
+            Value::Number(value) => Unpacked::Number(value),
+            Value::String(value) => Unpacked::Obj(self.intern(&value)),
+            Value::Function(function) => {
+                let prototype = function
+                    .object()
+                    .clone()
+                    .downcast::<Prototype>()
+                    .expect("only compiled functions are constants");
+                Unpacked::Obj(self.load(prototype))
+            }
+        })
+    }
+
+    fn value(&self, slot: Slot) -> Value {
+        match slot.unpack() {
+            Unpacked::Nil => Value::Nil,
+            Unpacked::Boolean(value) => Value::Boolean(value),
+            Unpacked::Number(value) => Value::Number(value),
+            Unpacked::Obj(obj) => match self.heap.get(obj) {
+                Obj::String(string) => Value::String(string.chars.to_string()),
+                Obj::Closure(closure) => self.value(Slot::new(Unpacked::Obj(closure.function))),
+                Obj::Function(function) => Value::Function(Function::new(
+                    &function.prototype.name,
+                    function.prototype.clone(),
+                )),
+                Obj::Upvalue(_) => unreachable!("upvalues are never values"),
+            },
+        }
+    }
+
+    // Operators share their semantics, and error messages, with the tree
+    // walking interpreter. Operands are popped before the result is
+    // allocated, which is safe since they have already been copied out.
+    fn unary(&mut self, kind: TokenKind) -> Result<(), RuntimeError> {
+        let right = self.pop();
+        let result = value::unary(&Token::from(kind), self.value(right))?;
+        let result = self.slot(result);
+        self.push(result);
+        Ok(())
+    }
+
+    // `==` or `!=`
+    fn equal(&mut self, kind: TokenKind) -> Result<(), RuntimeError> {
+        let operands = &self.stack[self.stack.len() - 2..];
+        // Strings are interned, so equal strings are the same object
+        if let (Some(left), Some(right)) = (operands[0].as_obj(), operands[1].as_obj()) {
+            let equal = (left == right) == (kind == TokenKind::EqualEqual);
+            self.stack.truncate(self.stack.len() - 2);
+            self.push(Slot::new(Unpacked::Boolean(equal)));
+            return Ok(());
+        }
+        self.binary(kind)
+    }
+
+    fn binary(&mut self, kind: TokenKind) -> Result<(), RuntimeError> {
+        let right = self.pop();
+        let left = self.pop();
+        let result = value::binary(self.value(left), &Token::from(kind), self.value(right))?;
+        let result = self.slot(result);
+        self.push(result);
+        Ok(())
+    }
+}
+
+// The distance operand of a jump
+fn distance(operands: &[u8]) -> usize {
+    u16::from_le_bytes([operands[0], operands[1]]) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use crate::{
+        chunk::{Chunk, OpCode},
+        compiler::{compile, compile_program},
+        slot::{Slot, Unpacked},
+        token::{Span, Token, TokenKind},
+        value::{RuntimeError, Value},
+        vm::Vm,
+    };
+
+    #[test]
+    fn test_runs_handwritten_chunk() {
+        let mut chunk = Chunk::new();
+        chunk.write_constant(Value::Number(1.5), Span::new(1, 1));
+        chunk.write_op(OpCode::Negate, Span::new(1, 1));
+        chunk.write_constant(Value::Number(2.0), Span::new(1, 1));
+        chunk.write_op(OpCode::Multiply, Span::new(1, 1));
+        chunk.write_op(OpCode::Return, Span::new(1, 1));
+        assert_eq!(Vm::new().run(&chunk), Ok(Value::Number(-3.0)));
+    }
+
+    #[test]
+    fn test_trace_stack() {
+        let mut vm = Vm::new().with_trace(true);
+        assert_eq!(vm.trace_stack(), " ".repeat(10));
+        let a = vm.slot(Value::String("a".to_string()));
+        vm.push(Slot::new(Unpacked::Number(1.0)));
+        vm.push(a);
+        vm.push(Slot::new(Unpacked::Number(f64::NAN)));
+        assert_eq!(
+            vm.trace_stack(),
+            format!("{}[ 1 ][ a ][ NaN ]", " ".repeat(10))
+        );
+    }
+
+    #[test]
+    fn test_long_constants() {
+        let mut chunk = Chunk::new();
+        for index in 0..300 {
+            chunk.write_constant(Value::Number(index as f64), Span::new(1, 1));
+        }
+        for _ in 1..300 {
+            chunk.write_op(OpCode::Add, Span::new(1, 1));
+        }
+        chunk.write_op(OpCode::Return, Span::new(1, 1));
+        assert_eq!(
+            Vm::new().run(&chunk),
+            Ok(Value::Number((0..300).sum::<i32>() as f64))
+        );
+    }
+
+    #[test]
+    fn test_errors_report_source_position() {
+        let expr = crate::parse("1 +\n  2 * -\"x\"").expect("source parses");
+        let chunk = compile(&expr).expect("expression compiles");
+        let error = Vm::new().run(&chunk).expect_err("negating a string fails");
+        assert_eq!(
+            error,
+            RuntimeError::OperandMustBeNumber(Token::from(TokenKind::Minus))
+        );
+        assert_eq!(error.token().span, Span::new(2, 7));
+        assert_eq!(error.to_string(), "Line 2:7: Operand of - must be a number");
+    }
+
+    #[test]
+    fn test_gc_stress_reclaims_intermediate_strings() {
+        let compiled = |source| compile(&crate::parse(source).expect("source parses"));
+        let mut vm = Vm::new().with_gc_stress(true);
+
+        let chunk = compiled("\"a\" + \"b\" + \"c\"").expect("expression compiles");
+        assert_eq!(vm.run(&chunk), Ok(Value::String("abc".to_string())));
+        // The constants, the result and the script's function and closure
+        // survive, but "ab" was collected when "abc" was allocated
+        assert_eq!(vm.heap().len(), 6);
+
+        // Nothing from an earlier run is rooted once another starts, so it
+        // all goes at the next allocation, leaving this run's constant,
+        // function and closure
+        let chunk = compiled("-\"x\"").expect("expression compiles");
+        assert!(vm.run(&chunk).is_err());
+        assert_eq!(vm.heap().len(), 3);
+    }
+
+    // Runs a program with a collection before every allocation, checking
+    // that it prints what it does on the tree-walking interpreter
+    fn output(source: &str) -> String {
+        let program = crate::parse_program(source).expect("source parses");
+        let script = compile_program(&program).expect("program compiles");
+        let mut output = Vec::new();
+        Vm::new()
+            .with_gc_stress(true)
+            .run_program(Rc::new(script), &mut output)
+            .expect("program runs");
+
+        let mut expected = Vec::new();
+        crate::execute(&program, &mut expected).expect("program runs");
+        assert_eq!(output, expected);
+        String::from_utf8(output).expect("output is UTF-8")
+    }
+
+    #[test]
+    fn test_sibling_closures_share_captured_variables() {
+        let source = "
+            var get;
+            var set;
+            fun make() {
+                var shared = 1;
+                fun getter() { return shared; }
+                fun setter(value) { shared = value; }
+                get = getter;
+                set = setter;
+                // While `shared` is still on the stack
+                set(2);
+                print get();
+                print shared;
+            }
+            make();
+            // and once it has been moved to the heap
+            set(3);
+            print get();
+        ";
+        assert_eq!(output(source), "2\n2\n3\n");
+    }
+
+    #[test]
+    fn test_closures_created_in_loops() {
+        // A `for` loop has one `i`, shared by every closure made in it, but
+        // each run of its body declares a new `j`
+        let source = "
+            var first;
+            var second;
+            for (var i = 0; i < 2; i = i + 1) {
+                var j = i;
+                fun show() { print i; print j; }
+                if (first == nil) first = show; else second = show;
+            }
+            first();
+            second();
+        ";
+        assert_eq!(output(source), "2\n0\n2\n1\n");
+
+        // The same with `while`, where the closures outlive the block each
+        // was made in
+        let source = "
+            var closures = nil;
+            fun link(f, rest) { fun node(which) { if (which) return f; return rest; } return node; }
+            var i = 0;
+            while (i < 3) {
+                var k = i * 10;
+                fun show() { print k; }
+                closures = link(show, closures);
+                i = i + 1;
+            }
+            while (closures != nil) {
+                closures(true)();
+                closures = closures(false);
+            }
+        ";
+        assert_eq!(output(source), "20\n10\n0\n");
+    }
+
+    #[test]
+    fn test_upvalues_of_upvalues() {
+        // `inner` captures `x` through `middle`, which never uses it itself
+        let source = "
+            fun outer() {
+                var x = \"before\";
+                fun middle() {
+                    fun inner() { print x; x = \"after\"; }
+                    return inner;
+                }
+                var inner = middle();
+                inner();
+                print x;
+                return inner;
+            }
+            outer()();
+        ";
+        assert_eq!(output(source), "before\nafter\nafter\n");
+    }
+}
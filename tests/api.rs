@@ -0,0 +1,88 @@
+use lox::{
+    Error, Expr, ParserError, RuntimeError, Stmt, TokenKind, Value, execute, interpret, lex, parse,
+    parse_program,
+};
+
+fn eval(source: &str) -> Result<Value, Error> {
+    Ok(interpret(&parse(source)?)?)
+}
+
+#[test]
+fn test_lex() {
+    let kinds = lex("1 + \"a\"")
+        .expect("source lexes")
+        .into_iter()
+        .map(|token| token.kind)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        kinds,
+        [
+            TokenKind::Number,
+            TokenKind::Plus,
+            TokenKind::String,
+            TokenKind::EoF
+        ]
+    );
+    assert!(lex("1 @ 2").is_err());
+}
+
+#[test]
+fn test_parse() {
+    assert_eq!(parse("nil").expect("source parses"), Expr::NilLiteral);
+    assert!(matches!(parse("\"open"), Err(Error::Lexer(_))));
+    assert!(matches!(
+        parse("(1"),
+        Err(Error::Parser(ParserError::UnclosedParenthesis))
+    ));
+    for source in ["1 2", "1 + 2 )"] {
+        assert!(matches!(
+            parse(source),
+            Err(Error::Parser(ParserError::UnexpectedToken(_)))
+        ));
+    }
+}
+
+#[test]
+fn test_interpret() {
+    assert_eq!(eval("(1 + 2) * 3").ok(), Some(Value::Number(9.0)));
+    assert_eq!(
+        eval("\"a\" + \"b\"").ok(),
+        Some(Value::String("ab".to_string()))
+    );
+    assert!(matches!(
+        eval("-nil"),
+        Err(Error::Runtime(RuntimeError::OperandMustBeNumber(_)))
+    ));
+}
+
+#[test]
+fn test_execute() {
+    let program = parse_program("var a = 1;\nprint a + 1;").expect("source parses");
+    assert!(matches!(program.as_slice(), [Stmt::Var(_), Stmt::Print(_)]));
+    let mut output = Vec::new();
+    execute(&program, &mut output).expect("program runs");
+    assert_eq!(output, b"2\n");
+
+    assert!(matches!(
+        parse_program("print 1"),
+        Err(Error::Parser(ParserError::Expected(
+            TokenKind::Semicolon,
+            _
+        )))
+    ));
+    let program = parse_program("print 1;\nprint b;").expect("source parses");
+    let mut output = Vec::new();
+    assert!(matches!(
+        execute(&program, &mut output),
+        Err(RuntimeError::UndefinedVariable(_))
+    ));
+    assert_eq!(output, b"1\n");
+}
+
+#[test]
+fn test_errors_are_std_errors() {
+    let error: Box<dyn std::error::Error> =
+        Box::new(parse("1 +").expect_err("source is incomplete"));
+    assert_eq!(error.to_string(), "Expected primary expression got EOF");
+    assert!(error.source().is_some());
+}
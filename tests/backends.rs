@@ -0,0 +1,188 @@
+use std::{fs, path::Path, rc::Rc};
+
+use lox::{
+    Error, Stmt, Value, bytecode,
+    compiler::{compile, compile_program},
+    execute,
+    gc::{GcMode, Heap},
+    interpret,
+    optimizer::{optimize, optimize_program},
+    parse, parse_program, peephole,
+    printer::print,
+    vm::Vm,
+};
+
+type Backend = fn(&str) -> Result<Value, Error>;
+
+// Every backend, and every backend with optimizations, runs the same cases
+// so they can be trusted to agree
+const BACKENDS: [(&str, Backend); 9] = [
+    ("tree", |source| Ok(interpret(&parse(source)?)?)),
+    ("tree --optimize", |source| {
+        Ok(interpret(&optimize(parse(source)?))?)
+    }),
+    (
+        "vm",
+        |source| Ok(Vm::new().run(&compile(&parse(source)?)?)?),
+    ),
+    ("vm --optimize", |source| {
+        Ok(Vm::new().run(&compile(&optimize(parse(source)?))?)?)
+    }),
+    ("vm with superinstructions", |source| {
+        Ok(Vm::new().run(&peephole::optimize(&compile(&parse(source)?)?))?)
+    }),
+    ("vm -O", |source| {
+        let chunk = peephole::optimize(&compile(&optimize(parse(source)?))?);
+        Ok(Vm::new().run(&chunk)?)
+    }),
+    ("vm from a bytecode file", |source| {
+        let bytes = bytecode::write(&compile(&parse(source)?)?);
+        Ok(Vm::new().run(&bytecode::read(&bytes)?)?)
+    }),
+    ("vm --gc incremental --gc-stress", |source| {
+        let heap = Heap::new().with_mode(GcMode::Incremental).with_stress(true);
+        Ok(Vm::new().with_heap(heap).run(&compile(&parse(source)?)?)?)
+    }),
+    ("vm --gc-stress", |source| {
+        Ok(Vm::new()
+            .with_gc_stress(true)
+            .run(&compile(&parse(source)?)?)?)
+    }),
+];
+
+fn show(result: Result<Value, Error>) -> String {
+    match result {
+        Ok(value) => print(&value.into_literal()),
+        Err(error) => format!("error: {}", error),
+    }
+}
+
+#[test]
+fn test_backends_agree_on_fixtures() {
+    let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/run");
+    let mut paths = fs::read_dir(&directory)
+        .expect("fixture directory exists")
+        .map(|entry| entry.expect("fixture directory is readable").path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "txt"))
+        .collect::<Vec<_>>();
+    paths.sort();
+
+    let mut cases = 0;
+    let mut failures = Vec::new();
+    for path in paths {
+        let contents = fs::read_to_string(&path).expect("fixture is readable");
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let Some((source, expected)) = line.split_once("=>") else {
+                panic!("{}:{}: missing '=>'", path.display(), number + 1);
+            };
+            for (backend, run) in BACKENDS {
+                let actual = show(run(source.trim()));
+                if actual != expected.trim() {
+                    failures.push(format!(
+                        "{}:{}: '{}' on {}\n  expected: {}\n  actual:   {}",
+                        path.display(),
+                        number + 1,
+                        source.trim(),
+                        backend,
+                        expected.trim(),
+                        actual
+                    ));
+                }
+            }
+            cases += 1;
+        }
+    }
+
+    assert!(cases > 0, "no fixtures found in {}", directory.display());
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
+
+// Runs a program, adding what it prints to the output
+type ProgramBackend = fn(&str, &mut Vec<u8>) -> Result<(), Error>;
+
+fn run_on_vm(mut vm: Vm, program: &[Stmt], output: &mut Vec<u8>) -> Result<(), Error> {
+    let script = compile_program(program)?;
+    vm.run_program(Rc::new(script), output)?;
+    Ok(())
+}
+
+const PROGRAM_BACKENDS: [(&str, ProgramBackend); 6] = [
+    ("tree", |source, output| {
+        Ok(execute(&parse_program(source)?, output)?)
+    }),
+    ("tree --optimize", |source, output| {
+        Ok(execute(&optimize_program(parse_program(source)?), output)?)
+    }),
+    ("vm", |source, output| {
+        run_on_vm(Vm::new(), &parse_program(source)?, output)
+    }),
+    ("vm --optimize", |source, output| {
+        run_on_vm(Vm::new(), &optimize_program(parse_program(source)?), output)
+    }),
+    ("vm --gc incremental --gc-stress", |source, output| {
+        let heap = Heap::new().with_mode(GcMode::Incremental).with_stress(true);
+        run_on_vm(Vm::new().with_heap(heap), &parse_program(source)?, output)
+    }),
+    ("vm --gc-stress", |source, output| {
+        run_on_vm(
+            Vm::new().with_gc_stress(true),
+            &parse_program(source)?,
+            output,
+        )
+    }),
+];
+
+#[test]
+fn test_backends_agree_on_programs() {
+    let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/programs");
+    let mut paths = fs::read_dir(&directory)
+        .expect("program directory exists")
+        .map(|entry| entry.expect("program directory is readable").path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "lox"))
+        .collect::<Vec<_>>();
+    paths.sort();
+
+    let mut failures = Vec::new();
+    for path in &paths {
+        let source = fs::read_to_string(path).expect("program is readable");
+        // What the program prints, then its error if it fails
+        let mut expected = String::new();
+        for line in source.lines() {
+            if let Some((_, output)) = line.split_once("// expect: ") {
+                expected += &format!("{}\n", output);
+            } else if let Some((_, error)) = line.split_once("// expect error: ") {
+                expected += &format!("error: {}\n", error);
+            }
+        }
+
+        for (backend, run) in PROGRAM_BACKENDS {
+            let mut output = Vec::new();
+            let result = run(&source, &mut output);
+            let mut actual = String::from_utf8(output).expect("output is UTF-8");
+            if let Err(error) = result {
+                actual += &format!("error: {}\n", error);
+            }
+            if actual != expected {
+                failures.push(format!(
+                    "{} on {}\n  expected:\n{}  actual:\n{}",
+                    path.display(),
+                    backend,
+                    expected,
+                    actual
+                ));
+            }
+        }
+    }
+
+    assert!(
+        !paths.is_empty(),
+        "no programs found in {}",
+        directory.display()
+    );
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
@@ -0,0 +1,98 @@
+use std::{
+    fs,
+    io::Write,
+    process::{Command, Stdio},
+};
+
+fn lox(args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_lox-rs"))
+        .args(args)
+        .output()
+        .expect("lox-rs runs");
+    assert!(output.status.success(), "lox-rs {:?} failed", args);
+    String::from_utf8(output.stdout).expect("output is UTF-8")
+}
+
+fn opcodes(disassembly: &str) -> Vec<String> {
+    disassembly
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.split_whitespace().nth(2).map(str::to_string))
+        .collect()
+}
+
+// The peephole pass runs on its own with --peephole, so the pairs it fuses
+// are still there for it to find. -O folds them away first.
+#[test]
+fn test_peephole_without_folding() {
+    let source = "(1 != 2)";
+    assert_eq!(
+        opcodes(&lox(&["disasm", "-e", source])),
+        ["Constant", "Constant", "Equal", "Not", "Return"]
+    );
+    assert_eq!(
+        opcodes(&lox(&["disasm", "--peephole", "-e", source])),
+        ["Constant", "Constant", "NotEqual", "Return"]
+    );
+    assert_eq!(
+        opcodes(&lox(&["disasm", "--peephole", "-e", "\"a\""])),
+        ["ReturnConstant"]
+    );
+    assert_eq!(
+        opcodes(&lox(&["disasm", "-O", "-e", source])),
+        ["True", "Return"]
+    );
+
+    for flags in [&[][..], &["--peephole"], &["-O"]] {
+        let args = [&["run", "--backend", "vm", "-e", source], flags].concat();
+        assert_eq!(lox(&args), "true\n");
+    }
+}
+
+#[test]
+fn test_stdin_is_read_once() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_lox-rs"))
+        .args(["parse", "-", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("lox-rs runs");
+    child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(b"1 + 2")
+        .expect("stdin is writable");
+    let output = child.wait_with_output().expect("lox-rs runs");
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).expect("output is UTF-8"),
+        "Parsing '<stdin>'\nAST:\n(1 + 2)\n".repeat(2)
+    );
+}
+
+// An unreadable file is reported, but the files after it are still formatted
+#[test]
+fn test_fmt_continues_past_failures() {
+    let directory = std::env::temp_dir().join(format!("lox-fmt-{}", std::process::id()));
+    fs::create_dir_all(&directory).expect("temporary directory is writable");
+    let missing = directory.join("missing.lox");
+    let file = directory.join("file.lox");
+    fs::write(&file, "1+2\n").expect("temporary file is writable");
+
+    let output = Command::new(env!("CARGO_BIN_EXE_lox-rs"))
+        .arg("fmt")
+        .args([&missing, &file])
+        .output()
+        .expect("lox-rs runs");
+    let formatted = fs::read_to_string(&file).expect("file is readable");
+    fs::remove_dir_all(&directory).expect("temporary directory is removable");
+
+    assert!(!output.status.success());
+    assert!(
+        String::from_utf8(output.stdout)
+            .expect("output is UTF-8")
+            .contains(&format!("Failed to read file {}", missing.display()))
+    );
+    assert_eq!(formatted, "1 + 2\n");
+}